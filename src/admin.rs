@@ -0,0 +1,108 @@
+use crate::{admin_rejection, cert, AppState};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{info, warn};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Forgets the last-applied fingerprints, and with DEDUP_ANNOTATION the ones
+/// annotated on secrets, so the next update of every config is pushed, for
+/// when the NodeBalancer was changed by hand. Secret data and requests aren't
+/// cached, so there is nothing else to clear.
+pub async fn clear_caches(req: HttpRequest, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if let Some(response) = admin_rejection(&state, &req) {
+        return response;
+    }
+
+    let fingerprints = state.last_applied.clear().await;
+    let annotations = match &state.annotations {
+        Some(annotations) => annotations.clear().await,
+        None => 0,
+    };
+    info!(
+        "Cleared {} last-applied fingerprint(s) and {} annotation(s) on request",
+        fingerprints, annotations
+    );
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "cleared",
+        "cleared": { "last_applied": fingerprints, "annotations": annotations },
+    }))
+}
+
+// Pauses longer than a day are more likely a typo than a maintenance window
+const DEFAULT_PAUSE_SECS: u64 = 1800;
+const MAX_PAUSE_SECS: u64 = 86_400;
+
+/// Query parameters of /admin/pause.
+#[derive(Debug, Deserialize)]
+pub struct PauseOptions {
+    #[serde(rename = "ttlSecs")]
+    ttl_secs: Option<u64>,
+}
+
+/// Makes every update answer 503 without touching Linode until `ttlSecs`
+/// (default 30 minutes, at most a day) have passed or /admin/resume is called.
+/// Scheduled revalidations are skipped meanwhile.
+pub async fn pause_updates(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    options: web::Query<PauseOptions>,
+) -> HttpResponse {
+    if let Some(response) = admin_rejection(&state, &req) {
+        return response;
+    }
+
+    let ttl_secs = options.ttl_secs.unwrap_or(DEFAULT_PAUSE_SECS).clamp(1, MAX_PAUSE_SECS);
+    let until = cert::unix_now() + ttl_secs as i64;
+    state.metrics.paused.pause(until);
+    warn!("Updates paused for {}s on request", ttl_secs);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "paused",
+        "ttl_secs": ttl_secs,
+        "until": until,
+    }))
+}
+
+pub async fn resume_updates(req: HttpRequest, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if let Some(response) = admin_rejection(&state, &req) {
+        return response;
+    }
+
+    let was_paused = state.metrics.paused.resume();
+    info!("Updates resumed on request{}", if was_paused { "" } else { ", they were not paused" });
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "resumed",
+        "was_paused": was_paused,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, state, testutil};
+
+    #[actix_web::test]
+    async fn cache_clear_forgets_annotations_too() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config = testutil::config(&mock, &[("ADMIN_TOKEN", "admin")]).await;
+        let state = Arc::try_unwrap(testutil::state(config, Default::default())).ok().unwrap();
+        // The annotation store's bookkeeping, backed by memory instead of secrets
+        let state = Arc::new(AppState { annotations: Some(Box::new(state::StateStore::memory())), ..state });
+        state.last_applied.insert(selftest::CONFIG_ID, "ab").await;
+        let annotations = state.annotations.as_deref().unwrap();
+        annotations.set("selftest/one-tls", "ab").await;
+        annotations.set("selftest/two-tls", "cd").await;
+
+        let app = actix_web::test::init_service(actix_web::App::new()
+            .app_data(web::Data::new(state.clone()))
+            .route("/admin/cache/clear", web::post().to(clear_caches))).await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/admin/cache/clear")
+            .insert_header(("authorization", "Bearer admin"))
+            .to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["cleared"], serde_json::json!({ "last_applied": 1, "annotations": 2 }));
+        assert_eq!(annotations.get("selftest/one-tls").await, None);
+        assert!(state.last_applied.snapshot().is_empty());
+        mock.stop().await;
+    }
+}
@@ -0,0 +1,127 @@
+use crate::linode::LinodeClient;
+use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
+
+/// The parts of an AGLB configuration (a listening port) that matter here.
+#[derive(Debug, Deserialize)]
+pub struct Configuration {
+    pub port: u16,
+    #[serde(default)]
+    pub certificates: Vec<CertificateRef>,
+}
+
+/// A downstream certificate served for `hostname` on a configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateRef {
+    pub id: u64,
+    pub hostname: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Certificate {
+    id: u64,
+    #[serde(default)]
+    label: String,
+}
+
+/// One page of a list endpoint, the filtered lookups here fit in the first.
+#[derive(Debug, Deserialize)]
+struct Page<T> {
+    data: Vec<T>,
+}
+
+fn certificates_url(linode: &LinodeClient) -> String {
+    format!("{}/certificates", linode.load_balancer_url())
+}
+
+async fn send(linode: &LinodeClient, request: reqwest::RequestBuilder) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let response = linode.authorize(request).send().await.map_err(|e| linode.transport_error(e))?;
+    linode.ratelimit.observe(response.headers());
+    if !response.status().is_success() {
+        return Err(linode.api_error(response).await.into());
+    }
+    Ok(response)
+}
+
+/// Uploads the chain and key as a downstream certificate resource and returns
+/// its id. Nothing serves it until a configuration references it.
+pub async fn upload_certificate(
+    linode: &LinodeClient,
+    target: &str,
+    label: &str,
+    cert: &str,
+    key: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let url = certificates_url(linode);
+    trace!("Linode request: POST {} label={}", url, label);
+    let payload = serde_json::json!({
+        "label": label,
+        "certificate": cert,
+        "key": key,
+        "type": "downstream",
+    });
+    let request = linode.with_target_timeout(linode.http.post(&url).json(&payload), target);
+    let certificate = send(linode, request).await?.json::<Certificate>().await?;
+    info!("Uploaded certificate {} ({})", certificate.id, label);
+    Ok(certificate.id)
+}
+
+/// Looks up a certificate by the label it was uploaded with, so a retried
+/// upload can tell whether the attempt that failed created it anyway.
+pub async fn find_certificate(
+    linode: &LinodeClient,
+    target: &str,
+    label: &str,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let url = certificates_url(linode);
+    let filter = serde_json::json!({ "label": label }).to_string();
+    debug!("Looking up certificate {}", label);
+    let request = linode.with_target_timeout(linode.http.get(&url).header("X-Filter", filter), target);
+    let page = send(linode, request).await?.json::<Page<Certificate>>().await?;
+    Ok(page.data.into_iter().find(|c| c.label == label).map(|c| c.id))
+}
+
+pub async fn get_configuration(
+    linode: &LinodeClient,
+    config_id: &str,
+) -> Result<Configuration, Box<dyn std::error::Error>> {
+    debug!("Fetching AGLB configuration {}", config_id);
+    let request = linode.with_target_timeout(linode.http.get(linode.config_url(config_id)), config_id);
+    Ok(send(linode, request).await?.json::<Configuration>().await?)
+}
+
+/// Replaces the certificates a configuration serves.
+pub async fn set_certificates(
+    linode: &LinodeClient,
+    config_id: &str,
+    certificates: &[CertificateRef],
+) -> Result<Configuration, Box<dyn std::error::Error>> {
+    let url = linode.config_url(config_id);
+    let payload = serde_json::json!({ "certificates": certificates });
+    trace!("Linode request: PUT {} payload={}", url, payload);
+    let request = linode.with_target_timeout(linode.http.put(&url).json(&payload), config_id);
+    let response = send(linode, request).await?;
+    info!("Updated certificates of AGLB configuration {}", config_id);
+    Ok(response.json::<Configuration>().await?)
+}
+
+pub async fn certificate_label(
+    linode: &LinodeClient,
+    target: &str,
+    certificate_id: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let url = format!("{}/{}", certificates_url(linode), certificate_id);
+    let response = send(linode, linode.with_target_timeout(linode.http.get(&url), target)).await?;
+    Ok(response.json::<Certificate>().await?.label)
+}
+
+pub async fn delete_certificate(
+    linode: &LinodeClient,
+    target: &str,
+    certificate_id: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/{}", certificates_url(linode), certificate_id);
+    send(linode, linode.with_target_timeout(linode.http.delete(&url), target)).await?;
+    info!("Deleted certificate {}", certificate_id);
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use k8s_openapi::chrono::Utc;
+use log::{error, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Bound on tracked sources, so a scan from many addresses can't grow the map forever
+const MAX_TRACKED_SOURCES: usize = 1024;
+// What ALERT_TEMPLATE may refer to as `{name}`
+const PLACEHOLDERS: &[&str] = &["text", "source", "reason", "error", "failures", "window_secs", "namespace", "secret", "timestamp"];
+
+pub struct AlertConfig {
+    pub url: String,
+    // Failures from one source within `window` that trigger an alert
+    pub threshold: usize,
+    pub window: Duration,
+    // Minimum time between two alerts for the same source
+    pub cooldown: Duration,
+    // ALERT_TEMPLATE, the payload shape when the default one doesn't fit the receiver
+    pub template: Option<AlertTemplate>,
+}
+
+/// A JSON payload whose strings may contain `{placeholder}`s, filled in for
+/// each alert. A string that is a single placeholder takes the value's type,
+/// so `"{failures}"` renders as a number.
+#[derive(Debug, Clone)]
+pub struct AlertTemplate(serde_json::Value);
+
+impl AlertTemplate {
+    /// Parses the template and checks every placeholder in it is known.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(raw).map_err(|e| format!("is not valid JSON: {}", e))?;
+        let mut unknown = Vec::new();
+        visit_strings(&value, &mut |text| {
+            for name in placeholders(text) {
+                if !PLACEHOLDERS.contains(&name) && !unknown.contains(&name.to_string()) {
+                    unknown.push(name.to_string());
+                }
+            }
+        });
+        if !unknown.is_empty() {
+            return Err(format!(
+                "uses unknown placeholder(s) {}, expected any of {}",
+                unknown.iter().map(|name| format!("{{{}}}", name)).collect::<Vec<_>>().join(", "),
+                PLACEHOLDERS.iter().map(|name| format!("{{{}}}", name)).collect::<Vec<_>>().join(", ")
+            ));
+        }
+        Ok(AlertTemplate(value))
+    }
+
+    fn render(&self, values: &serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+        render_value(&self.0, values)
+    }
+}
+
+/// Names of the `{name}` placeholders in `text`. Braces around anything but
+/// a lowercase name are left as text.
+fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    text.split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+}
+
+fn visit_strings(value: &serde_json::Value, visit: &mut impl FnMut(&str)) {
+    match value {
+        serde_json::Value::String(text) => visit(text),
+        serde_json::Value::Array(items) => items.iter().for_each(|item| visit_strings(item, visit)),
+        serde_json::Value::Object(fields) => fields.iter().for_each(|(key, item)| {
+            visit(key);
+            visit_strings(item, visit);
+        }),
+        _ => {}
+    }
+}
+
+fn render_value(value: &serde_json::Value, values: &serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(text) => {
+            let whole = text.strip_prefix('{').and_then(|rest| rest.strip_suffix('}'));
+            match whole.and_then(|name| values.get(name)) {
+                Some(value) => value.clone(),
+                None => serde_json::Value::String(render_text(text, values)),
+            }
+        }
+        serde_json::Value::Array(items) => items.iter().map(|item| render_value(item, values)).collect(),
+        serde_json::Value::Object(fields) => fields
+            .iter()
+            .map(|(key, item)| (render_text(key, values), render_value(item, values)))
+            .collect(),
+        other => other.clone(),
+    }
+}
+
+fn render_text(text: &str, values: &serde_json::Map<String, serde_json::Value>) -> String {
+    let mut rendered = text.to_string();
+    for (name, value) in values {
+        let value = match value {
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        rendered = rendered.replace(&format!("{{{}}}", name), &value);
+    }
+    rendered
+}
+
+/// Tracks validation failures per source and posts to ALERT_WEBHOOK_URL when
+/// one source keeps failing.
+pub struct FailureAlerts {
+    config: AlertConfig,
+    http: reqwest::Client,
+    failures: Mutex<HashMap<String, VecDeque<Instant>>>,
+    last_alert: Mutex<HashMap<String, Instant>>,
+}
+
+impl FailureAlerts {
+    pub fn new(config: AlertConfig, http: reqwest::Client) -> Self {
+        FailureAlerts {
+            config,
+            http,
+            failures: Mutex::new(HashMap::new()),
+            last_alert: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a failure from `source`, with the secret the request named when
+    /// it got that far.
+    pub fn record(&self, source: &str, reason: &str, secret: Option<(&str, &str)>) {
+        let now = Instant::now();
+        let count = {
+            let mut failures = self.failures.lock().unwrap();
+            if failures.len() >= MAX_TRACKED_SOURCES && !failures.contains_key(source) {
+                failures.retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < self.config.window));
+            }
+            let times = failures.entry(source.to_string()).or_default();
+            times.push_back(now);
+            while times.front().is_some_and(|first| now.duration_since(*first) > self.config.window) {
+                times.pop_front();
+            }
+            times.len()
+        };
+
+        if count < self.config.threshold {
+            return;
+        }
+
+        {
+            let mut last_alert = self.last_alert.lock().unwrap();
+            if last_alert.get(source).is_some_and(|at| now.duration_since(*at) < self.config.cooldown) {
+                return;
+            }
+            if last_alert.len() >= MAX_TRACKED_SOURCES {
+                last_alert.retain(|_, at| now.duration_since(*at) < self.config.cooldown);
+            }
+            last_alert.insert(source.to_string(), now);
+        }
+
+        warn!(
+            "{} validation failures from {} within {}s (latest: {}), sending alert",
+            count,
+            source,
+            self.config.window.as_secs(),
+            reason
+        );
+        let text = format!(
+            "cert-webhook: {} invalid requests from {} in the last {}s (latest reason: {})",
+            count,
+            source,
+            self.config.window.as_secs(),
+            reason
+        );
+        let payload = match &self.config.template {
+            Some(template) => {
+                let (namespace, secret) = secret.unwrap_or_default();
+                let values: serde_json::Map<String, serde_json::Value> = [
+                    ("text", text.clone().into()),
+                    ("source", source.into()),
+                    ("reason", reason.into()),
+                    ("error", reason.into()),
+                    ("failures", count.into()),
+                    ("window_secs", self.config.window.as_secs().into()),
+                    ("namespace", namespace.into()),
+                    ("secret", secret.into()),
+                    ("timestamp", Utc::now().to_rfc3339().into()),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect();
+                template.render(&values)
+            }
+            None => serde_json::json!({
+                "text": text,
+                "source": source,
+                "reason": reason,
+                "failures": count,
+                "window_secs": self.config.window.as_secs(),
+            }),
+        };
+        let request = self.http.post(&self.config.url).json(&payload);
+
+        actix_web::rt::spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => info!("Validation failure alert sent"),
+                Ok(response) => error!("Alert webhook responded with status {}", response.status()),
+                Err(e) => error!("Failed to send validation failure alert: {}", e),
+            }
+        });
+    }
+}
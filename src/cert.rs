@@ -0,0 +1,395 @@
+use log::info;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use x509_parser::extensions::GeneralName;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::pem::Pem;
+
+fn leaf_pem(cert_pem: &str) -> Result<Pem, Box<dyn std::error::Error>> {
+    Ok(Pem::iter_from_buffer(cert_pem.as_bytes())
+        .next()
+        .ok_or("no PEM block found in certificate")??)
+}
+
+/// Converts CRLF (or lone CR) line endings to LF, strips trailing whitespace
+/// from each line and drops blank lines between PEM blocks, leaving a single
+/// trailing newline. Some tools write PEM that parsers reject otherwise. Blank
+/// lines inside a block are kept, an RFC 1421 encrypted key needs the one
+/// after its Proc-Type and DEK-Info headers.
+pub fn normalize_pem(raw: &str) -> String {
+    let mut normalized = String::with_capacity(raw.len());
+    let mut in_block = false;
+    for line in raw.replace("\r\n", "\n").replace('\r', "\n").lines().map(str::trim_end) {
+        if line.starts_with("-----BEGIN ") {
+            in_block = true;
+        } else if line.starts_with("-----END ") {
+            in_block = false;
+        } else if line.is_empty() && !in_block {
+            continue;
+        }
+        normalized.push_str(line);
+        normalized.push('\n');
+    }
+    normalized
+}
+
+/// Hex SHA-256 of the leaf certificate's DER encoding.
+pub fn leaf_fingerprint(cert_pem: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let pem = leaf_pem(cert_pem)?;
+    Ok(hex::encode(Sha256::digest(&pem.contents)))
+}
+
+/// Compares the leaf against a fingerprint as reported by Linode, which is
+/// colon-separated uppercase hex. The digest is picked from its length, so
+/// both SHA-1 and SHA-256 fingerprints are understood.
+pub fn fingerprint_matches(cert_pem: &str, linode_fingerprint: &str) -> bool {
+    let Ok(pem) = leaf_pem(cert_pem) else {
+        return false;
+    };
+    let expected: String = linode_fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase();
+
+    match expected.len() {
+        40 => hex::encode(Sha1::digest(&pem.contents)) == expected,
+        64 => hex::encode(Sha256::digest(&pem.contents)) == expected,
+        _ => false,
+    }
+}
+
+/// `notBefore` and `notAfter` of the leaf certificate as unix timestamps.
+pub fn leaf_validity(cert_pem: &str) -> Result<(i64, i64), Box<dyn std::error::Error>> {
+    let pem = leaf_pem(cert_pem)?;
+    let cert = pem.parse_x509()?;
+    let validity = cert.validity();
+    Ok((validity.not_before.timestamp(), validity.not_after.timestamp()))
+}
+
+/// Hex SHA-256 of a DER certificate, the same format as `leaf_fingerprint`.
+pub fn der_fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+/// `notBefore` and `notAfter` of a DER certificate as unix timestamps.
+pub fn der_validity(der: &[u8]) -> Result<(i64, i64), Box<dyn std::error::Error>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+    let validity = cert.validity();
+    Ok((validity.not_before.timestamp(), validity.not_after.timestamp()))
+}
+
+/// `notAfter` of a DER certificate as a unix timestamp.
+pub fn der_not_after(der: &[u8]) -> Result<i64, Box<dyn std::error::Error>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+    Ok(cert.validity().not_after.timestamp())
+}
+
+/// Checks that the leaf is valid at `now`, tolerating `skew_secs` of clock
+/// difference with the CA on both ends of the validity window.
+pub fn check_validity(cert_pem: &str, now: i64, skew_secs: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let (not_before, not_after) = leaf_validity(cert_pem)?;
+
+    // Saturating, a huge CLOCK_SKEW_SECS must not overflow
+    if now.saturating_add(skew_secs) < not_before {
+        return Err(format!("certificate is not valid until {} ({}s from now)", not_before, not_before - now).into());
+    }
+    if now.saturating_sub(skew_secs) > not_after {
+        return Err(format!("certificate expired at {} ({}s ago)", not_after, now - not_after).into());
+    }
+
+    if now < not_before {
+        info!("Certificate becomes valid in {}s, accepting it within the {}s clock skew tolerance", not_before - now, skew_secs);
+    } else if now > not_after {
+        info!("Certificate expired {}s ago, accepting it within the {}s clock skew tolerance", now - not_after, skew_secs);
+    }
+    Ok(())
+}
+
+/// Current time as a unix timestamp.
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Returns the DNS subject alternative names of the leaf (first) certificate
+/// in a PEM bundle.
+pub fn leaf_sans(cert_pem: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let pem = leaf_pem(cert_pem)?;
+    let cert = pem.parse_x509()?;
+
+    let mut sans = Vec::new();
+    if let Some(ext) = cert.subject_alternative_name()? {
+        for name in &ext.value.general_names {
+            if let GeneralName::DNSName(dns) = name {
+                sans.push(dns.to_lowercase());
+            }
+        }
+    }
+
+    Ok(sans)
+}
+
+/// Whether a leaf with these SANs is valid for `hostname`. A wildcard covers
+/// a single label, so `*.example.com` covers `www.example.com` but not
+/// `example.com` or `a.b.example.com`.
+pub fn sans_cover(sans: &[String], hostname: &str) -> bool {
+    let hostname = hostname.to_lowercase();
+    sans.iter().any(|san| match san.strip_prefix("*.") {
+        Some(parent) => hostname.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest == parent),
+        None => *san == hostname,
+    })
+}
+
+/// Parses a `san=config_id` list separated by commas, e.g.
+/// `example.com=12345,*.example.org=67890`.
+pub fn parse_san_map(raw: &str) -> Result<Vec<(String, String)>, String> {
+    let mut map = Vec::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (san, config_id) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid SAN mapping '{}', expected san=config_id", entry))?;
+        let san = san.trim().to_lowercase();
+        let config_id = config_id.trim();
+        if san.is_empty() || config_id.is_empty() {
+            return Err(format!("invalid SAN mapping '{}', expected san=config_id", entry));
+        }
+        if !config_id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("config id '{}' for SAN '{}' must be numeric", config_id, san));
+        }
+        map.push((san, config_id.to_string()));
+    }
+    Ok(map)
+}
+
+/// Picks the config ids whose mapped SAN appears on the certificate. SANs are
+/// compared literally (case-insensitive), so a wildcard mapping only matches a
+/// wildcard SAN. Falls back to `default_config_ids` when nothing matches.
+pub fn route_config_ids(
+    sans: &[String],
+    san_map: &[(String, String)],
+    default_config_ids: &[String],
+) -> Vec<String> {
+    let mut config_ids: Vec<String> = Vec::new();
+    for (san, config_id) in san_map {
+        if sans.contains(san) && !config_ids.contains(config_id) {
+            config_ids.push(config_id.clone());
+        }
+    }
+
+    if config_ids.is_empty() {
+        config_ids.extend_from_slice(default_config_ids);
+    }
+    config_ids
+}
+
+// Smallest key sizes accepted by the strength check
+const MIN_RSA_BITS: u32 = 2048;
+const MIN_EC_BITS: u32 = 256;
+
+/// Whether the private key belongs to the leaf certificate (DER).
+pub fn key_matches(leaf_der: &[u8], key_pem: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let leaf = openssl::x509::X509::from_der(leaf_der)?;
+    let key = openssl::pkey::PKey::private_key_from_pem(key_pem.as_bytes())?;
+    Ok(leaf.public_key()?.public_eq(&key))
+}
+
+/// Describes the leaf's public key, or why it is too weak.
+pub fn check_key_strength(leaf_der: &[u8]) -> Result<String, String> {
+    let leaf = openssl::x509::X509::from_der(leaf_der).map_err(|e| e.to_string())?;
+    let key = leaf.public_key().map_err(|e| e.to_string())?;
+    let bits = key.bits();
+    let (algorithm, minimum) = match key.id() {
+        openssl::pkey::Id::RSA => ("RSA", MIN_RSA_BITS),
+        openssl::pkey::Id::EC => ("EC", MIN_EC_BITS),
+        openssl::pkey::Id::ED25519 => ("Ed25519", 0),
+        openssl::pkey::Id::ED448 => ("Ed448", 0),
+        other => return Err(format!("unsupported key type {:?}", other)),
+    };
+    if bits < minimum {
+        return Err(format!("{} key of {} bits is below the {}-bit minimum", algorithm, bits, minimum));
+    }
+    Ok(format!("{} {} bits", algorithm, bits))
+}
+
+/// Checks that each certificate in the chain (DER, leaf first) is issued by
+/// the next one. A lone leaf is only complete when it is self-signed.
+pub fn check_chain(chain: &[Vec<u8>]) -> Result<(), String> {
+    let certs = chain
+        .iter()
+        .map(|der| x509_parser::parse_x509_certificate(der).map(|(_, cert)| cert))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("unparseable certificate in chain: {}", e))?;
+
+    for (index, pair) in certs.windows(2).enumerate() {
+        if pair[0].issuer() != pair[1].subject() {
+            return Err(format!(
+                "certificate {} is issued by '{}' but is followed by '{}'",
+                index,
+                pair[0].issuer(),
+                pair[1].subject()
+            ));
+        }
+    }
+
+    match certs.as_slice() {
+        [leaf] if leaf.issuer() != leaf.subject() => {
+            Err(format!("no intermediate certificate for issuer '{}'", leaf.issuer()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reorders a PEM bundle so the leaf comes first, followed by its issuers in
+/// chain order. The leaf is the certificate that issued none of the others.
+/// Returns `None` when the bundle is already in order, or when no single leaf
+/// can be identified, in which case it is best left as is.
+pub fn order_leaf_first(cert_pem: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut blocks = Vec::new();
+    for pem in Pem::iter_from_buffer(cert_pem.as_bytes()) {
+        let pem = pem?;
+        if pem.label == "CERTIFICATE" {
+            blocks.push(pem.contents);
+        }
+    }
+    if blocks.len() < 2 {
+        return Ok(None);
+    }
+
+    let names = blocks
+        .iter()
+        .map(|der| {
+            let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+            Ok((cert.subject().as_raw().to_vec(), cert.issuer().as_raw().to_vec()))
+        })
+        .collect::<Result<Vec<_>, x509_parser::nom::Err<x509_parser::error::X509Error>>>()?;
+
+    let issued_another = |index: usize| {
+        names
+            .iter()
+            .enumerate()
+            .any(|(other, (_, issuer))| other != index && *issuer == names[index].0)
+    };
+    let leaves: Vec<usize> = (0..blocks.len()).filter(|index| !issued_another(*index)).collect();
+    let [leaf] = leaves[..] else {
+        return Ok(None);
+    };
+
+    let mut order = vec![leaf];
+    while let Some(next) = (0..blocks.len())
+        .find(|index| !order.contains(index) && names[*index].0 == names[*order.last().unwrap()].1)
+    {
+        order.push(next);
+    }
+    // Anything not on the leaf's chain keeps its relative position at the end
+    order.extend((0..blocks.len()).filter(|index| !order.contains(index)).collect::<Vec<_>>());
+
+    if order.iter().copied().eq(0..blocks.len()) {
+        return Ok(None);
+    }
+    Ok(Some(order.into_iter().map(|index| der_to_pem(&blocks[index])).collect()))
+}
+
+fn der_to_pem(der: &[u8]) -> String {
+    use base64::Engine as _;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+/// The leaf's subject when it is self-signed, i.e. its own issuer.
+pub fn self_signed_subject(leaf_der: &[u8]) -> Result<Option<String>, String> {
+    let (_, leaf) = x509_parser::parse_x509_certificate(leaf_der).map_err(|e| format!("unparseable leaf: {}", e))?;
+    Ok((leaf.issuer() == leaf.subject()).then(|| leaf.subject().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Random bytes wrapped as a certificate PEM block, which gets the input
+    /// past the PEM layer and into the DER parsers.
+    fn pem_wrapped() -> impl Strategy<Value = String> {
+        proptest::collection::vec(any::<u8>(), 0..512).prop_map(|der| der_to_pem(&der))
+    }
+
+    #[test]
+    fn crlf_pem_is_normalized_to_lf() {
+        let (bundle, _) = crate::selftest::generate_chain().unwrap();
+        let crlf = format!("\r\n{}\r\n", bundle.replace('\n', " \r\n"));
+        let normalized = normalize_pem(&crlf);
+        assert_eq!(normalized, bundle);
+        assert_eq!(leaf_fingerprint(&normalized).unwrap(), leaf_fingerprint(&bundle).unwrap());
+    }
+
+    #[test]
+    fn encrypted_key_keeps_its_header_separator() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let pem = String::from_utf8(rsa.private_key_to_pem_passphrase(openssl::symm::Cipher::aes_128_cbc(), b"secret").unwrap()).unwrap();
+        assert!(pem.contains("DEK-Info"));
+        let normalized = normalize_pem(&pem.replace('\n', "\r\n"));
+        assert_eq!(normalized, pem);
+        assert!(openssl::rsa::Rsa::private_key_from_pem_passphrase(normalized.as_bytes(), b"secret").is_ok());
+    }
+
+    #[test]
+    fn wildcard_san_covers_one_label() {
+        let sans = ["example.com".to_string(), "*.example.org".to_string()];
+        assert!(sans_cover(&sans, "Example.com"));
+        assert!(sans_cover(&sans, "www.example.org"));
+        assert!(!sans_cover(&sans, "example.org"));
+        assert!(!sans_cover(&sans, "a.b.example.org"));
+        assert!(!sans_cover(&sans, "www.example.com"));
+    }
+
+    #[test]
+    fn reversed_bundle_is_put_leaf_first() {
+        let (bundle, _) = crate::selftest::generate_chain().unwrap();
+        let ca_start = bundle.rfind("-----BEGIN CERTIFICATE-----").unwrap();
+        let (leaf, ca) = bundle.split_at(ca_start);
+        let reversed = format!("{}{}", ca, leaf);
+
+        assert_eq!(order_leaf_first(&reversed).unwrap().as_deref(), Some(bundle.as_str()));
+        assert_eq!(order_leaf_first(&bundle).unwrap(), None);
+        // A lone certificate has nothing to reorder
+        assert_eq!(order_leaf_first(leaf).unwrap(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn parsers_never_panic_on_arbitrary_text(raw in any::<String>()) {
+            prop_assert!(leaf_fingerprint(&raw).is_err());
+            prop_assert!(leaf_validity(&raw).is_err());
+            prop_assert!(leaf_sans(&raw).is_err());
+            prop_assert!(!fingerprint_matches(&raw, &"ab".repeat(32)));
+            let _ = order_leaf_first(&raw);
+        }
+
+        #[test]
+        fn parsers_return_errors_for_random_der(pem in pem_wrapped()) {
+            prop_assert!(leaf_validity(&pem).is_err());
+            prop_assert!(leaf_sans(&pem).is_err());
+            prop_assert!(order_leaf_first(&pem.repeat(2)).is_err());
+            let der = Pem::iter_from_buffer(pem.as_bytes()).next().unwrap().unwrap().contents;
+            prop_assert!(der_validity(&der).is_err());
+            let _ = check_key_strength(&der);
+            let _ = self_signed_subject(&der);
+            let _ = check_chain(&[der.clone(), der]);
+        }
+
+        #[test]
+        fn normalize_pem_is_idempotent(raw in any::<String>()) {
+            let normalized = normalize_pem(&raw);
+            prop_assert!(!normalized.contains('\r'));
+            prop_assert_eq!(normalize_pem(&normalized), normalized);
+        }
+    }
+}
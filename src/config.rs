@@ -0,0 +1,1011 @@
+use crate::alert::{AlertConfig, AlertTemplate};
+use crate::linode::{Chaos, LbType, TargetProfile};
+use crate::metrics;
+use crate::provider::ProviderKind;
+use crate::queue::{InputMode, RedisStreamConfig};
+use crate::reconcile::{self, Target};
+use crate::verify::{LiveVerifyConfig, PostVerifyConfig, PreferIp};
+use crate::window::{self, ApplyWindow};
+use crate::{
+    cert, is_kube_name, parse_trusted_proxies, CertMode, ChainPolicy, DeepHealthMode, JsonSecretLayout, SecretFormat, SecretKeys, MAX_NAMESPACE_LEN, MAX_SECRET_NAME_LEN,
+};
+use actix_web::http::StatusCode;
+use ipnet::IpNet;
+use k8s_openapi::chrono::FixedOffset;
+use reqwest::header::{HeaderName, AUTHORIZATION};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_LINODE_API_URL: &str = "https://api.linode.com";
+const DEFAULT_LINODE_API_VERSION: &str = "v4";
+const DEFAULT_LINODE_AUTH_SCHEME: &str = "Bearer";
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_BATCH: usize = 50;
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+const DEFAULT_MAX_PARALLEL_CONFIG_UPDATES: usize = 4;
+const DEFAULT_WATCH_CONCURRENCY: usize = 2;
+const DEFAULT_QUEUE_STREAM: &str = "cert-webhook";
+const DEFAULT_QUEUE_GROUP: &str = "cert-webhook";
+const DEFAULT_LEASE_NAME: &str = "cert-webhook";
+const DEFAULT_LEASE_DURATION_SECS: u64 = 15;
+const DEFAULT_CLOCK_SKEW_SECS: i64 = 60;
+const DEFAULT_VERIFY_LIVE_ATTEMPTS: u32 = 5;
+const DEFAULT_VERIFY_LIVE_DELAY_SECS: u64 = 3;
+const DEFAULT_POST_VERIFY_ATTEMPTS: u32 = 3;
+const DEFAULT_POST_VERIFY_DELAY_MS: u64 = 1000;
+const DEFAULT_EXPIRY_WARN_DAYS: i64 = 14;
+const DEFAULT_VALIDATION_ALERT_THRESHOLD: usize = 10;
+const DEFAULT_VALIDATION_ALERT_WINDOW_SECS: u64 = 300;
+const DEFAULT_ALERT_COOLDOWN_SECS: u64 = 900;
+// Matches the three attempts other operations get
+const DEFAULT_KUBE_MAX_RETRIES: u32 = 2;
+// A long chain is a few KiB, anything near these is not a certificate
+const DEFAULT_MAX_SECRET_CERT_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_SECRET_KEY_BYTES: usize = 16 * 1024;
+const DEFAULT_MAX_CHAIN_CERTS: usize = 10;
+const DEFAULT_SECRET_JSON_KEY: &str = "secret";
+const DEFAULT_SECRET_JSON_CERT_FIELD: &str = "cert";
+const DEFAULT_SECRET_JSON_KEY_FIELD: &str = "key";
+const DEFAULT_LINODE_RATELIMIT_WARN_BELOW: i64 = 20;
+const DEFAULT_EXTRA_HEALTH_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_SIGNATURE_MAX_AGE_SECS: u64 = 300;
+// Bounds of TARGET_PROFILES values
+const MAX_TARGET_TIMEOUT_SECS: u64 = 300;
+const MAX_TARGET_RETRIES: u32 = 10;
+const MAX_TARGET_RETRY_BASE_MS: u64 = 60_000;
+
+/// Raw settings keyed by environment variable name. Values come from the
+/// optional `CONFIG_PATH` YAML file, whose keys are the lowercase variable
+/// names, with the environment taking precedence.
+pub struct Settings {
+    file: HashMap<String, String>,
+    path: Option<String>,
+    // Keys read so far, so leftover file keys can be reported as unknown
+    read: RefCell<HashSet<String>>,
+    // Every problem found, reported together instead of one per restart
+    errors: RefCell<Vec<String>>,
+    // Whether the environment is consulted at all, off for tests running side by side
+    env: bool,
+}
+
+impl Settings {
+    pub fn load() -> Result<Self, String> {
+        let path = env::var("CONFIG_PATH").ok();
+        let file = match &path {
+            Some(path) => read_yaml(path)?,
+            None => HashMap::new(),
+        };
+        Ok(Settings {
+            file,
+            path,
+            read: RefCell::new(HashSet::new()),
+            errors: RefCell::new(Vec::new()),
+            env: true,
+        })
+    }
+
+    fn raw(&self, key: &str) -> Option<(String, String)> {
+        self.read.borrow_mut().insert(key.to_string());
+        if let Some(value) = self.env.then(|| env::var(key).ok()).flatten() {
+            return Some((key.to_string(), value));
+        }
+        let label = format!(
+            "{} (in {})",
+            key.to_lowercase(),
+            self.path.as_deref().unwrap_or("config file")
+        );
+        self.file.get(key).map(|value| (label, value.clone()))
+    }
+
+    pub fn string(&self, key: &str) -> Option<String> {
+        self.raw(key).map(|(_, value)| value)
+    }
+
+    pub fn error(&self, message: String) {
+        self.errors.borrow_mut().push(message);
+    }
+
+    /// A setting that must be present, empty (and an error recorded) when it isn't.
+    pub fn required(&self, key: &str) -> String {
+        match self.raw(key) {
+            Some((_, value)) if !value.trim().is_empty() => value,
+            Some((label, _)) => {
+                self.error(format!("{} must not be empty", label));
+                String::new()
+            }
+            None => {
+                self.error(format!("{} must be set", key));
+                String::new()
+            }
+        }
+    }
+
+    /// Parses a setting with `FromStr`, `None` when it isn't set or is invalid
+    /// (which records an error).
+    pub fn parse<T>(&self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse_with(key, |raw| {
+            raw.trim()
+                .parse::<T>()
+                .map_err(|e| format!("is not a valid {} ({})", std::any::type_name::<T>(), e))
+        })
+    }
+
+    /// Parses a setting with a custom parser whose error completes the
+    /// sentence "`<setting>='<value>'` ...".
+    pub fn parse_with<T>(&self, key: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Option<T> {
+        let (label, value) = self.raw(key)?;
+        match parse(&value) {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                self.error(format!("{}='{}' {}", label, value, e));
+                None
+            }
+        }
+    }
+
+    pub fn flag(&self, key: &str) -> bool {
+        self.parse_with(key, |raw| match raw.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" => Ok(true),
+            "false" | "0" | "no" | "" => Ok(false),
+            _ => Err("is not a valid boolean (expected true or false)".to_string()),
+        })
+        .unwrap_or(false)
+    }
+
+    /// Comma-separated list, empty when unset.
+    pub fn list(&self, key: &str) -> Vec<String> {
+        self.string(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    }
+
+    /// File keys that no setting ever asked for, most likely typos.
+    pub fn unknown_file_keys(&self) -> Vec<String> {
+        let read = self.read.borrow();
+        let mut unknown: Vec<String> = self
+            .file
+            .keys()
+            .filter(|key| !read.contains(*key))
+            .map(|key| key.to_lowercase())
+            .collect();
+        unknown.sort();
+        unknown
+    }
+}
+
+/// Flattens the YAML file into `KEY -> value` strings. Lists become
+/// comma-separated values and maps become `key=value` pairs, matching the
+/// formats accepted from the environment.
+fn read_yaml(path: &str) -> Result<HashMap<String, String>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("failed to read CONFIG_PATH {}: {}", path, e))?;
+    let root: serde_yaml::Value =
+        serde_yaml::from_str(&content).map_err(|e| format!("failed to parse CONFIG_PATH {}: {}", path, e))?;
+
+    let mapping = match root {
+        serde_yaml::Value::Mapping(mapping) => mapping,
+        serde_yaml::Value::Null => return Ok(HashMap::new()),
+        _ => return Err(format!("{} must contain a mapping of settings", path)),
+    };
+
+    let mut values = HashMap::new();
+    for (key, value) in mapping {
+        let key = scalar_to_string(&key).ok_or_else(|| format!("{} contains a non-string key", path))?;
+        let flattened = match &value {
+            serde_yaml::Value::Sequence(items) => items
+                .iter()
+                .map(scalar_to_string)
+                .collect::<Option<Vec<_>>>()
+                .map(|items| items.join(",")),
+            serde_yaml::Value::Mapping(entries) => entries
+                .iter()
+                .map(|(k, v)| Some(format!("{}={}", scalar_to_string(k)?, scalar_to_string(v)?)))
+                .collect::<Option<Vec<_>>>()
+                .map(|entries| entries.join(",")),
+            scalar => scalar_to_string(scalar),
+        }
+        .ok_or_else(|| format!("{} (in {}) has an unsupported nested value", key, path))?;
+        values.insert(key.to_uppercase(), flattened);
+    }
+    Ok(values)
+}
+
+fn scalar_to_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        serde_yaml::Value::Null => Some(String::new()),
+        _ => None,
+    }
+}
+
+/// Parses `namespace/secret=cert_key:key_key` entries separated by commas,
+/// the data keys to read from secrets that don't use tls.crt/tls.key. Either
+/// key may be left empty to keep its default, e.g. `team-a/web=cert.pem:`.
+fn parse_secret_keys(raw: &str) -> Result<HashMap<String, SecretKeys>, String> {
+    let mut map = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid = || format!("invalid entry '{}', expected namespace/secret=cert_key:key_key", entry);
+        let (secret, keys) = entry.split_once('=').ok_or_else(invalid)?;
+        let (namespace, name) = secret.trim().split_once('/').ok_or_else(invalid)?;
+        let (cert_key, key_key) = keys.trim().split_once(':').ok_or_else(invalid)?;
+
+        if !is_kube_name(namespace, MAX_NAMESPACE_LEN, false) || !is_kube_name(name, MAX_SECRET_NAME_LEN, true) {
+            return Err(format!("invalid secret '{}' in entry '{}'", secret.trim(), entry));
+        }
+        let defaults = SecretKeys::default();
+        let keys = SecretKeys {
+            cert: secret_data_key(cert_key.trim(), &defaults.cert, entry)?,
+            key: secret_data_key(key_key.trim(), &defaults.key, entry)?,
+        };
+        if map.insert(format!("{}/{}", namespace, name), keys).is_some() {
+            return Err(format!("secret '{}' is listed more than once", secret.trim()));
+        }
+    }
+    Ok(map)
+}
+
+/// Parses `namespace=name|name` entries separated by commas, the secrets each
+/// namespace may push. A name ending in `*` matches by prefix, so `*` alone
+/// allows the whole namespace. Repeating a namespace adds to its names.
+fn parse_allowed_secrets(raw: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut map: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (namespace, names) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid entry '{}', expected namespace=name|name", entry))?;
+        let namespace = namespace.trim();
+        if !is_kube_name(namespace, MAX_NAMESPACE_LEN, false) {
+            return Err(format!("invalid namespace '{}' in entry '{}'", namespace, entry));
+        }
+        for name in names.split('|').map(str::trim) {
+            let valid = match name.strip_suffix('*') {
+                Some(prefix) => prefix.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.'),
+                None => is_kube_name(name, MAX_SECRET_NAME_LEN, true),
+            };
+            if !valid {
+                return Err(format!("invalid secret name '{}' in entry '{}'", name, entry));
+            }
+            map.entry(namespace.to_string()).or_default().push(name.to_string());
+        }
+    }
+    Ok(map)
+}
+
+/// Parses comma-separated SHA-256 fingerprints into lowercase hex, accepting
+/// the colon-separated uppercase form `openssl x509 -fingerprint` prints.
+fn parse_fingerprints(raw: &str) -> Result<Vec<String>, String> {
+    let mut fingerprints = Vec::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let hex: String = entry.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("'{}' is not a SHA-256 fingerprint", entry));
+        }
+        fingerprints.push(hex);
+    }
+    Ok(fingerprints)
+}
+
+/// Parses `config_id=timeout_secs:max_retries:retry_base_ms` entries separated
+/// by commas. Empty fields keep the global value, e.g. `12345=60::` only
+/// raises the timeout of config 12345.
+fn parse_target_profiles(raw: &str) -> Result<HashMap<String, TargetProfile>, String> {
+    let mut map = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid = || format!("invalid entry '{}', expected config_id=timeout_secs:max_retries:retry_base_ms", entry);
+        let (config_id, values) = entry.split_once('=').ok_or_else(invalid)?;
+        let fields: Vec<&str> = values.split(':').map(str::trim).collect();
+        let [timeout, max_retries, retry_base] = fields[..] else {
+            return Err(invalid());
+        };
+        let config_id = numeric_id(config_id).map_err(|e| format!("config id in entry '{}' {}", entry, e))?;
+
+        let profile = TargetProfile {
+            timeout: profile_value(timeout, 1, MAX_TARGET_TIMEOUT_SECS, "timeout_secs", entry)?.map(Duration::from_secs),
+            max_retries: profile_value(max_retries, 0, MAX_TARGET_RETRIES as u64, "max_retries", entry)?.map(|n| n as u32),
+            retry_base: profile_value(retry_base, 1, MAX_TARGET_RETRY_BASE_MS, "retry_base_ms", entry)?
+                .map(Duration::from_millis),
+        };
+        if map.insert(config_id, profile).is_some() {
+            return Err(format!("config id in entry '{}' is listed more than once", entry));
+        }
+    }
+    Ok(map)
+}
+
+fn profile_value(raw: &str, min: u64, max: u64, field: &str, entry: &str) -> Result<Option<u64>, String> {
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    match raw.parse::<u64>() {
+        Ok(value) if (min..=max).contains(&value) => Ok(Some(value)),
+        _ => Err(format!("{} '{}' in entry '{}' must be between {} and {}", field, raw, entry, min, max)),
+    }
+}
+
+/// A secret data key as Kubernetes allows them, or `default` when empty.
+fn secret_data_key(raw: &str, default: &str, entry: &str) -> Result<String, String> {
+    if raw.is_empty() {
+        return Ok(default.to_string());
+    }
+    if raw.len() > MAX_SECRET_NAME_LEN || !raw.chars().all(|c| c.is_ascii_alphanumeric() || "-._".contains(c)) {
+        return Err(format!("invalid data key '{}' in entry '{}'", raw, entry));
+    }
+    Ok(raw.to_string())
+}
+
+/// Prometheus metric name rules, without the colons reserved for recording rules.
+fn is_metric_namespace(raw: &str) -> bool {
+    let mut chars = raw.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn numeric_id(raw: &str) -> Result<String, String> {
+    let id = raw.trim();
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+        Ok(id.to_string())
+    } else {
+        Err("must be a numeric id".to_string())
+    }
+}
+
+pub struct LeaderSettings {
+    pub lease_name: String,
+    pub lease_namespace: Option<String>,
+    pub lease_duration: Duration,
+}
+
+/// Effective configuration, validated at startup.
+pub struct Config {
+    pub linode_token: String,
+    pub linode_token_file: Option<String>,
+    pub nodebalancer_id: String,
+    // Configs every certificate goes to unless SAN routing or the request says otherwise
+    pub https_config_ids: Vec<String>,
+    // Base URL including the API version
+    pub linode_api_url: String,
+    // Header carrying the token, and the scheme before it ("" sends the bare token)
+    pub linode_auth_header: HeaderName,
+    pub linode_auth_scheme: String,
+    pub linode_ca_bundle: Option<String>,
+    pub linode_tls_insecure: bool,
+    pub http_timeout: Duration,
+    pub http_connect_timeout: Duration,
+    pub http_user_agent: String,
+    pub port: u16,
+    // Scope of every route, "" or a path like "/cert-webhook" without trailing slash
+    pub route_prefix: String,
+    pub success_status: StatusCode,
+    pub apply_delay: Duration,
+    pub async_apply: bool,
+    // Listener for /metrics, health checks and admin routes, the main port keeps only the update routes
+    pub metrics_port: Option<u16>,
+    // Prefix of every metric name, ours and actix-web-prom's
+    pub metrics_namespace: String,
+    pub san_map: Vec<(String, String)>,
+    pub allowed_config_ids: Vec<String>,
+    // Secret names (or `prefix*` patterns) each namespace may push, anything goes when empty
+    pub allowed_secrets: HashMap<String, Vec<String>>,
+    // SHA-256 leaf fingerprints that may be pushed, no pinning when empty
+    pub allowed_fingerprints: Vec<String>,
+    pub trusted_proxies: Vec<IpNet>,
+    pub live_verify: Option<LiveVerifyConfig>,
+    pub post_verify: Option<PostVerifyConfig>,
+    // Where the served certificate is checked, shared by live verification and /health/cert
+    pub nodebalancer_host: Option<String>,
+    // Address family tried first when connecting to nodebalancer_host
+    pub prefer_ip: PreferIp,
+    pub expiry_warn_days: i64,
+    pub deep_health_mode: DeepHealthMode,
+    pub chain_policy: ChainPolicy,
+    pub max_chain_certs: usize,
+    pub extra_health_urls: Vec<String>,
+    pub extra_health_timeout: Duration,
+    pub provider: ProviderKind,
+    pub lb_type: LbType,
+    pub cert_mode: CertMode,
+    pub clock_skew_secs: i64,
+    pub kube_max_retries: u32,
+    // Timeout and retry overrides per config id
+    pub target_profiles: HashMap<String, TargetProfile>,
+    // Warn when fewer Linode requests than this are left in the rate limit window
+    pub linode_ratelimit_warn_below: i64,
+    pub chaos: Option<Chaos>,
+    pub ready_requires_sync: bool,
+    pub include_timing: bool,
+    pub max_concurrent_updates: Option<usize>,
+    pub max_batch: usize,
+    pub batch_concurrency: usize,
+    pub batch_fail_fast: bool,
+    pub max_parallel_config_updates: usize,
+    pub state_configmap: Option<String>,
+    pub state_configmap_namespace: Option<String>,
+    // APPLY_WINDOW, pushes outside it are queued in `<state_configmap>-pending` or refused
+    pub apply_window: Option<ApplyWindow>,
+    pub dedup_annotation: bool,
+    pub secret_keys: HashMap<String, SecretKeys>,
+    pub secret_format: SecretFormat,
+    pub append_ca_crt: bool,
+    pub default_namespace: Option<String>,
+    pub max_secret_cert_bytes: usize,
+    pub max_secret_key_bytes: usize,
+    pub allow_self_signed: bool,
+    pub leader_election: Option<LeaderSettings>,
+    pub revalidate: Option<(Duration, Vec<Target>)>,
+    // Certificate watched for renewals, its namespace defaults to the client's
+    pub certificate_name: Option<String>,
+    pub certificate_namespace: Option<String>,
+    // Watch-triggered pushes running at once
+    pub watch_concurrency: usize,
+    pub input_mode: InputMode,
+    // Where update requests are consumed from when input_mode includes the queue
+    pub queue: Option<RedisStreamConfig>,
+    pub alert: Option<AlertConfig>,
+    pub admin_token: Option<String>,
+    // Key of the X-Signature-256 HMAC update requests must carry, unsigned requests are fine without it
+    pub webhook_hmac_secret: Option<String>,
+    // How far X-Timestamp may be from now, either way, for a signed request to be accepted
+    pub signature_max_age_secs: u64,
+}
+
+impl Config {
+    /// Loads and validates every setting, returning all problems at once.
+    pub async fn load() -> Result<Self, Vec<String>> {
+        let settings = Settings::load().map_err(|e| vec![e])?;
+        let config = Self::from_settings(&settings).await;
+
+        let unknown = settings.unknown_file_keys();
+        if !unknown.is_empty() {
+            settings.error(format!("unknown setting(s) in CONFIG_PATH: {}", unknown.join(", ")));
+        }
+        let errors = settings.errors.into_inner();
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Loads from `settings` alone, never the environment, as tests do.
+    #[cfg(test)]
+    pub async fn from_pairs(settings: &[(&str, &str)]) -> Result<Self, Vec<String>> {
+        let settings = Settings {
+            file: settings.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            path: None,
+            read: RefCell::new(HashSet::new()),
+            errors: RefCell::new(Vec::new()),
+            env: false,
+        };
+        let config = Self::from_settings(&settings).await;
+        let errors = settings.errors.into_inner();
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Every setting is read up front, even when a feature is disabled, so the
+    // file can't be flagged as containing unknown keys for them
+    async fn from_settings(settings: &Settings) -> Self {
+        // LINODE_TOKEN_FILE is re-read when Linode rejects the token, see LinodeToken
+        let linode_token_file = settings
+            .string("LINODE_TOKEN_FILE")
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty());
+        let linode_token = match (&linode_token_file, settings.string("LINODE_TOKEN").filter(|token| !token.trim().is_empty())) {
+            (Some(_), Some(_)) => {
+                settings.error("set either LINODE_TOKEN or LINODE_TOKEN_FILE, not both".to_string());
+                String::new()
+            }
+            (Some(path), None) => match std::fs::read_to_string(path) {
+                Ok(token) if !token.trim().is_empty() => token.trim().to_string(),
+                Ok(_) => {
+                    settings.error(format!("LINODE_TOKEN_FILE {} is empty", path));
+                    String::new()
+                }
+                Err(e) => {
+                    settings.error(format!("failed to read LINODE_TOKEN_FILE {}: {}", path, e));
+                    String::new()
+                }
+            },
+            (None, _) => settings.required("LINODE_TOKEN"),
+        };
+        let nodebalancer_id = settings.required("NODEBALANCER_ID");
+        if !nodebalancer_id.is_empty() && numeric_id(&nodebalancer_id).is_err() {
+            settings.error(format!("NODEBALANCER_ID='{}' is not a numeric id", nodebalancer_id));
+        }
+        let mut https_config_ids: Vec<String> = Vec::new();
+        let raw_config_ids = settings.required("HTTPS_CONFIG_ID");
+        let entries: Vec<&str> = raw_config_ids.split(',').map(str::trim).filter(|id| !id.is_empty()).collect();
+        // A missing value is already reported as required, only separators are left to catch
+        if entries.is_empty() && !raw_config_ids.is_empty() {
+            settings.error(format!("HTTPS_CONFIG_ID='{}' lists no config id", raw_config_ids));
+        }
+        for id in entries {
+            match numeric_id(id) {
+                Ok(id) if !https_config_ids.contains(&id) => https_config_ids.push(id),
+                Ok(_) => {}
+                Err(_) => settings.error(format!("HTTPS_CONFIG_ID contains '{}' which is not a numeric id", id)),
+            }
+        }
+
+        let linode_api_url = format!(
+            "{}/{}",
+            settings
+                .string("LINODE_API_URL")
+                .unwrap_or_else(|| DEFAULT_LINODE_API_URL.to_string())
+                .trim_end_matches('/'),
+            settings
+                .string("LINODE_API_VERSION")
+                .unwrap_or_else(|| DEFAULT_LINODE_API_VERSION.to_string())
+                .trim_matches('/'),
+        );
+        let linode_auth_header = settings
+            .parse_with("LINODE_AUTH_HEADER", |raw| {
+                HeaderName::from_str(raw.trim()).map_err(|_| "is not a legal header name".to_string())
+            })
+            .unwrap_or(AUTHORIZATION);
+        let linode_auth_scheme = settings
+            .parse_with("LINODE_AUTH_SCHEME", |raw| {
+                let scheme = raw.trim();
+                if scheme.chars().all(|c| c.is_ascii_graphic()) {
+                    Ok(scheme.to_string())
+                } else {
+                    Err("must be a single word of visible ASCII characters".to_string())
+                }
+            })
+            .unwrap_or_else(|| DEFAULT_LINODE_AUTH_SCHEME.to_string());
+        let linode_ca_bundle = settings.string("LINODE_CA_BUNDLE");
+        let linode_tls_insecure = settings.flag("LINODE_TLS_INSECURE");
+        let http_timeout = settings.parse::<u64>("HTTP_TIMEOUT_SECS").unwrap_or(DEFAULT_HTTP_TIMEOUT_SECS);
+        let http_connect_timeout = settings
+            .parse::<u64>("HTTP_CONNECT_TIMEOUT_SECS")
+            .unwrap_or(DEFAULT_HTTP_CONNECT_TIMEOUT_SECS);
+
+        let cluster_name = settings.string("CLUSTER_NAME").filter(|name| !name.is_empty());
+        let http_user_agent = settings.string("HTTP_USER_AGENT").unwrap_or_else(|| {
+            let agent = format!("cert-webhook/{}", env!("CARGO_PKG_VERSION"));
+            match &cluster_name {
+                Some(cluster) => format!("{} ({})", agent, cluster),
+                None => agent,
+            }
+        });
+        if let Err(e) = reqwest::header::HeaderValue::from_str(&http_user_agent) {
+            settings.error(format!("HTTP_USER_AGENT='{}' is not a valid header value ({})", http_user_agent, e));
+        }
+
+        let port = settings.parse::<u16>("PORT").unwrap_or(DEFAULT_PORT);
+        let route_prefix = settings
+            .string("ROUTE_PREFIX")
+            .map(|prefix| prefix.trim().trim_matches('/').to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("/{}", prefix))
+            .unwrap_or_default();
+        let success_status = settings
+            .parse_with("SUCCESS_STATUS", |raw| match raw.trim() {
+                "200" => Ok(StatusCode::OK),
+                "204" => Ok(StatusCode::NO_CONTENT),
+                _ => Err("is invalid, expected 200 or 204".to_string()),
+            })
+            .unwrap_or(StatusCode::OK);
+        let apply_delay = Duration::from_secs(settings.parse::<u64>("APPLY_DELAY_SECS").unwrap_or(0));
+        let async_apply = settings.flag("ASYNC_APPLY");
+        let metrics_port = settings.parse::<u16>("METRICS_PORT");
+        if metrics_port == Some(port) {
+            settings.error(format!("METRICS_PORT must differ from PORT ({})", port));
+        }
+        let metrics_namespace = settings
+            .string("METRICS_NAMESPACE")
+            .map(|namespace| namespace.trim().to_string())
+            .filter(|namespace| !namespace.is_empty())
+            .unwrap_or_else(|| metrics::DEFAULT_NAMESPACE.to_string());
+        if !is_metric_namespace(&metrics_namespace) {
+            settings.error(format!(
+                "METRICS_NAMESPACE='{}' is not a valid Prometheus name, expected [a-zA-Z_][a-zA-Z0-9_]*",
+                metrics_namespace
+            ));
+        }
+        let san_map = settings
+            .parse_with("SAN_CONFIG_MAP", |raw| cert::parse_san_map(raw).map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+        let allowed_config_ids = settings.list("ALLOWED_CONFIG_IDS");
+        if let Some(id) = allowed_config_ids.iter().find(|id| numeric_id(id).is_err()) {
+            settings.error(format!("ALLOWED_CONFIG_IDS contains '{}' which is not a numeric id", id));
+        }
+        let allowed_secrets = settings
+            .parse_with("ALLOWED_SECRETS", |raw| parse_allowed_secrets(raw).map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+        let allowed_fingerprints = settings
+            .parse_with("ALLOWED_FINGERPRINTS", |raw| parse_fingerprints(raw).map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+        let trusted_proxies = settings
+            .parse_with("TRUSTED_PROXIES", |raw| parse_trusted_proxies(raw).map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+
+        let verify_live = settings.flag("VERIFY_LIVE_HANDSHAKE");
+        let nodebalancer_host = settings.string("NODEBALANCER_HOST");
+        let verify_attempts = settings
+            .parse::<u32>("VERIFY_LIVE_ATTEMPTS")
+            .unwrap_or(DEFAULT_VERIFY_LIVE_ATTEMPTS)
+            .max(1);
+        let nodebalancer_host = nodebalancer_host.filter(|host| !host.is_empty());
+        let prefer_ip = settings
+            .parse_with("PREFER_IP", |raw| raw.trim().parse::<PreferIp>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+        let expiry_warn_days = settings
+            .parse::<i64>("EXPIRY_WARN_DAYS")
+            .unwrap_or(DEFAULT_EXPIRY_WARN_DAYS)
+            .max(0);
+        let deep_health_mode = settings
+            .parse_with("DEEP_HEALTH_MODE", |raw| raw.trim().parse::<DeepHealthMode>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or(DeepHealthMode::Full);
+        let chain_policy = settings
+            .parse_with("CHAIN_POLICY", |raw| raw.trim().parse::<ChainPolicy>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or(ChainPolicy::Warn);
+        let max_chain_certs = settings
+            .parse::<usize>("MAX_CHAIN_CERTS")
+            .unwrap_or(DEFAULT_MAX_CHAIN_CERTS)
+            .max(1);
+        let extra_health_urls = settings.list("EXTRA_HEALTH_URLS");
+        for url in &extra_health_urls {
+            if !reqwest::Url::parse(url).is_ok_and(|parsed| matches!(parsed.scheme(), "http" | "https")) {
+                settings.error(format!("EXTRA_HEALTH_URLS entry '{}' is not a valid http(s) URL", url));
+            }
+        }
+        let extra_health_timeout = Duration::from_secs(
+            settings
+                .parse::<u64>("EXTRA_HEALTH_TIMEOUT_SECS")
+                .unwrap_or(DEFAULT_EXTRA_HEALTH_TIMEOUT_SECS)
+                .max(1),
+        );
+        let live_verify = match (verify_live, &nodebalancer_host) {
+            (true, Some(host)) => Some(LiveVerifyConfig {
+                host: host.clone(),
+                attempts: verify_attempts,
+                delay: Duration::from_secs(DEFAULT_VERIFY_LIVE_DELAY_SECS),
+                prefer_ip,
+            }),
+            (true, None) => {
+                settings.error("NODEBALANCER_HOST must be set when VERIFY_LIVE_HANDSHAKE is enabled".to_string());
+                None
+            }
+            (false, _) => None,
+        };
+
+        let provider = settings
+            .parse_with("CERT_PROVIDER", |raw| raw.trim().parse::<ProviderKind>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or(ProviderKind::Linode);
+        let lb_type = settings
+            .parse_with("LINODE_LB_TYPE", |raw| raw.trim().parse::<LbType>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or(LbType::NodeBalancer);
+        let cert_mode = settings
+            .parse_with("LINODE_CERT_MODE", |raw| raw.trim().parse::<CertMode>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or(CertMode::Inline);
+        let clock_skew_secs = settings
+            .parse::<i64>("CLOCK_SKEW_SECS")
+            .unwrap_or(DEFAULT_CLOCK_SKEW_SECS)
+            .max(0);
+        let kube_max_retries = settings.parse::<u32>("KUBE_MAX_RETRIES").unwrap_or(DEFAULT_KUBE_MAX_RETRIES);
+        let linode_ratelimit_warn_below = settings
+            .parse::<i64>("LINODE_RATELIMIT_WARN_BELOW")
+            .unwrap_or(DEFAULT_LINODE_RATELIMIT_WARN_BELOW)
+            .max(0);
+        // Only the exact value enables it, a stray ENABLE_CHAOS=1 in prod is an error instead
+        let chaos_fail_rate = settings.parse::<f64>("CHAOS_FAIL_RATE").unwrap_or(0.0);
+        if !(0.0..=1.0).contains(&chaos_fail_rate) {
+            settings.error(format!("CHAOS_FAIL_RATE={} must be between 0 and 1", chaos_fail_rate));
+        }
+        let chaos_latency = Duration::from_millis(settings.parse::<u64>("CHAOS_LATENCY_MS").unwrap_or(0));
+        let chaos = match settings.string("ENABLE_CHAOS").as_deref().map(str::trim) {
+            None | Some("") | Some("false") => None,
+            Some("true") => Some(Chaos { fail_rate: chaos_fail_rate.clamp(0.0, 1.0), latency: chaos_latency }),
+            Some(other) => {
+                settings.error(format!("ENABLE_CHAOS='{}' is invalid, it must be exactly 'true' to inject failures", other));
+                None
+            }
+        };
+        let target_profiles = settings
+            .parse_with("TARGET_PROFILES", |raw| parse_target_profiles(raw).map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+        let ready_requires_sync = settings.flag("READY_REQUIRES_SYNC");
+        let include_timing = settings.flag("INCLUDE_TIMING");
+        let max_concurrent_updates = settings.parse::<usize>("MAX_CONCURRENT_UPDATES").map(|n| n.max(1));
+        let max_batch = settings.parse::<usize>("MAX_BATCH").unwrap_or(DEFAULT_MAX_BATCH);
+        let batch_concurrency = settings
+            .parse::<usize>("BATCH_CONCURRENCY")
+            .unwrap_or(DEFAULT_BATCH_CONCURRENCY)
+            .max(1);
+        let batch_fail_fast = settings.flag("BATCH_FAIL_FAST");
+
+        let max_parallel_config_updates = settings
+            .parse::<usize>("MAX_PARALLEL_CONFIG_UPDATES")
+            .unwrap_or(DEFAULT_MAX_PARALLEL_CONFIG_UPDATES)
+            .max(1);
+
+        let state_configmap = settings.string("STATE_CONFIGMAP").filter(|name| !name.is_empty());
+        let state_configmap_namespace = settings.string("STATE_CONFIGMAP_NAMESPACE");
+        let dedup_annotation = settings.flag("DEDUP_ANNOTATION");
+        let apply_window_tz = settings
+            .parse_with("APPLY_WINDOW_TZ", window::parse_offset)
+            .unwrap_or_else(|| FixedOffset::east_opt(0).expect("zero is a valid offset"));
+        let apply_window = settings
+            .parse_with("APPLY_WINDOW", |raw| ApplyWindow::parse(raw, apply_window_tz).map_err(|e| format!("is invalid: {}", e)));
+        let secret_keys = settings
+            .parse_with("SECRET_KEYS", |raw| parse_secret_keys(raw).map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or_default();
+        let raw_json_key = settings.string("SECRET_JSON_KEY").unwrap_or_default();
+        let json_cert_field = settings.string("SECRET_JSON_CERT_FIELD").filter(|field| !field.trim().is_empty());
+        let json_key_field = settings.string("SECRET_JSON_KEY_FIELD").filter(|field| !field.trim().is_empty());
+        let secret_format = match settings.string("SECRET_FORMAT").as_deref().map(str::trim) {
+            None | Some("") | Some("keys") => SecretFormat::Keys,
+            Some("json") => {
+                let key = secret_data_key(raw_json_key.trim(), DEFAULT_SECRET_JSON_KEY, &raw_json_key).unwrap_or_else(|_| {
+                    settings.error(format!("SECRET_JSON_KEY='{}' is not a valid secret data key", raw_json_key));
+                    DEFAULT_SECRET_JSON_KEY.to_string()
+                });
+                let layout = JsonSecretLayout {
+                    key,
+                    cert_field: json_cert_field.unwrap_or_else(|| DEFAULT_SECRET_JSON_CERT_FIELD.to_string()),
+                    key_field: json_key_field.unwrap_or_else(|| DEFAULT_SECRET_JSON_KEY_FIELD.to_string()),
+                };
+                if layout.cert_field == layout.key_field {
+                    settings.error("SECRET_JSON_CERT_FIELD and SECRET_JSON_KEY_FIELD must differ".to_string());
+                }
+                if !secret_keys.is_empty() {
+                    settings.error("SECRET_KEYS only applies with SECRET_FORMAT=keys".to_string());
+                }
+                SecretFormat::Json(layout)
+            }
+            Some(other) => {
+                settings.error(format!("SECRET_FORMAT='{}' is invalid, expected keys or json", other));
+                SecretFormat::Keys
+            }
+        };
+        let append_ca_crt = settings.flag("APPEND_CA_CRT");
+        let default_namespace = settings
+            .string("DEFAULT_NAMESPACE")
+            .map(|namespace| namespace.trim().to_string())
+            .filter(|namespace| !namespace.is_empty());
+        if let Some(namespace) = &default_namespace {
+            if !is_kube_name(namespace, MAX_NAMESPACE_LEN, false) {
+                settings.error(format!("DEFAULT_NAMESPACE='{}' is not a valid namespace", namespace));
+            }
+        }
+        let max_secret_cert_bytes = settings
+            .parse::<usize>("MAX_SECRET_CERT_BYTES")
+            .unwrap_or(DEFAULT_MAX_SECRET_CERT_BYTES)
+            .max(1);
+        let max_secret_key_bytes = settings
+            .parse::<usize>("MAX_SECRET_KEY_BYTES")
+            .unwrap_or(DEFAULT_MAX_SECRET_KEY_BYTES)
+            .max(1);
+        let allow_self_signed = settings.flag("ALLOW_SELF_SIGNED");
+
+        let enable_leader_election = settings.flag("ENABLE_LEADER_ELECTION");
+        let lease_name = settings.string("LEASE_NAME").unwrap_or_else(|| DEFAULT_LEASE_NAME.to_string());
+        let lease_namespace = settings.string("LEASE_NAMESPACE");
+        let lease_duration_secs = settings
+            .parse::<u64>("LEASE_DURATION_SECS")
+            .unwrap_or(DEFAULT_LEASE_DURATION_SECS)
+            .max(1);
+        let leader_election = enable_leader_election.then(|| LeaderSettings {
+            lease_name,
+            lease_namespace,
+            lease_duration: Duration::from_secs(lease_duration_secs),
+        });
+
+        let revalidate_interval = settings.parse::<u64>("REVALIDATE_INTERVAL_SECS");
+        let revalidate_targets = settings.string("REVALIDATE_TARGETS").unwrap_or_default();
+        let revalidate_targets = reconcile::parse_targets(&revalidate_targets)
+            .await
+            .unwrap_or_else(|e| {
+                settings.error(format!("REVALIDATE_TARGETS is invalid: {}", e));
+                Vec::new()
+            });
+        let revalidate = revalidate_interval
+            .map(|interval| (Duration::from_secs(interval.max(1)), revalidate_targets));
+        // Drift detection compares against the fingerprint NodeBalancer configs report
+        if revalidate.is_some() && lb_type == LbType::Aglb {
+            settings.error("REVALIDATE_INTERVAL_SECS is only supported with LINODE_LB_TYPE=nodebalancer".to_string());
+        }
+        let post_verify_attempts = settings
+            .parse::<u32>("POST_VERIFY_ATTEMPTS")
+            .unwrap_or(DEFAULT_POST_VERIFY_ATTEMPTS)
+            .max(1);
+        let post_verify_delay =
+            Duration::from_millis(settings.parse::<u64>("POST_VERIFY_DELAY_MS").unwrap_or(DEFAULT_POST_VERIFY_DELAY_MS));
+        let post_verify = settings
+            .flag("POST_VERIFY")
+            .then_some(PostVerifyConfig { attempts: post_verify_attempts, delay: post_verify_delay });
+        // Reads back ssl_fingerprint, which only NodeBalancer configs report
+        if post_verify.is_some() && lb_type == LbType::Aglb {
+            settings.error("POST_VERIFY is only supported with LINODE_LB_TYPE=nodebalancer".to_string());
+        }
+
+        let certificate_name = settings.string("CERTIFICATE_NAME").filter(|name| !name.is_empty());
+        let certificate_namespace = settings.string("CERTIFICATE_NAMESPACE").filter(|ns| !ns.is_empty());
+        if let Some(name) = certificate_name.as_deref().filter(|name| !is_kube_name(name, MAX_SECRET_NAME_LEN, true)) {
+            settings.error(format!("CERTIFICATE_NAME='{}' is not a valid resource name", name));
+        }
+        if let Some(namespace) = certificate_namespace.as_deref().filter(|ns| !is_kube_name(ns, MAX_NAMESPACE_LEN, false)) {
+            settings.error(format!("CERTIFICATE_NAMESPACE='{}' is not a valid namespace", namespace));
+        }
+        let watch_concurrency = settings
+            .parse::<usize>("WATCH_CONCURRENCY")
+            .unwrap_or(DEFAULT_WATCH_CONCURRENCY)
+            .max(1);
+
+        let input_mode = settings
+            .parse_with("INPUT_MODE", |raw| raw.trim().parse::<InputMode>().map_err(|e| format!("is invalid: {}", e)))
+            .unwrap_or(InputMode::Http);
+        let queue_url = settings.string("QUEUE_URL").filter(|url| !url.trim().is_empty());
+        let queue_stream = settings
+            .string("QUEUE_STREAM")
+            .filter(|stream| !stream.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_QUEUE_STREAM.to_string());
+        let queue_group = settings
+            .string("QUEUE_GROUP")
+            .filter(|group| !group.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_QUEUE_GROUP.to_string());
+        // Unique per replica, so each one only replays its own unacked entries
+        let queue_consumer = settings
+            .string("QUEUE_CONSUMER")
+            .filter(|consumer| !consumer.trim().is_empty())
+            .or_else(|| env::var("POD_NAME").ok())
+            .or_else(|| env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "cert-webhook".to_string());
+        let queue = match (input_mode.consumes_queue(), queue_url) {
+            (true, Some(url)) => RedisStreamConfig::parse_url(&url, queue_stream, queue_group, queue_consumer)
+                .map_err(|e| settings.error(format!("QUEUE_URL {}", e)))
+                .ok(),
+            (true, None) => {
+                settings.error("QUEUE_URL must be set when INPUT_MODE is queue or both".to_string());
+                None
+            }
+            (false, _) => None,
+        };
+
+        let alert_url = settings.string("ALERT_WEBHOOK_URL").filter(|url| !url.is_empty());
+        let alert_threshold = settings
+            .parse::<usize>("VALIDATION_ALERT_THRESHOLD")
+            .unwrap_or(DEFAULT_VALIDATION_ALERT_THRESHOLD)
+            .max(1);
+        let alert_window = settings
+            .parse::<u64>("VALIDATION_ALERT_WINDOW_SECS")
+            .unwrap_or(DEFAULT_VALIDATION_ALERT_WINDOW_SECS)
+            .max(1);
+        let alert_cooldown = settings
+            .parse::<u64>("ALERT_COOLDOWN_SECS")
+            .unwrap_or(DEFAULT_ALERT_COOLDOWN_SECS);
+        let alert_template = settings.parse_with("ALERT_TEMPLATE", AlertTemplate::parse);
+        if let Some(url) = alert_url.as_deref().filter(|url| reqwest::Url::parse(url).is_err()) {
+            settings.error(format!("ALERT_WEBHOOK_URL='{}' is not a valid URL", url));
+        }
+        let alert = alert_url.map(|url| AlertConfig {
+            url,
+            threshold: alert_threshold,
+            window: Duration::from_secs(alert_window),
+            cooldown: Duration::from_secs(alert_cooldown),
+            template: alert_template,
+        });
+
+        let admin_token = settings.string("ADMIN_TOKEN").map(|token| token.trim().to_string()).filter(|token| !token.is_empty());
+        // Not trimmed, the sender signs with the exact bytes
+        let webhook_hmac_secret = settings.string("WEBHOOK_HMAC_SECRET").filter(|secret| !secret.is_empty());
+        let signature_max_age_secs = settings
+            .parse::<u64>("SIGNATURE_MAX_AGE_SECS")
+            .unwrap_or(DEFAULT_SIGNATURE_MAX_AGE_SECS)
+            .max(1);
+
+        Config {
+            linode_token,
+            linode_token_file,
+            nodebalancer_id,
+            https_config_ids,
+            linode_api_url,
+            linode_auth_header,
+            linode_auth_scheme,
+            linode_ca_bundle,
+            linode_tls_insecure,
+            http_timeout: Duration::from_secs(http_timeout),
+            http_connect_timeout: Duration::from_secs(http_connect_timeout),
+            http_user_agent,
+            port,
+            route_prefix,
+            success_status,
+            apply_delay,
+            async_apply,
+            metrics_port,
+            metrics_namespace,
+            san_map,
+            allowed_config_ids,
+            allowed_secrets,
+            allowed_fingerprints,
+            trusted_proxies,
+            live_verify,
+            post_verify,
+            nodebalancer_host,
+            prefer_ip,
+            expiry_warn_days,
+            deep_health_mode,
+            chain_policy,
+            max_chain_certs,
+            extra_health_urls,
+            extra_health_timeout,
+            provider,
+            lb_type,
+            cert_mode,
+            clock_skew_secs,
+            kube_max_retries,
+            target_profiles,
+            linode_ratelimit_warn_below,
+            chaos,
+            ready_requires_sync,
+            include_timing,
+            max_concurrent_updates,
+            max_batch,
+            batch_concurrency,
+            batch_fail_fast,
+            max_parallel_config_updates,
+            state_configmap,
+            state_configmap_namespace,
+            apply_window,
+            dedup_annotation,
+            append_ca_crt,
+            default_namespace,
+            max_secret_cert_bytes,
+            max_secret_key_bytes,
+            allow_self_signed,
+            secret_keys,
+            secret_format,
+            leader_election,
+            revalidate,
+            certificate_name,
+            certificate_namespace,
+            watch_concurrency,
+            input_mode,
+            queue,
+            alert,
+            admin_token,
+            webhook_hmac_secret,
+            signature_max_age_secs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn with_config_ids(ids: &str) -> Result<Config, Vec<String>> {
+        Config::from_pairs(&[("LINODE_TOKEN", "token"), ("NODEBALANCER_ID", "1"), ("HTTPS_CONFIG_ID", ids)]).await
+    }
+
+    #[actix_web::test]
+    async fn https_config_id_takes_a_comma_separated_list() {
+        assert_eq!(with_config_ids("2").await.unwrap().https_config_ids, ["2"]);
+        // Whitespace and empty entries are ignored, repeats are dropped
+        assert_eq!(with_config_ids("2, 3,,2 ").await.unwrap().https_config_ids, ["2", "3"]);
+
+        let errors = with_config_ids("2,abc").await.err();
+        assert_eq!(errors.unwrap(), ["HTTPS_CONFIG_ID contains 'abc' which is not a numeric id"]);
+        // Only separators leave nothing to push to
+        assert!(with_config_ids(",").await.is_err());
+        assert!(with_config_ids(" , ").await.is_err());
+    }
+}
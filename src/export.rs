@@ -0,0 +1,134 @@
+use crate::linode::LbType;
+use crate::{cert, admin_rejection, AppState, SecretFormat};
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Snapshot of the effective configuration and in-memory state, for backups
+/// and DR notes. Tokens, keys and certificates are never part of it, only
+/// their fingerprints.
+pub async fn export_state(req: HttpRequest, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if let Some(response) = admin_rejection(&state, &req) {
+        return response;
+    }
+
+    let history = state.history.entries();
+    // Entries are newest first, so the first success per secret is its latest
+    let mut last_success: BTreeMap<String, i64> = BTreeMap::new();
+    for entry in history.iter().filter(|entry| entry.http_status < 300) {
+        last_success
+            .entry(format!("{}/{}", entry.namespace, entry.secret_name))
+            .or_insert(entry.at);
+    }
+
+    let snapshot = serde_json::json!({
+        "exported_at": cert::unix_now(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "config": effective_config(&state),
+        "state": {
+            "last_applied": state.last_applied.snapshot(),
+            "pending": state.pending.snapshot().into_keys().collect::<Vec<_>>(),
+            "last_success": last_success,
+            "history": history,
+        },
+    });
+
+    HttpResponse::Ok().json(snapshot)
+}
+
+/// The settings held by the running state, redacted like the export. Also
+/// logged once at startup.
+pub fn effective_config(state: &AppState) -> serde_json::Value {
+    let lb_type = match state.linode.lb_type {
+        LbType::NodeBalancer => "nodebalancer",
+        LbType::Aglb => "aglb",
+    };
+    serde_json::json!({
+        "provider": state.provider.name(),
+        "linode_api_url": state.linode.api_url,
+        "linode_token_file": state.linode.token.file(),
+        "linode_auth_header": state.linode.auth_header.as_str(),
+        "linode_auth_scheme": state.linode.auth_scheme,
+        "lb_type": lb_type,
+        "nodebalancer_id": state.linode.nodebalancer_id,
+        "https_config_ids": state.https_config_ids,
+        "san_map": state.san_map.iter().map(|(san, id)| format!("{}={}", san, id)).collect::<Vec<_>>(),
+        "allowed_config_ids": state.allowed_config_ids,
+        "allowed_secrets": state.allowed_secrets.iter().collect::<BTreeMap<_, _>>(),
+        "allowed_fingerprints": state.allowed_fingerprints,
+        "max_batch": state.max_batch,
+        "batch_concurrency": state.batch_concurrency,
+        "batch_fail_fast": state.batch_fail_fast,
+        "max_parallel_config_updates": state.max_parallel_config_updates,
+        "dedup_annotation": state.annotations.is_some(),
+        "secret_keys": state
+            .secret_keys
+            .iter()
+            .map(|(secret, keys)| (secret.clone(), format!("{}:{}", keys.cert, keys.key)))
+            .collect::<BTreeMap<_, _>>(),
+        "secret_format": match &state.secret_format {
+            SecretFormat::Keys => serde_json::json!({ "format": "keys" }),
+            SecretFormat::Json(layout) => serde_json::json!({
+                "format": "json",
+                "key": layout.key,
+                "cert_field": layout.cert_field,
+                "key_field": layout.key_field,
+            }),
+        },
+        "append_ca_crt": state.append_ca_crt,
+        "default_namespace": state.default_namespace,
+        "max_secret_cert_bytes": state.max_secret_cert_bytes,
+        "max_secret_key_bytes": state.max_secret_key_bytes,
+        "allow_self_signed": state.allow_self_signed,
+        "chain_policy": format!("{:?}", state.chain_policy).to_lowercase(),
+        "max_chain_certs": state.max_chain_certs,
+        "live_verify": state.live_verify.as_ref().map(|verify| serde_json::json!({
+            "host": verify.host,
+            "attempts": verify.attempts,
+            "delay_secs": verify.delay.as_secs(),
+        })),
+        "post_verify": state.post_verify.as_ref().map(|verify| serde_json::json!({
+            "attempts": verify.attempts,
+            "delay_ms": verify.delay.as_millis() as u64,
+        })),
+        "nodebalancer_host": state.nodebalancer_host,
+        "prefer_ip": format!("{:?}", state.prefer_ip).to_lowercase(),
+        "expiry_warn_days": state.expiry_warn_days,
+        "clock_skew_secs": state.clock_skew_secs,
+        "kube_max_retries": state.kube_max_retries,
+        "target_profiles": state
+            .linode
+            .profiles
+            .iter()
+            .map(|(config_id, profile)| {
+                (config_id.clone(), serde_json::json!({
+                    "timeout_secs": profile.timeout.map(|timeout| timeout.as_secs()),
+                    "max_retries": profile.max_retries,
+                    "retry_base_ms": profile.retry_base.map(|base| base.as_millis() as u64),
+                }))
+            })
+            .collect::<BTreeMap<_, _>>(),
+        "chaos": state.linode.chaos.map(|chaos| serde_json::json!({
+            "fail_rate": chaos.fail_rate,
+            "latency_ms": chaos.latency.as_millis() as u64,
+        })),
+        "deep_health_mode": format!("{:?}", state.deep_health_mode).to_lowercase(),
+        "extra_health_urls": state
+            .extra_health_urls
+            .iter()
+            .map(|url| url.split('?').next().unwrap_or(url))
+            .collect::<Vec<_>>(),
+        "extra_health_timeout_secs": state.extra_health_timeout.as_secs(),
+        "ready_requires_sync": state.ready_requires_sync,
+        "include_timing": state.include_timing,
+        "success_status": state.success_status.as_u16(),
+        "apply_delay_secs": state.apply_delay.as_secs(),
+        "async_apply": state.async_apply,
+        "apply_window": state.apply_window.as_ref().map(ToString::to_string),
+        "trusted_proxies": state.trusted_proxies.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "alerting": state.alerts.is_some(),
+        "admin_endpoints": state.admin_token.is_some(),
+        "webhook_hmac": state.webhook_hmac_secret.is_some(),
+        "signature_max_age_secs": state.signature_max_age_secs,
+    })
+}
@@ -0,0 +1,60 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+const HISTORY_LEN: usize = 50;
+
+/// Outcome of one processed update request.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub at: i64,
+    pub namespace: String,
+    pub secret_name: String,
+    pub http_status: u16,
+    pub status: String,
+    pub message: Option<String>,
+    // notAfter of the leaf in the secret, when it could be read
+    pub not_after: Option<i64>,
+    // One per targeted config, empty when the request failed before any push
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub configs: Vec<ConfigResult>,
+}
+
+/// What one config of a request ended up with.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigResult {
+    pub config_id: String,
+    // applied, unconfirmed, unchanged or failed
+    pub outcome: &'static str,
+    // Leaf pushed to or already on the config, unknown after a failure or a key-only update
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// The most recent update results, newest first. Kept in memory only, so it
+/// starts empty after a restart.
+pub struct History {
+    entries: Mutex<VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            entries: Mutex::new(VecDeque::with_capacity(HISTORY_LEN)),
+        }
+    }
+
+    pub fn record(&self, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == HISTORY_LEN {
+            entries.pop_back();
+        }
+        entries.push_front(entry);
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
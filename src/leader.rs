@@ -0,0 +1,141 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::{Api, PostParams};
+use kube::Client;
+use log::{debug, info, warn};
+use prometheus::IntGauge;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub struct LeaderConfig {
+    pub lease_name: String,
+    pub lease_namespace: String,
+    pub identity: String,
+    pub lease_duration: Duration,
+    pub renew_interval: Duration,
+}
+
+/// Shared view of whether this replica currently holds the lease.
+#[derive(Clone)]
+pub struct Leadership {
+    leader: Arc<AtomicBool>,
+}
+
+impl Leadership {
+    /// Leadership when election is disabled: the only replica always leads.
+    pub fn always(gauge: &IntGauge) -> Self {
+        gauge.set(1);
+        Leadership { leader: Arc::new(AtomicBool::new(true)) }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+}
+
+/// Starts the election loop in the background. The replica steps down as soon
+/// as it fails to renew, so at worst there is a short window with no leader
+/// rather than two.
+pub fn spawn(client: Client, config: LeaderConfig, gauge: IntGauge) -> Leadership {
+    let leadership = Leadership { leader: Arc::new(AtomicBool::new(false)) };
+    gauge.set(0);
+
+    let handle = leadership.clone();
+    tokio::spawn(async move {
+        let leases: Api<Lease> = Api::namespaced(client, &config.lease_namespace);
+        info!(
+            "Starting leader election for lease {}/{} as {}",
+            config.lease_namespace, config.lease_name, config.identity
+        );
+
+        loop {
+            let is_leader = match try_acquire_or_renew(&leases, &config).await {
+                Ok(is_leader) => is_leader,
+                Err(e) => {
+                    warn!("Failed to acquire or renew lease {}: {}", config.lease_name, e);
+                    false
+                }
+            };
+
+            if is_leader != handle.is_leader() {
+                if is_leader {
+                    info!("Acquired leadership of lease {}", config.lease_name);
+                } else {
+                    warn!("Lost leadership of lease {}", config.lease_name);
+                }
+            }
+            handle.leader.store(is_leader, Ordering::SeqCst);
+            gauge.set(is_leader as i64);
+
+            sleep(config.renew_interval).await;
+        }
+    });
+
+    leadership
+}
+
+async fn try_acquire_or_renew(leases: &Api<Lease>, config: &LeaderConfig) -> Result<bool, kube::Error> {
+    let now = Utc::now();
+    let duration_secs = config.lease_duration.as_secs() as i32;
+
+    let lease = match leases.get_opt(&config.lease_name).await? {
+        Some(lease) => lease,
+        None => {
+            debug!("Lease {} not found, creating it", config.lease_name);
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(config.lease_name.clone()),
+                    namespace: Some(config.lease_namespace.clone()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(config.identity.clone()),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_duration_seconds: Some(duration_secs),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match leases.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(resp)) if resp.code == 409 => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+    };
+
+    let spec = lease.spec.clone().unwrap_or_default();
+    let held_by_us = spec.holder_identity.as_deref() == Some(config.identity.as_str());
+
+    if !held_by_us {
+        let expired = match (&spec.renew_time, spec.lease_duration_seconds) {
+            (Some(renew), Some(secs)) => renew.0 + chrono::Duration::seconds(secs as i64) < now,
+            _ => true,
+        };
+        if spec.holder_identity.is_some() && !expired {
+            return Ok(false);
+        }
+        debug!("Lease {} expired, taking over from {:?}", config.lease_name, spec.holder_identity);
+    }
+
+    let mut new_spec = spec.clone();
+    if !held_by_us {
+        new_spec.holder_identity = Some(config.identity.clone());
+        new_spec.acquire_time = Some(MicroTime(now));
+        new_spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+    }
+    new_spec.renew_time = Some(MicroTime(now));
+    new_spec.lease_duration_seconds = Some(duration_secs);
+
+    // Replacing with the fetched resourceVersion makes concurrent takeovers conflict
+    let mut updated = lease;
+    updated.spec = Some(new_spec);
+    match leases.replace(&config.lease_name, &PostParams::default(), &updated).await {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(resp)) if resp.code == 409 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
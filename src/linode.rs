@@ -0,0 +1,630 @@
+use log::{debug, info, trace, warn};
+use prometheus::{IntCounter, IntCounterVec, IntGauge};
+use reqwest::header::{HeaderMap, HeaderName};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Connection details shared by every call to the Linode API.
+#[derive(Clone)]
+pub struct LinodeClient {
+    pub http: reqwest::Client,
+    // Base URL including the API version, e.g. https://api.linode.com/v4
+    pub api_url: String,
+    pub token: Arc<LinodeToken>,
+    // Where the token goes, `Authorization: Bearer <token>` unless overridden
+    pub auth_header: HeaderName,
+    pub auth_scheme: String,
+    // Id of the NodeBalancer, or of the load balancer when `lb_type` is AGLB
+    pub nodebalancer_id: String,
+    pub lb_type: LbType,
+    // Overrides per config id, from TARGET_PROFILES
+    pub profiles: Arc<HashMap<String, TargetProfile>>,
+    pub ratelimit: RateLimits,
+    // linode_errors_total, by error_reason_label
+    pub errors: IntCounterVec,
+    // Failure injection on config updates, never set unless ENABLE_CHAOS=true
+    pub chaos: Option<Chaos>,
+}
+
+/// The API token, from LINODE_TOKEN or LINODE_TOKEN_FILE. A file-backed token
+/// is re-read when Linode answers 401, so a rotated secret is picked up
+/// without a restart.
+pub struct LinodeToken {
+    value: RwLock<String>,
+    file: Option<String>,
+    pub reloads: IntCounter,
+}
+
+impl LinodeToken {
+    pub fn new(value: String, file: Option<String>, reloads: IntCounter) -> Self {
+        LinodeToken { value: RwLock::new(value), file, reloads }
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn get(&self) -> String {
+        self.value.read().unwrap().clone()
+    }
+
+    /// Re-reads LINODE_TOKEN_FILE, true when it now holds a different token
+    /// worth retrying with. Always false for a token from the environment.
+    pub fn reload(&self) -> bool {
+        let Some(path) = &self.file else {
+            return false;
+        };
+        let token = match std::fs::read_to_string(path) {
+            Ok(token) => token.trim().to_string(),
+            Err(e) => {
+                warn!("Failed to re-read LINODE_TOKEN_FILE {}: {}", path, e);
+                return false;
+            }
+        };
+        let mut value = self.value.write().unwrap();
+        if token.is_empty() || *value == token {
+            warn!("Linode rejected the token and LINODE_TOKEN_FILE {} holds no new one", path);
+            return false;
+        }
+        *value = token;
+        self.reloads.inc();
+        info!("Reloaded the Linode token from {}", path);
+        true
+    }
+}
+
+/// Tracks the rate limit Linode reports on each response.
+#[derive(Clone)]
+pub struct RateLimits {
+    pub remaining: IntGauge,
+    pub limit: IntGauge,
+    // Remaining requests below which a warning is logged
+    pub warn_below: i64,
+}
+
+impl RateLimits {
+    /// Updates the gauges from `X-RateLimit-Limit`/`X-RateLimit-Remaining`,
+    /// leaving them as they were when a response doesn't carry them.
+    pub fn observe(&self, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<i64>().ok();
+        if let Some(limit) = header("x-ratelimit-limit") {
+            self.limit.set(limit);
+        }
+        if let Some(remaining) = header("x-ratelimit-remaining") {
+            self.remaining.set(remaining);
+            if remaining < self.warn_below {
+                warn!(
+                    "Linode rate limit nearly exhausted: {} of {} requests left (resets at {})",
+                    remaining,
+                    self.limit.get(),
+                    headers.get("x-ratelimit-reset").and_then(|v| v.to_str().ok()).unwrap_or("unknown")
+                );
+            }
+        }
+    }
+}
+
+/// ENABLE_CHAOS: delays config updates and fails a share of them, so retries
+/// and alerts can be watched end to end in staging.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    // Share of calls failed, between 0 and 1
+    pub fail_rate: f64,
+    // Added before every call
+    pub latency: Duration,
+}
+
+impl Chaos {
+    /// Waits the configured latency, then fails with `fail_rate` probability.
+    /// The failure is retried like a connection error.
+    pub async fn inject(&self, operation: &str) -> Result<(), ChaosError> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        let mut bytes = [0u8; 4];
+        // Falling back to never failing is fine, this is a test aid
+        if openssl::rand::rand_bytes(&mut bytes).is_ok() && (u32::from_ne_bytes(bytes) as f64 / u32::MAX as f64) < self.fail_rate {
+            warn!("Chaos: failing {}", operation);
+            return Err(ChaosError(operation.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ChaosError(String);
+
+impl std::fmt::Display for ChaosError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "injected failure of {} (ENABLE_CHAOS)", self.0)
+    }
+}
+
+impl std::error::Error for ChaosError {}
+
+/// Timeout and retry overrides for one target, from `TARGET_PROFILES`. The
+/// target's value wins when set, otherwise HTTP_TIMEOUT_SECS and the built-in
+/// retry defaults (2 retries, 500ms base backoff) apply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TargetProfile {
+    pub timeout: Option<Duration>,
+    // Retries after the first attempt
+    pub max_retries: Option<u32>,
+    // Backoff before the first retry, doubled for each one after
+    pub retry_base: Option<Duration>,
+}
+
+impl LinodeClient {
+    /// Value of the auth header, the bare token when the scheme is empty.
+    pub fn auth_value(&self) -> String {
+        if self.auth_scheme.is_empty() {
+            self.token.get()
+        } else {
+            format!("{} {}", self.auth_scheme, self.token.get())
+        }
+    }
+
+    /// Reads a failed response into an error, counting it by reason.
+    pub async fn api_error(&self, response: reqwest::Response) -> LinodeApiError {
+        let err = LinodeApiError::from_response(response).await;
+        self.errors.with_label_values(&[err.reason]).inc();
+        err
+    }
+
+    /// Counts a request that got no response at all by its transport
+    /// reason, handing the error back for `?`.
+    pub fn transport_error(&self, err: reqwest::Error) -> reqwest::Error {
+        let reason = transport_reason(&err);
+        debug!("Linode API call got no response ({}): {:?}", reason, err);
+        self.errors.with_label_values(&[reason]).inc();
+        err
+    }
+
+    /// Adds the auth header to a request.
+    pub fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.header(self.auth_header.clone(), self.auth_value())
+    }
+
+    pub fn profile(&self, target: &str) -> TargetProfile {
+        self.profiles.get(target).copied().unwrap_or_default()
+    }
+
+    /// Applies the target's timeout to a request, if it has its own.
+    pub fn with_target_timeout(&self, request: reqwest::RequestBuilder, target: &str) -> reqwest::RequestBuilder {
+        match self.profile(target).timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    /// The load balancer resource itself, whichever kind it is.
+    pub fn load_balancer_url(&self) -> String {
+        match self.lb_type {
+            LbType::NodeBalancer => format!("{}/nodebalancers/{}", self.api_url, self.nodebalancer_id),
+            LbType::Aglb => format!("{}/aclb/{}", self.api_url, self.nodebalancer_id),
+        }
+    }
+
+    pub fn config_url(&self, config_id: &str) -> String {
+        match self.lb_type {
+            LbType::NodeBalancer => format!("{}/configs/{}", self.load_balancer_url(), config_id),
+            LbType::Aglb => format!("{}/configurations/{}", self.load_balancer_url(), config_id),
+        }
+    }
+}
+
+/// Kind of load balancer certificates are pushed to, from `LINODE_LB_TYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LbType {
+    // Classic NodeBalancer, the cert and key are set inline on a config
+    NodeBalancer,
+    // Application Load Balancer, certificates are uploaded as resources and
+    // referenced from a configuration
+    Aglb,
+}
+
+impl std::str::FromStr for LbType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nodebalancer" => Ok(LbType::NodeBalancer),
+            "aglb" => Ok(LbType::Aglb),
+            other => Err(format!("unknown load balancer type '{}', expected nodebalancer or aglb", other)),
+        }
+    }
+}
+
+/// A NodeBalancer config as returned by `GET .../configs/{id}`. Every field
+/// except `id` and `port` is optional, and anything not modelled here is kept
+/// in `extra`, so a config read and written back loses nothing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinodeConfigDetail {
+    pub id: u64,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stickiness: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<String>,
+    // Only meaningful for https configs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher_suite: Option<String>,
+    // Linode returns "<REDACTED>" for both once they are set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_commonname: Option<String>,
+    // Fingerprint of the certificate currently served, null when none is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_timeout: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_passive: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodebalancer_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes_status: Option<NodesStatus>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl LinodeConfigDetail {
+    /// Fingerprint of the certificate the config serves, or `None` on a fresh
+    /// config, where Linode reports `ssl_fingerprint` (and `ssl_cert`) as null
+    /// or empty. Anything compared against it must then be applied.
+    pub fn served_fingerprint(&self) -> Option<&str> {
+        self.ssl_fingerprint.as_deref().map(str::trim).filter(|fingerprint| !fingerprint.is_empty())
+    }
+}
+
+/// Backend health counts nested in a config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodesStatus {
+    #[serde(default)]
+    pub up: u32,
+    #[serde(default)]
+    pub down: u32,
+}
+
+/// Whether a failed Linode call is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Retryable,
+    Permanent,
+    // 401, the token is expired or invalid, only a new token helps
+    Unauthorized,
+    // 403, the token lacks a scope or the grant, a new token won't fix that
+    Forbidden,
+}
+
+/// Linode's standard error envelope, `{"errors":[{"reason":"...","field":"..."}]}`.
+#[derive(Debug, Deserialize)]
+struct ErrorEnvelope {
+    #[serde(default)]
+    errors: Vec<ErrorEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorEntry {
+    #[serde(default)]
+    reason: String,
+}
+
+// Reasons Linode uses for transient conditions, which can come with a 400
+const RETRYABLE_REASONS: &[&str] = &[
+    "too many requests",
+    "rate limit",
+    "please try again",
+    "try again later",
+    "temporarily unavailable",
+    "timed out",
+    "is busy",
+];
+
+/// Reasons from Linode's error envelope, empty when the body isn't one.
+pub fn error_reasons(body: &str) -> Vec<String> {
+    serde_json::from_str::<ErrorEnvelope>(body)
+        .map(|envelope| {
+            envelope
+                .errors
+                .into_iter()
+                .map(|e| e.reason)
+                .filter(|r| !r.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Decides retryability from the status and the reasons in the error
+/// envelope. A transient reason wins over the status code, otherwise 5xx, 408
+/// and 429 are retried, 401 and 403 get their own classes and every other
+/// status (e.g. "Invalid certificate") is permanent.
+pub fn classify_linode_error(status: StatusCode, body: &str) -> ErrorClass {
+    let transient_reason = error_reasons(body).iter().any(|reason| {
+        let reason = reason.to_lowercase();
+        RETRYABLE_REASONS.iter().any(|r| reason.contains(r))
+    });
+
+    if transient_reason
+        || status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+    {
+        ErrorClass::Retryable
+    } else if status == StatusCode::UNAUTHORIZED {
+        ErrorClass::Unauthorized
+    } else if status == StatusCode::FORBIDDEN {
+        ErrorClass::Forbidden
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+// Substrings of Linode's reasons and the label they're counted under, first match wins
+const REASON_LABELS: &[(&str, &str)] = &[
+    ("too many requests", "rate_limited"),
+    ("rate limit", "rate_limited"),
+    ("timed out", "timeout"),
+    ("temporarily unavailable", "unavailable"),
+    ("is busy", "unavailable"),
+    ("try again", "unavailable"),
+    ("invalid token", "unauthorized"),
+    ("not found", "not_found"),
+    ("ssl_key", "invalid_key"),
+    ("private key", "invalid_key"),
+    ("ssl_cert", "invalid_certificate"),
+    ("certificate", "invalid_certificate"),
+];
+
+/// Reduces an error to one of a fixed set of labels, so the reason label of
+/// linode_errors_total stays bounded whatever Linode writes. Reasons from the
+/// envelope are matched first, then the class and status decide, and anything
+/// else is `other`.
+pub fn error_reason_label(status: StatusCode, body: &str, class: ErrorClass) -> &'static str {
+    for reason in error_reasons(body) {
+        let reason = reason.to_lowercase();
+        if let Some((_, label)) = REASON_LABELS.iter().find(|(needle, _)| reason.contains(needle)) {
+            return label;
+        }
+    }
+    match class {
+        ErrorClass::Unauthorized => "unauthorized",
+        ErrorClass::Forbidden => "forbidden",
+        _ if status == StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+        _ if status == StatusCode::REQUEST_TIMEOUT => "timeout",
+        _ if status == StatusCode::NOT_FOUND => "not_found",
+        _ if status.is_server_error() => "server_error",
+        _ => "other",
+    }
+}
+
+/// Classifies a call that failed without a status code. DNS, connect and
+/// timeout failures are transient (a CoreDNS restart, an overloaded node), as
+/// is a connection dropped mid-request. An invalid URL, a redirect loop or a
+/// body that doesn't parse will fail the same way next time.
+pub fn classify_transport_error(err: &reqwest::Error) -> ErrorClass {
+    if err.is_connect() || err.is_timeout() {
+        ErrorClass::Retryable
+    } else if err.is_builder() || err.is_redirect() || err.is_decode() {
+        ErrorClass::Permanent
+    } else {
+        ErrorClass::Retryable
+    }
+}
+
+/// linode_errors_total label of a transport error. reqwest reports a failed
+/// lookup as a connect error, so DNS is told apart by the underlying error.
+pub fn transport_reason(err: &reqwest::Error) -> &'static str {
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        let message = cause.to_string().to_lowercase();
+        if message.contains("dns error") || message.contains("failed to lookup address") {
+            return "dns";
+        }
+        source = cause.source();
+    }
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        "connect"
+    } else if err.is_decode() {
+        "decode"
+    } else {
+        "transport"
+    }
+}
+
+/// A non-success response from the Linode API. The body is read lossily since
+/// 5xx responses from the edge are often empty or not UTF-8.
+#[derive(Debug)]
+pub struct LinodeApiError {
+    pub status: StatusCode,
+    pub body: String,
+    pub class: ErrorClass,
+    // Normalized for the linode_errors_total label
+    pub reason: &'static str,
+}
+
+impl LinodeApiError {
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let body = match response.bytes().await {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).trim().to_string(),
+            Err(e) => format!("<failed to read body: {}>", e),
+        };
+        trace!("Linode response: {} {}", status, body);
+        let class = classify_linode_error(status, &body);
+        let reason = error_reason_label(status, &body, class);
+        LinodeApiError { status, body, class, reason }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.class == ErrorClass::Retryable
+    }
+
+    /// Whether reloading the token could make the call succeed.
+    pub fn is_unauthorized(&self) -> bool {
+        self.class == ErrorClass::Unauthorized
+    }
+}
+
+impl std::fmt::Display for LinodeApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reasons = error_reasons(&self.body);
+        if !reasons.is_empty() {
+            write!(f, "Linode API returned {}: {}", self.status, reasons.join("; "))
+        } else if self.body.is_empty() {
+            write!(f, "Linode API returned {} with an empty body", self.status)
+        } else {
+            write!(f, "Linode API returned {}: {}", self.status, self.body)
+        }
+    }
+}
+
+impl std::error::Error for LinodeApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::{self, MockLinode};
+    use crate::testutil;
+
+    // Error bodies as the Linode API returns them, with the status they came with
+    const TOO_MANY_REQUESTS: (u16, &str) = (429, r#"{"errors": [{"reason": "Too many requests"}]}"#);
+    const INVALID_CERTIFICATE: (u16, &str) =
+        (400, r#"{"errors": [{"reason": "Invalid certificate", "field": "ssl_cert"}]}"#);
+    const INVALID_KEY: (u16, &str) =
+        (400, r#"{"errors": [{"reason": "ssl_key cannot be decrypted or is not a valid private key", "field": "ssl_key"}]}"#);
+    const INVALID_TOKEN: (u16, &str) = (401, r#"{"errors": [{"reason": "Invalid Token"}]}"#);
+    const UNAUTHORIZED: (u16, &str) = (403, r#"{"errors": [{"reason": "Unauthorized"}]}"#);
+    const NOT_FOUND: (u16, &str) = (404, r#"{"errors": [{"reason": "Not found"}]}"#);
+    const UNEXPECTED: (u16, &str) = (500, r#"{"errors": [{"reason": "An unexpected error occurred."}]}"#);
+    const BUSY: (u16, &str) = (400, r#"{"errors": [{"reason": "NodeBalancer 1234 is busy; please try again later"}]}"#);
+    const GATEWAY_HTML: (u16, &str) = (502, "<html><body><h1>502 Bad Gateway</h1></body></html>");
+
+    // GET /nodebalancers/{id}/configs/{id} for an https config with a certificate set
+    const HTTPS_CONFIG: &str = r#"{
+        "algorithm": "roundrobin",
+        "check": "http_body",
+        "check_attempts": 3,
+        "check_body": "it works",
+        "check_interval": 90,
+        "check_passive": true,
+        "check_path": "/test",
+        "check_timeout": 10,
+        "cipher_suite": "recommended",
+        "id": 4567,
+        "nodebalancer_id": 12345,
+        "nodes_status": {"down": 0, "up": 4},
+        "port": 443,
+        "protocol": "https",
+        "proxy_protocol": "none",
+        "ssl_cert": "<REDACTED>",
+        "ssl_commonname": "www.example.com",
+        "ssl_fingerprint": "00:01:02:03:04:05:06:07:08:09:0A:0B:0C:0D:0E:0F:10:11:12:13",
+        "ssl_key": "<REDACTED>",
+        "stickiness": "http_cookie",
+        "udp_check_port": 80,
+        "udp_session_timeout": 16
+    }"#;
+
+    #[test]
+    fn captured_config_deserializes() {
+        let config: LinodeConfigDetail = serde_json::from_str(HTTPS_CONFIG).unwrap();
+        assert_eq!((config.id, config.port, config.nodebalancer_id), (4567, 443, Some(12345)));
+        assert_eq!(config.protocol.as_deref(), Some("https"));
+        assert_eq!(config.ssl_cert.as_deref(), Some("<REDACTED>"));
+        assert_eq!(config.ssl_commonname.as_deref(), Some("www.example.com"));
+        assert_eq!(config.served_fingerprint(), Some("00:01:02:03:04:05:06:07:08:09:0A:0B:0C:0D:0E:0F:10:11:12:13"));
+        assert_eq!((config.check_interval, config.check_timeout, config.check_attempts), (Some(90), Some(10), Some(3)));
+        assert_eq!(config.check_passive, Some(true));
+        let nodes = config.nodes_status.as_ref().unwrap();
+        assert_eq!((nodes.up, nodes.down), (4, 0));
+
+        // Fields the struct doesn't model survive a round trip
+        assert_eq!(config.extra.get("udp_check_port"), Some(&serde_json::json!(80)));
+        let written = serde_json::to_value(&config).unwrap();
+        assert_eq!(written, serde_json::from_str::<serde_json::Value>(HTTPS_CONFIG).unwrap());
+    }
+
+    #[test]
+    fn missing_fingerprint_is_not_served() {
+        for fingerprint in [r#"null"#, r#""""#, r#""  ""#] {
+            let body = format!(r#"{{"id": 1, "port": 443, "ssl_cert": null, "ssl_fingerprint": {}}}"#, fingerprint);
+            let config: LinodeConfigDetail = serde_json::from_str(&body).unwrap();
+            assert_eq!(config.served_fingerprint(), None, "{}", body);
+        }
+        let config: LinodeConfigDetail = serde_json::from_str(r#"{"id": 1, "port": 443}"#).unwrap();
+        assert_eq!(config.served_fingerprint(), None);
+    }
+
+    fn classify((status, body): (u16, &str)) -> (ErrorClass, &'static str) {
+        let status = StatusCode::from_u16(status).unwrap();
+        let class = classify_linode_error(status, body);
+        (class, error_reason_label(status, body, class))
+    }
+
+    #[test]
+    fn linode_error_fixtures_are_classified() {
+        assert_eq!(classify(TOO_MANY_REQUESTS), (ErrorClass::Retryable, "rate_limited"));
+        assert_eq!(classify(INVALID_CERTIFICATE), (ErrorClass::Permanent, "invalid_certificate"));
+        assert_eq!(classify(INVALID_KEY), (ErrorClass::Permanent, "invalid_key"));
+        assert_eq!(classify(INVALID_TOKEN), (ErrorClass::Unauthorized, "unauthorized"));
+        assert_eq!(classify(UNAUTHORIZED), (ErrorClass::Forbidden, "forbidden"));
+        assert_eq!(classify(NOT_FOUND), (ErrorClass::Permanent, "not_found"));
+        assert_eq!(classify(UNEXPECTED), (ErrorClass::Retryable, "server_error"));
+        // A transient reason is retried even on a 400
+        assert_eq!(classify(BUSY), (ErrorClass::Retryable, "unavailable"));
+        assert_eq!(classify(GATEWAY_HTML), (ErrorClass::Retryable, "server_error"));
+    }
+
+    #[test]
+    fn error_reasons_reads_the_envelope() {
+        assert_eq!(error_reasons(INVALID_CERTIFICATE.1), ["Invalid certificate"]);
+        assert!(error_reasons(GATEWAY_HTML.1).is_empty());
+        assert!(error_reasons("").is_empty());
+    }
+
+    #[actix_web::test]
+    async fn bodyless_500_keeps_its_status_and_is_retryable() {
+        let mock = MockLinode::start().await.unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, Default::default());
+        let result = crate::update_linode_config(
+            &state.linode,
+            selftest::ERROR_CONFIG_ID,
+            crate::CertMode::Inline,
+            Some("cert"),
+            Some("key"),
+        )
+        .await;
+        mock.stop().await;
+
+        let err = result.unwrap_err();
+        let err = err.downcast_ref::<LinodeApiError>().expect("a Linode API error");
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.body, "");
+        assert!(err.is_retryable());
+        assert_eq!(err.reason, "server_error");
+        assert_eq!(err.to_string(), "Linode API returned 500 Internal Server Error with an empty body");
+    }
+}
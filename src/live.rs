@@ -0,0 +1,189 @@
+use crate::linode::LbType;
+use crate::{admin_rejection, cert, get_linode_config, resolve_config_ids, serving_port, validate, verify, AppState, SecretRef};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Body of `POST /verify`: the secret to compare against, and optionally one
+/// config to look at instead of those the secret routes to.
+#[derive(Debug, Deserialize)]
+pub struct VerifyRequest {
+    #[serde(rename = "secretRef")]
+    secret_ref: SecretRef,
+    #[serde(rename = "configId", default)]
+    config_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SecretSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_after: Option<i64>,
+    sans: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct LiveConfig {
+    config_id: String,
+    // What the Linode API reports for the config, NodeBalancers only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reported_fingerprint: Option<String>,
+    // What a TLS handshake gets, when NODEBALANCER_HOST is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    served: Option<ServedCert>,
+    // Unknown when neither of the above could be read
+    matches_secret: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ServedCert {
+    fingerprint: String,
+    not_before: i64,
+    not_after: i64,
+    expired: bool,
+    days_left: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyReport {
+    in_sync: bool,
+    secret: SecretSummary,
+    configs: Vec<LiveConfig>,
+}
+
+/// Compares what the load balancer serves with the secret, without pushing
+/// anything. Answers 200 when every config serves the secret's leaf and 409
+/// otherwise, including when a config couldn't be checked. Needs ADMIN_TOKEN,
+/// the report describes the secret and the load balancer.
+pub async fn verify_live(state: web::Data<Arc<AppState>>, req: HttpRequest, body: web::Json<VerifyRequest>) -> HttpResponse {
+    if let Some(response) = admin_rejection(&state, &req) {
+        return response;
+    }
+    let mut body = body.into_inner();
+    body.secret_ref.apply_default_namespace(&state);
+    let (cert_pem, _) = match validate::read_secret(&state, &body.secret_ref, body.config_id.as_deref()).await {
+        Ok(pair) => pair,
+        Err(response) => return response,
+    };
+
+    let secret = SecretSummary {
+        fingerprint: cert::leaf_fingerprint(&cert_pem).ok(),
+        not_after: cert::leaf_validity(&cert_pem).ok().map(|(_, not_after)| not_after),
+        sans: cert::leaf_sans(&cert_pem).unwrap_or_default(),
+    };
+    let config_ids = match body.config_id {
+        Some(config_id) => vec![config_id],
+        None => resolve_config_ids(&state, &cert_pem),
+    };
+
+    let mut configs = Vec::with_capacity(config_ids.len());
+    for config_id in config_ids {
+        configs.push(inspect_config(&state, config_id, &cert_pem, secret.fingerprint.as_deref()).await);
+    }
+    let in_sync = configs.iter().all(|config| config.matches_secret == Some(true));
+    info!(
+        "Verified {}/{} against the live config(s): {}",
+        body.secret_ref.namespace,
+        body.secret_ref.name,
+        if in_sync { "in sync" } else { "out of sync" }
+    );
+
+    let report = VerifyReport { in_sync, secret, configs };
+    if in_sync {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::Conflict().json(report)
+    }
+}
+
+async fn inspect_config(state: &AppState, config_id: String, cert_pem: &str, fingerprint: Option<&str>) -> LiveConfig {
+    let mut live = LiveConfig { config_id, ..LiveConfig::default() };
+
+    let port = match state.linode.lb_type {
+        LbType::NodeBalancer => match get_linode_config(&state.linode, &live.config_id).await {
+            Ok(config) => {
+                live.reported_fingerprint = config.served_fingerprint().map(str::to_string);
+                if live.reported_fingerprint.is_none() {
+                    live.errors.push("the config reports no certificate".to_string());
+                }
+                Some(config.port)
+            }
+            Err(e) => {
+                live.errors.push(format!("failed to read the config: {}", e));
+                None
+            }
+        },
+        LbType::Aglb => match serving_port(&state.linode, &live.config_id).await {
+            Ok(port) => Some(port),
+            Err(e) => {
+                live.errors.push(format!("failed to read the configuration: {}", e));
+                None
+            }
+        },
+    };
+
+    match (&state.nodebalancer_host, port) {
+        (Some(host), Some(port)) => match verify::served_certificate(host, port, state.prefer_ip).await {
+            Ok(der) => match cert::der_validity(&der) {
+                Ok((not_before, not_after)) => {
+                    let now = cert::unix_now();
+                    live.served = Some(ServedCert {
+                        fingerprint: cert::der_fingerprint(&der),
+                        not_before,
+                        not_after,
+                        expired: not_after <= now,
+                        days_left: (not_after - now) / 86_400,
+                    });
+                }
+                Err(e) => live.errors.push(format!("failed to parse the served certificate: {}", e)),
+            },
+            Err(e) => live.errors.push(format!("failed to read the served certificate from {}:{}: {}", host, port, e)),
+        },
+        (None, _) => debug!("NODEBALANCER_HOST is not set, not checking what config {} serves", live.config_id),
+        (Some(_), None) => {}
+    }
+
+    let mut comparisons = Vec::new();
+    if let Some(served) = &live.served {
+        comparisons.push(Some(served.fingerprint.as_str()) == fingerprint);
+    }
+    if let Some(reported) = &live.reported_fingerprint {
+        comparisons.push(cert::fingerprint_matches(cert_pem, reported));
+    }
+    live.matches_secret = (!comparisons.is_empty()).then(|| comparisons.iter().all(|matches| *matches));
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, testutil};
+    use actix_web::http::StatusCode;
+
+    #[actix_web::test]
+    async fn verify_needs_the_admin_token() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let config = testutil::config(&mock, &[("ADMIN_TOKEN", "admin")]).await;
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+        let app = actix_web::test::init_service(actix_web::App::new()
+            .app_data(web::Data::new(state))
+            .route("/verify", web::post().to(verify_live))).await;
+        let body = serde_json::json!({ "secretRef": { "name": selftest::SECRET_NAME, "namespace": selftest::NAMESPACE } });
+
+        let req = actix_web::test::TestRequest::post().uri("/verify").set_json(&body).to_request();
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = actix_web::test::TestRequest::post()
+            .uri("/verify")
+            .insert_header(("authorization", "Bearer admin"))
+            .set_json(&body)
+            .to_request();
+        // The mock config serves nothing yet
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+        mock.stop().await;
+    }
+}
@@ -1,121 +1,751 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, middleware, Error};
-use kube::{
-    api::Api,
-    Client,
-};
+// serde_json::json! recurses once per key, the /export snapshot needs more than the default 128
+#![recursion_limit = "256"]
+
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, middleware, Error, http::StatusCode};
+use actix_web::dev::Service;
+use actix_web::error::JsonPayloadError;
+use kube::Client;
 use k8s_openapi::api::core::v1::Secret;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use base64::{engine::general_purpose, Engine as _};
-use log::{info, error, debug, warn};
+use log::{info, error, debug, warn, trace, log_enabled};
 use reqwest::{
-    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
     ClientBuilder,
 };
 use std::env;
+use futures::stream::{self, StreamExt};
 use std::time::Duration;
 use tokio::time::sleep;
 use actix_web::middleware::Logger;
 use actix_web_prom::PrometheusMetricsBuilder;
+use prometheus::Encoder;
+use ipnet::IpNet;
+use linode::{LinodeApiError, LinodeClient, LinodeConfigDetail};
+use state::FingerprintStore as _;
+
+mod admin;
+mod aglb;
+mod alert;
+mod cert;
+mod config;
+mod export;
+mod leader;
+mod live;
+mod history;
+mod linode;
+mod metrics;
+mod provider;
+mod queue;
+mod reconcile;
+mod secrets;
+mod selftest;
+mod state;
+mod status;
+#[cfg(test)]
+mod testutil;
+mod validate;
+mod verify;
+mod watch;
+mod window;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct HookRequest {
     namespace: String,
     secret_name: String,
+    config_id: Option<String>,
+    update_cert: bool,
+    update_key: bool,
+}
+
+impl From<&CertManagerHook> for HookRequest {
+    fn from(hook: &CertManagerHook) -> Self {
+        HookRequest {
+            namespace: hook.secret_ref.namespace.clone(),
+            secret_name: hook.secret_ref.name.clone(),
+            config_id: hook.config_id.clone(),
+            update_cert: hook.update_cert,
+            update_key: hook.update_key,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ApiResponse {
     status: String,
     message: Option<String>,
+    // Stable machine-readable error code, absent on success
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    ValidationError,
+    ConfigNotAllowed,
+    SecretNotAllowed,
+    FingerprintNotAllowed,
+    InvalidJson,
+    PayloadTooLarge,
+    SecretNotFound,
+    CertInvalid,
+    KubeError,
+    LinodeError,
+    LiveVerifyMismatch,
+    LiveVerifyFailed,
+    SecretDecodeFailed,
+    CertExpiring,
+    PartialUpdateRejected,
+    Unauthorized,
+    InvalidSignature,
+    SignatureExpired,
+    SelfSigned,
+    SecretTooLarge,
+    Paused,
+    OutsideApplyWindow,
+    ChainIncomplete,
+    ChainTooLong,
+    PostVerifyUnconfirmed,
+    KeyMismatch,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct LinodeConfigsResponse {
-    data: Vec<LinodeConfig>,
+struct BatchResponse {
+    status: String,
+    // "best_effort" processes every item, "fail_fast" stops at the first failure
+    mode: String,
+    succeeded: usize,
+    failed: usize,
+    // Items never attempted because an earlier one failed in fail_fast mode
+    skipped: usize,
+    // Index of the item that stopped a fail_fast batch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failed_item: Option<usize>,
+    results: Vec<BatchItemResult>,
+}
+
+/// Query parameters of /update-batch.
+#[derive(Debug, Deserialize)]
+struct BatchOptions {
+    // Overrides BATCH_FAIL_FAST for this request
+    #[serde(rename = "failFast")]
+    fail_fast: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct LinodeConfig {
-    id: u32,
-    port: u16,
+struct BatchItemResult {
+    namespace: String,
+    secret_name: String,
+    http_status: u16,
+    #[serde(flatten)]
+    response: ApiResponse,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timing: Option<metrics::Timing>,
+}
+
+/// Body of /health/deep: the core status plus each EXTRA_HEALTH_URLS result.
+#[derive(Debug, Serialize)]
+struct DeepHealthResponse {
+    #[serde(flatten)]
+    response: ApiResponse,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dependencies: Vec<DependencyStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct DependencyStatus {
+    url: String,
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// An ApiResponse with the request id alongside, for errors answered before
+/// a handler gets to log anything.
+#[derive(Debug, Serialize)]
+struct IdentifiedResponse {
+    #[serde(flatten)]
+    response: ApiResponse,
+    request_id: String,
+}
+
+/// An ApiResponse with the INCLUDE_TIMING breakdown alongside.
+#[derive(Debug, Serialize)]
+struct TimedResponse {
+    #[serde(flatten)]
+    response: ApiResponse,
+    timing: metrics::Timing,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CertManagerHook {
     #[serde(rename = "secretRef")]
     secret_ref: SecretRef,
+    // Overrides HTTPS_CONFIG_ID (and SAN routing) for this request
+    #[serde(rename = "configId", default, skip_serializing_if = "Option::is_none")]
+    config_id: Option<String>,
+    // Set one of these to false for a partial update that keeps the live cert or key
+    #[serde(rename = "updateCert", default = "default_true")]
+    update_cert: bool,
+    #[serde(rename = "updateKey", default = "default_true")]
+    update_key: bool,
     // Add other fields as needed
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SecretRef {
     name: String,
+    // May be left out when DEFAULT_NAMESPACE is set
+    #[serde(default)]
+    namespace: String,
+}
+
+impl SecretRef {
+    /// Fills an empty namespace from DEFAULT_NAMESPACE. Without one it stays
+    /// empty, and validation rejects it as before.
+    fn apply_default_namespace(&mut self, state: &AppState) {
+        if let (true, Some(namespace)) = (self.namespace.trim().is_empty(), &state.default_namespace) {
+            debug!("No namespace given for secret {}, using {}", self.name, namespace);
+            self.namespace = namespace.clone();
+        }
+    }
+}
+
+/// How the certificate is handed to Linode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CertMode {
+    // ssl_cert/ssl_key sent inline in the config update
+    Inline,
+    // Uploaded separately and referenced by id
+    Reference,
+}
+
+impl std::str::FromStr for CertMode {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inline" => Ok(CertMode::Inline),
+            "reference" => Ok(CertMode::Reference),
+            other => Err(format!("unknown cert mode '{}', expected inline or reference", other)),
+        }
+    }
+}
+
+/// Entry point an update came through, the `source` label of updates_total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpdateSource {
+    // /update-nodebalancer-cert and /update-batch
+    Webhook,
+    // Certificate watch (CERTIFICATE_NAME)
+    Watch,
+    // Scheduled revalidation (REVALIDATE_INTERVAL_SECS)
+    Schedule,
+    // INPUT_MODE=queue|both
+    Queue,
+    // Queued outside APPLY_WINDOW, run once it opened
+    ApplyWindow,
+}
+
+impl UpdateSource {
+    fn as_str(self) -> &'static str {
+        match self {
+            UpdateSource::Webhook => "webhook",
+            UpdateSource::Watch => "watch",
+            UpdateSource::Schedule => "schedule",
+            UpdateSource::Queue => "queue",
+            UpdateSource::ApplyWindow => "apply_window",
+        }
+    }
+}
+
+/// What the deep health check asks Linode for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeepHealthMode {
+    // GET the load balancer itself
+    Full,
+    // GET /profile, which only confirms connectivity and the token
+    Token,
+}
+
+impl std::str::FromStr for DeepHealthMode {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(DeepHealthMode::Full),
+            "token" => Ok(DeepHealthMode::Token),
+            other => Err(format!("unknown deep health mode '{}', expected full or token", other)),
+        }
+    }
+}
+
+/// What happens to a bundle whose chain can't be followed from the leaf to a
+/// root, e.g. a missing intermediate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChainPolicy {
+    // Reject it with a 422
+    Strict,
+    // Log it and push anyway
+    Warn,
+    // Don't look at the chain
+    Off,
+}
+
+impl std::str::FromStr for ChainPolicy {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ChainPolicy::Strict),
+            "warn" => Ok(ChainPolicy::Warn),
+            "off" => Ok(ChainPolicy::Off),
+            other => Err(format!("unknown chain policy '{}', expected strict, warn or off", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NotImplementedError(String);
+
+impl std::fmt::Display for NotImplementedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not implemented: {}", self.0)
+    }
+}
+
+impl std::error::Error for NotImplementedError {}
+
+/// A secret field that isn't valid base64 or UTF-8. This is a data problem,
+/// so it is never retried.
+#[derive(Debug)]
+struct SecretDecodeError {
+    field: String,
+    namespace: String,
+    name: String,
+    action: &'static str,
+    detail: String,
+}
+
+impl std::fmt::Display for SecretDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to {} {} for {}/{}: {}", self.action, self.field, self.namespace, self.name, self.detail)
+    }
+}
+
+impl std::error::Error for SecretDecodeError {}
+
+/// A secret field over its size limit, rejected before being decoded.
+#[derive(Debug)]
+struct SecretTooLargeError {
+    field: String,
     namespace: String,
+    name: String,
+    size: usize,
+    limit: usize,
+}
+
+impl std::fmt::Display for SecretTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {}/{} is {} bytes, over the {}-byte limit",
+            self.field, self.namespace, self.name, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for SecretTooLargeError {}
+
+/// Secret data keys holding the certificate and the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SecretKeys {
+    cert: String,
+    key: String,
+}
+
+impl Default for SecretKeys {
+    fn default() -> Self {
+        SecretKeys {
+            cert: "tls.crt".to_string(),
+            key: "tls.key".to_string(),
+        }
+    }
+}
+
+/// How the certificate and key are laid out in a secret.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum SecretFormat {
+    // One data key each, tls.crt/tls.key unless SECRET_KEYS says otherwise
+    #[default]
+    Keys,
+    // A single data key holding a JSON object with both PEMs as string fields
+    Json(JsonSecretLayout),
+}
+
+/// Where SECRET_FORMAT=json finds the PEMs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct JsonSecretLayout {
+    // Data key holding the JSON object
+    key: String,
+    cert_field: String,
+    key_field: String,
+}
+
+/// The certificate and key read from a TLS secret.
+struct SecretData {
+    cert: String,
+    key: String,
 }
 
 struct AppState {
     kube_client: Client,
-    http_client: reqwest::Client,
-    linode_token: String,
-    nodebalancer_id: String,
-    https_config_id: String,
+    // Where TLS secrets are read, the cluster outside of selftest
+    secrets: Box<dyn secrets::SecretSource>,
+    linode: LinodeClient,
+    // HTTPS_CONFIG_ID, one or more configs, in order
+    https_config_ids: Vec<String>,
+    // SAN -> config id routing, empty when every cert goes to https_config_ids
+    san_map: Vec<(String, String)>,
+    // Config ids a request may target through configId, empty allows any
+    allowed_config_ids: Vec<String>,
+    // ALLOWED_SECRETS, namespace to permitted names or `prefix*` patterns
+    allowed_secrets: HashMap<String, Vec<String>>,
+    // ALLOWED_FINGERPRINTS, leaf fingerprints that may be pushed, empty pins nothing
+    allowed_fingerprints: Vec<String>,
+    max_batch: usize,
+    batch_concurrency: usize,
+    // Default of the failFast batch option
+    batch_fail_fast: bool,
+    // Configs of a single request updated at once
+    max_parallel_config_updates: usize,
+    last_applied: state::StateStore,
+    // APPLY_WINDOW, and the updates that arrived outside it, keyed by pending_key
+    apply_window: Option<window::ApplyWindow>,
+    pending: state::StateStore,
+    // Data keys per "namespace/secret", for secrets not using tls.crt/tls.key
+    secret_keys: HashMap<String, SecretKeys>,
+    // Plain data keys or a JSON blob
+    secret_format: SecretFormat,
+    // Append the secret's ca.crt, when it has one, to the certificate
+    append_ca_crt: bool,
+    // Namespace of secretRefs that leave it out
+    default_namespace: Option<String>,
+    // Size limits of the certificate (ca.crt included) and key fields, before decoding
+    max_secret_cert_bytes: usize,
+    max_secret_key_bytes: usize,
+    // Push leaves that are their own issuer, otherwise rejected with 422
+    allow_self_signed: bool,
+    // DEDUP_ANNOTATION, the last-applied fingerprint also kept on each secret
+    annotations: Option<Box<dyn state::FingerprintStore>>,
+    history: history::History,
+    metrics: metrics::Metrics,
+    live_verify: Option<verify::LiveVerifyConfig>,
+    // Read configs back after a PUT until they report the pushed certificate
+    post_verify: Option<verify::PostVerifyConfig>,
+    nodebalancer_host: Option<String>,
+    // Address family tried first when connecting to nodebalancer_host
+    prefer_ip: verify::PreferIp,
+    deep_health_mode: DeepHealthMode,
+    // Whether an incomplete chain blocks an update
+    chain_policy: ChainPolicy,
+    // Bundles with more certificates than this are rejected
+    max_chain_certs: usize,
+    // Extra dependencies /health/deep checks, each with `extra_health_timeout`
+    extra_health_urls: Vec<String>,
+    extra_health_timeout: Duration,
+    // Client for those, without the Linode-specific roots and TLS overrides
+    health_http: reqwest::Client,
+    // /health/cert turns unhealthy this many days before the served cert expires
+    expiry_warn_days: i64,
+    // Where certificates get installed, the Linode API unless CERT_PROVIDER says otherwise
+    provider: Box<dyn provider::CertProvider>,
+    // 200 answers successful updates with an ApiResponse, 204 with an empty body
+    success_status: StatusCode,
+    // Wait before reading the secret, so a hook fired mid-write sees the settled data
+    apply_delay: Duration,
+    // Answer 202 and update in the background instead of holding the request
+    async_apply: bool,
+    trusted_proxies: Arc<Vec<IpNet>>,
+    // Set when ALERT_WEBHOOK_URL is configured
+    alerts: Option<alert::FailureAlerts>,
+    // Bearer token for the admin endpoints, which are disabled without one
+    admin_token: Option<String>,
+    // WEBHOOK_HMAC_SECRET, update requests must be signed with it when set
+    webhook_hmac_secret: Option<String>,
+    // SIGNATURE_MAX_AGE_SECS, how stale a signed request's X-Timestamp may be
+    signature_max_age_secs: u64,
+    // Tolerance for notBefore/notAfter checks against the CA's clock
+    clock_skew_secs: i64,
+    // Retries of a failed secret read, separate from the Linode budget
+    kube_max_retries: u32,
+    // Add a kube/Linode/retries breakdown to update responses
+    include_timing: bool,
+    // /ready waits for `synced` when set
+    ready_requires_sync: bool,
+    // Set once an update or revalidation confirmed the certificate is applied
+    synced: AtomicBool,
+    // Bounds concurrent updates across all workers when MAX_CONCURRENT_UPDATES is set
+    update_permits: Option<tokio::sync::Semaphore>,
 }
 
 const MAX_RETRIES: u32 = 3;
+// Same as actix's default Logger format, with the peer address replaced by the resolved client IP
+// and the request id appended
+const LOG_FORMAT: &str = r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{request_id}xi"#;
 const RETRY_DELAY_MS: u64 = 500;
+const MAX_JSON_BYTES: usize = 256 * 1024;  // 256k payload limit
+const MAX_NAMESPACE_LEN: usize = 63;
+const MAX_SECRET_NAME_LEN: usize = 253;
+const CA_CRT_KEY: &str = "ca.crt";
+const SHUTDOWN_FLUSH_TIMEOUT_SECS: u64 = 10;
+// Hex HMAC-SHA256 of "<X-Timestamp>.<raw body>", checked when WEBHOOK_HMAC_SECRET is set
+const SIGNATURE_HEADER: &str = "x-signature-256";
+// Unix seconds the request was signed at, so a captured request can't be replayed later
+const TIMESTAMP_HEADER: &str = "x-timestamp";
+// From SIGTERM, for the watch queue to finish its pushes, leaves room for the flush in a 30s grace period
+const SHUTDOWN_DRAIN_TIMEOUT_SECS: u64 = 20;
 
 async fn health_check() -> impl Responder {
     HttpResponse::Ok().json(ApiResponse {
         status: "healthy".to_string(),
         message: None,
+        code: None,
+    })
+}
+
+/// Readiness probe. With READY_REQUIRES_SYNC it stays 503 until an update or a
+/// revalidation has confirmed the certificate is in place.
+async fn readiness_check(state: web::Data<Arc<AppState>>) -> impl Responder {
+    if state.ready_requires_sync && !state.synced.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json(ApiResponse {
+            status: "not_ready".to_string(),
+            message: Some("Waiting for a first successful certificate sync".to_string()),
+            code: None,
+        });
+    }
+    HttpResponse::Ok().json(ApiResponse {
+        status: "ready".to_string(),
+        message: None,
+        code: None,
     })
 }
 
+/// Checks Kubernetes and Linode, then every EXTRA_HEALTH_URLS dependency.
+/// Any failure makes the whole check 503, the dependencies are listed either way.
 async fn deep_health_check(state: web::Data<Arc<AppState>>) -> impl Responder {
+    let (mut status, mut response) = core_health(&state).await;
+    
+    let dependencies: Vec<DependencyStatus> = stream::iter(&state.extra_health_urls)
+        .map(|url| check_dependency(&state, url))
+        .buffered(state.extra_health_urls.len().max(1))
+        .collect()
+        .await;
+    let failed = dependencies.iter().filter(|dependency| !dependency.healthy).count();
+    if failed > 0 && status.is_success() {
+        status = StatusCode::SERVICE_UNAVAILABLE;
+        response = ApiResponse {
+            status: "degraded".to_string(),
+            message: Some(format!("{} of {} extra health dependencies failed", failed, dependencies.len())),
+            code: None,
+        };
+    }
+    
+    HttpResponse::build(status).json(DeepHealthResponse { response, dependencies })
+}
+
+async fn check_dependency(state: &AppState, url: &str) -> DependencyStatus {
+    // Query strings may carry credentials, keep them out of the response and logs
+    let display = url.split('?').next().unwrap_or(url).to_string();
+    match state.health_http.get(url).timeout(state.extra_health_timeout).send().await {
+        Ok(response) if response.status().is_success() => DependencyStatus {
+            url: display,
+            healthy: true,
+            status: Some(response.status().as_u16()),
+            error: None,
+        },
+        Ok(response) => {
+            warn!("Health dependency {} responded with status {}", display, response.status());
+            DependencyStatus {
+                url: display,
+                healthy: false,
+                status: Some(response.status().as_u16()),
+                error: None,
+            }
+        }
+        Err(e) => {
+            let e = e.without_url();
+            warn!("Health dependency {} is unreachable: {}", display, e);
+            DependencyStatus {
+                url: display,
+                healthy: false,
+                status: None,
+                error: Some(e.to_string()),
+            }
+        }
+    }
+}
+
+/// The Kubernetes and Linode checks every deep health check runs.
+async fn core_health(state: &AppState) -> (StatusCode, ApiResponse) {
     // Check if we can connect to Kubernetes
     match state.kube_client.apiserver_version().await {
         Ok(_) => {
             // Check if we can connect to Linode API
-            let url = format!("https://api.linode.com/v4/nodebalancers/{}", state.nodebalancer_id);
-            match state.http_client.get(&url)
-                .header(AUTHORIZATION, format!("Bearer {}", state.linode_token))
+            let url = match state.deep_health_mode {
+                DeepHealthMode::Full => state.linode.load_balancer_url(),
+                DeepHealthMode::Token => format!("{}/profile", state.linode.api_url),
+            };
+            match state.linode.authorize(state.linode.http.get(&url))
                 .send()
                 .await
             {
                 Ok(response) => {
+                    state.linode.ratelimit.observe(response.headers());
                     if response.status().is_success() {
-                        HttpResponse::Ok().json(ApiResponse {
+                        (StatusCode::OK, ApiResponse {
                             status: "healthy".to_string(),
                             message: None,
+                            code: None,
                         })
                     } else {
                         warn!("Linode API responded with status: {}", response.status());
-                        HttpResponse::ServiceUnavailable().json(ApiResponse {
+                        (StatusCode::SERVICE_UNAVAILABLE, ApiResponse {
                             status: "degraded".to_string(),
                             message: Some(format!("Linode API responded with status: {}", response.status())),
+                            code: Some(ErrorCode::LinodeError),
                         })
                     }
                 },
                 Err(e) => {
                     error!("Failed to connect to Linode API: {}", e);
-                    HttpResponse::ServiceUnavailable().json(ApiResponse {
+                    (StatusCode::SERVICE_UNAVAILABLE, ApiResponse {
                         status: "degraded".to_string(),
                         message: Some(format!("Failed to connect to Linode API: {}", e)),
+                        code: Some(ErrorCode::LinodeError),
                     })
                 }
             }
         },
         Err(e) => {
             error!("Failed to connect to Kubernetes API: {}", e);
-            HttpResponse::ServiceUnavailable().json(ApiResponse {
+            (StatusCode::SERVICE_UNAVAILABLE, ApiResponse {
                 status: "degraded".to_string(),
                 message: Some(format!("Failed to connect to Kubernetes API: {}", e)),
+                code: Some(ErrorCode::KubeError),
             })
         }
     }
 }
 
+/// Reports whether the certificates served by the NodeBalancer are outside the
+/// EXPIRY_WARN_DAYS window. The served leaf is read through a TLS handshake,
+/// since Linode redacts ssl_cert from the config.
+async fn cert_health_check(state: web::Data<Arc<AppState>>) -> impl Responder {
+    let Some(host) = &state.nodebalancer_host else {
+        return HttpResponse::ServiceUnavailable().json(ApiResponse {
+            status: "degraded".to_string(),
+            message: Some("NODEBALANCER_HOST is not set, cannot check the served certificate".to_string()),
+            code: Some(ErrorCode::CertExpiring),
+        });
+    };
+    
+    let mut config_ids = state.https_config_ids.clone();
+    for (_, config_id) in &state.san_map {
+        if !config_ids.contains(config_id) {
+            config_ids.push(config_id.clone());
+        }
+    }
+    
+    let now = cert::unix_now();
+    let warn_secs = state.expiry_warn_days.saturating_mul(86_400);
+    let mut problems = Vec::new();
+    for config_id in &config_ids {
+        let not_after = match serving_port(&state.linode, config_id).await {
+            Ok(port) => match verify::served_certificate(host, port, state.prefer_ip).await {
+                Ok(der) => cert::der_not_after(&der),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        
+        match not_after {
+            Ok(not_after) if not_after <= now => {
+                problems.push(format!("config {} serves a certificate that expired {}s ago", config_id, now - not_after));
+            }
+            Ok(not_after) if not_after - now <= warn_secs => {
+                problems.push(format!(
+                    "config {} serves a certificate expiring in {} day(s)",
+                    config_id,
+                    (not_after - now) / 86_400
+                ));
+            }
+            Ok(not_after) => debug!("Config {} certificate expires in {}s", config_id, not_after - now),
+            Err(e) => problems.push(format!("failed to check the certificate of config {}: {}", config_id, e)),
+        }
+    }
+    
+    if problems.is_empty() {
+        HttpResponse::Ok().json(ApiResponse {
+            status: "healthy".to_string(),
+            message: None,
+            code: None,
+        })
+    } else {
+        warn!("Certificate health check failed: {}", problems.join("; "));
+        HttpResponse::ServiceUnavailable().json(ApiResponse {
+            status: "degraded".to_string(),
+            message: Some(problems.join("; ")),
+            code: Some(ErrorCode::CertExpiring),
+        })
+    }
+}
+
+/// Port a config listens on, read from whichever kind of load balancer is in use.
+async fn serving_port(linode: &LinodeClient, config_id: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    match linode.lb_type {
+        linode::LbType::NodeBalancer => Ok(get_linode_config(linode, config_id).await?.port),
+        linode::LbType::Aglb => Ok(aglb::get_configuration(linode, config_id).await?.port),
+    }
+}
+
+/// Serves the registry, as OpenMetrics to clients that accept it and in the
+/// Prometheus text format otherwise.
+async fn metrics_export(req: HttpRequest, registry: web::Data<prometheus::Registry>) -> HttpResponse {
+    let accepts_openmetrics = req.headers()
+        .get_all(actix_web::http::header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/openmetrics-text"));
+    if accepts_openmetrics {
+        return HttpResponse::Ok()
+            .content_type(metrics::OPENMETRICS_FORMAT)
+            .body(metrics::encode_openmetrics(&registry.gather()));
+    }
+    
+    let encoder = prometheus::TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&registry.gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(buffer),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 async fn validate_hook_request(req: &HookRequest) -> Result<(), String> {
     if req.namespace.is_empty() {
         return Err("namespace cannot be empty".to_string());
@@ -123,249 +753,2467 @@ async fn validate_hook_request(req: &HookRequest) -> Result<(), String> {
     if req.secret_name.is_empty() {
         return Err("secret_name cannot be empty".to_string());
     }
-    // Validate that namespace and secret_name don't contain invalid characters
-    if !req.namespace.chars().all(|c| c.is_alphanumeric() || c == '-') {
+    // Validate that namespace and secret_name are names Kubernetes would accept,
+    // anything else can only come back from the apiserver as a confusing error
+    if !is_kube_name(&req.namespace, MAX_NAMESPACE_LEN, false) {
         return Err("namespace contains invalid characters".to_string());
     }
-    if !req.secret_name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.') {
+    if !is_kube_name(&req.secret_name, MAX_SECRET_NAME_LEN, true) {
         return Err("secret_name contains invalid characters".to_string());
     }
+    if let Some(config_id) = &req.config_id {
+        if config_id.is_empty() || !config_id.chars().all(|c| c.is_ascii_digit()) {
+            return Err("configId must be numeric".to_string());
+        }
+    }
+    if !req.update_cert && !req.update_key {
+        return Err("at least one of updateCert and updateKey must be true".to_string());
+    }
     Ok(())
 }
 
+/// RFC 1123 label (or subdomain when `allow_dots`), as used for namespace and
+/// secret names: lowercase ASCII alphanumerics and '-', starting and ending
+/// with an alphanumeric.
+fn is_kube_name(name: &str, max_len: usize, allow_dots: bool) -> bool {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    name.len() <= max_len
+        && name.starts_with(alphanumeric)
+        && name.ends_with(alphanumeric)
+        && name.chars().all(|c| alphanumeric(c) || c == '-' || (allow_dots && c == '.'))
+}
+
+/// Whether ALLOWED_SECRETS lets the secret be pushed. Once it is set, a
+/// namespace it doesn't list has no secret allowed.
+fn secret_allowed(allowed: &HashMap<String, Vec<String>>, namespace: &str, name: &str) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    allowed.get(namespace).is_some_and(|names| {
+        names.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        })
+    })
+}
+
+/// Handles both POST and PUT, the body and the outcome don't depend on the method.
 async fn update_nodebalancer_cert(
+    req: HttpRequest,
     state: web::Data<Arc<AppState>>,
-    webhook_data: web::Json<CertManagerHook>,
+    payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
-    // Convert cert-manager format to our internal format
-    let request = HookRequest {
-        namespace: webhook_data.secret_ref.namespace.clone(),
-        secret_name: webhook_data.secret_ref.name.clone(),
-    };
-    
-    info!("Processing certificate request for {}/{}", request.namespace, request.secret_name);
+    state.metrics.last_request.touch();
+    let mut webhook_data: CertManagerHook = read_signed_json(&req, &state, payload).await?;
+    webhook_data.secret_ref.apply_default_namespace(&state);
+    if state.async_apply {
+        return schedule_update(&req, &state, webhook_data).await;
+    }
+    if !state.apply_delay.is_zero() {
+        debug!("Waiting {}s before reading the secret", state.apply_delay.as_secs());
+        sleep(state.apply_delay).await;
+    }
     
-    // Validate request
+    let request_id = request_id(&req);
+    let ((status, response, details), timing) =
+        metrics::timed(process_update_detailed(&state, &webhook_data, UpdateSource::Webhook)).await;
+    // One line per request with everything a log-based dashboard needs
+    info!(
+        "request_summary request_id={} namespace={} secret={} source={} result={} status={} retries={} kube_ms={} linode_ms={} fingerprint={} config_ids={}",
+        request_id,
+        webhook_data.secret_ref.namespace,
+        webhook_data.secret_ref.name,
+        UpdateSource::Webhook.as_str(),
+        details.outcome,
+        status.as_u16(),
+        timing.retries,
+        timing.kube_ms,
+        timing.linode_ms,
+        details.fingerprint.as_deref().unwrap_or("-"),
+        if details.config_ids.is_empty() { "-".to_string() } else { details.config_ids.join(",") },
+    );
+    if let Some(code) = response.code {
+        record_validation_failure(&state, &req, code, Some(&webhook_data.secret_ref));
+    }
+    if status == StatusCode::OK && state.success_status == StatusCode::NO_CONTENT {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    if state.include_timing {
+        return Ok(HttpResponse::build(status).json(TimedResponse { response, timing }));
+    }
+    Ok(HttpResponse::build(status).json(response))
+}
+
+/// OPTIONS on the update endpoint: the methods it takes and a short
+/// description of the body and limits, for clients that probe before posting.
+async fn describe_update_endpoint(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let namespace = match &state.default_namespace {
+        Some(namespace) => format!("string, optional, defaults to '{}'", namespace),
+        None => "string, required".to_string(),
+    };
+    HttpResponse::Ok()
+        .insert_header((actix_web::http::header::ALLOW, "POST, PUT, OPTIONS"))
+        .json(serde_json::json!({
+            "methods": ["POST", "PUT", "OPTIONS"],
+            "content_type": "application/json",
+            "payload": {
+                "secretRef": {
+                    "name": "string, required",
+                    "namespace": namespace,
+                },
+                "configId": "string, optional, overrides HTTPS_CONFIG_ID and SAN routing",
+                "updateCert": "boolean, optional, defaults to true",
+                "updateKey": "boolean, optional, defaults to true",
+            },
+            "limits": {
+                "max_json_bytes": MAX_JSON_BYTES,
+                "max_namespace_len": MAX_NAMESPACE_LEN,
+                "max_secret_name_len": MAX_SECRET_NAME_LEN,
+                "max_chain_certs": state.max_chain_certs,
+            },
+            // WEBHOOK_HMAC_SECRET: updates are signed rather than carrying a token
+            "auth_required": state.webhook_hmac_secret.is_some(),
+            "signature": state.webhook_hmac_secret.as_ref().map(|_| serde_json::json!({
+                "algorithm": "hmac-sha256",
+                "header": SIGNATURE_HEADER,
+                "timestamp_header": TIMESTAMP_HEADER,
+                "signed": "<X-Timestamp>.<raw body>",
+                "max_age_secs": state.signature_max_age_secs,
+            })),
+            "async_apply": state.async_apply,
+        }))
+}
+
+/// ASYNC_APPLY: answers 202 right away and runs the update in the background,
+/// after APPLY_DELAY_SECS if set. A request that would be refused anyway, malformed, not allowed or
+/// paused, is still rejected synchronously and one outside the apply window is deferred like an
+/// update would be. The outcome of the update itself goes to the logs, metrics and history.
+async fn schedule_update(req: &HttpRequest, state: &Arc<AppState>, hook: CertManagerHook) -> Result<HttpResponse, Error> {
+    let request = HookRequest::from(&hook);
     if let Err(e) = validate_hook_request(&request).await {
         error!("Validation error: {}", e);
+        record_validation_failure(state, req, ErrorCode::ValidationError, Some(&hook.secret_ref));
         return Ok(HttpResponse::BadRequest().json(ApiResponse {
             status: "error".to_string(),
             message: Some(format!("Invalid request: {}", e)),
+            code: Some(ErrorCode::ValidationError),
         }));
     }
     
-    // Get the certificate data from Kubernetes with retries
-    let cert_result = retry_operation(|| async {
-        get_secret_data(&state.kube_client, &request.namespace, &request.secret_name).await
-    }).await;
-    
-    match cert_result {
-        Ok((cert, key)) => {
-            // Update Linode NodeBalancer with retries
-            let update_result = retry_operation(|| async {
-                update_linode_config(
-                    &state.http_client,
-                    &state.linode_token, 
-                    &state.nodebalancer_id,
-                    &state.https_config_id,
-                    &cert, 
-                    &key
-                ).await
-            }).await;
-            
-            match update_result {
-                Ok(_) => {
-                    info!("Successfully updated certificate for {}/{}", request.namespace, request.secret_name);
-                    Ok(HttpResponse::Ok().json(ApiResponse {
-                        status: "success".to_string(),
-                        message: None,
-                    }))
-                }
-                Err(e) => {
-                    error!("Failed to update NodeBalancer after retries: {}", e);
-                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                        status: "error".to_string(),
-                        message: Some(format!("Failed to update NodeBalancer: {}", e)),
-                    }))
-                }
-            }
-        }
-        Err(e) => {
-            error!("Failed to retrieve certificate data after retries: {}", e);
-            Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                status: "error".to_string(),
-                message: Some(format!("Failed to retrieve certificate data: {}", e)),
-            }))
+    if let Err((status, response)) = check_allowed(state, &request).and_then(|()| check_paused(state, &hook)) {
+        if let Some(code) = response.code {
+            record_validation_failure(state, req, code, Some(&hook.secret_ref));
         }
+        return Ok(HttpResponse::build(status).json(response));
     }
-}
-
-async fn retry_operation<F, Fut, T>(operation: F) -> Result<T, Box<dyn std::error::Error>>
-where
-    F: Fn() -> Fut,
-    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
-{
-    let mut last_error = None;
     
-    for attempt in 1..=MAX_RETRIES {
-        match operation().await {
-            Ok(result) => return Ok(result),
-            Err(e) => {
-                warn!("Operation failed (attempt {}/{}): {}", attempt, MAX_RETRIES, e);
-                last_error = Some(e);
-                
-                if attempt < MAX_RETRIES {
-                    let backoff = RETRY_DELAY_MS * 2u64.pow(attempt - 1);
-                    debug!("Retrying after {}ms", backoff);
-                    sleep(Duration::from_millis(backoff)).await;
-                }
-            }
-        }
+    if !window::is_open(state) {
+        let (status, response) = defer_update(state, &hook).await;
+        return Ok(HttpResponse::build(status).json(response));
     }
     
-    Err(last_error.unwrap_or_else(|| 
-        Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Unknown error during retry"))
-    ))
+    info!(
+        "Scheduling update for {}/{} in {}s",
+        hook.secret_ref.namespace, hook.secret_ref.name, state.apply_delay.as_secs()
+    );
+    let delay = state.apply_delay;
+    let state = state.clone();
+    // The update path isn't Send, so it stays on this worker's arbiter
+    actix_web::rt::spawn(async move {
+        sleep(delay).await;
+        let (status, response) = process_update(&state, &hook, UpdateSource::Webhook).await;
+        if !status.is_success() || status == StatusCode::MULTI_STATUS {
+            error!(
+                "Scheduled update for {}/{} failed ({}): {}",
+                hook.secret_ref.namespace,
+                hook.secret_ref.name,
+                status,
+                response.message.unwrap_or_default()
+            );
+        }
+    });
+    
+    Ok(HttpResponse::Accepted().json(ApiResponse {
+        status: "accepted".to_string(),
+        message: Some(format!("Update scheduled in {}s", delay.as_secs())),
+        code: None,
+    }))
 }
 
-async fn get_secret_data(
-    client: &Client,
-    namespace: &str,
-    name: &str,
-) -> Result<(String, String), Box<dyn std::error::Error>> {
-    debug!("Retrieving secret {}/{} from Kubernetes", namespace, name);
-    let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
-    let secret = secrets.get(name).await?;
-    
-    let cert_data = secret.data.as_ref()
-        .and_then(|data| data.get("tls.crt"))
-        .ok_or("tls.crt not found in secret")?;
-    
-    let key_data = secret.data.as_ref()
-        .and_then(|data| data.get("tls.key"))
-        .ok_or("tls.key not found in secret")?;
+async fn update_batch(
+    req: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+    options: web::Query<BatchOptions>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    state.metrics.last_request.touch();
+    let mut hooks: Vec<CertManagerHook> = read_signed_json(&req, &state, payload).await?;
+    for hook in &mut hooks {
+        hook.secret_ref.apply_default_namespace(&state);
+    }
     
-    let cert = String::from_utf8(general_purpose::STANDARD.decode(&cert_data.0)?)?;
-    let key = String::from_utf8(general_purpose::STANDARD.decode(&key_data.0)?)?;
+    if hooks.is_empty() || hooks.len() > state.max_batch {
+        error!("Rejecting batch of {} items (max {})", hooks.len(), state.max_batch);
+        record_validation_failure(&state, &req, ErrorCode::ValidationError, None);
+        return Ok(HttpResponse::BadRequest().json(ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!("Invalid request: batch must contain between 1 and {} items", state.max_batch)),
+            code: Some(ErrorCode::ValidationError),
+        }));
+    }
     
-    Ok((cert, key))
-}
-
-async fn update_linode_config(
-    client: &reqwest::Client,
-    token: &str,
-    nodebalancer_id: &str,
-    https_config_id: &str,
-    cert: &str,
-    key: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token))?);
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if !state.apply_delay.is_zero() {
+        debug!("Waiting {}s before reading the batch's secrets", state.apply_delay.as_secs());
+        sleep(state.apply_delay).await;
+    }
     
-    // Update the existing HTTPS config using the provided ID
-    info!("Updating HTTPS config (ID: {})", https_config_id);
+    let fail_fast = options.fail_fast.unwrap_or(state.batch_fail_fast);
+    info!(
+        "Processing batch of {} certificate requests ({})",
+        hooks.len(),
+        if fail_fast { "fail fast" } else { "best effort" }
+    );
     
-    let update_url = format!("https://api.linode.com/v4/nodebalancers/{}/configs/{}", 
-                             nodebalancer_id, https_config_id);
+    let results: Vec<BatchItemResult> = if fail_fast {
+        // One at a time, so nothing after the failing item is touched
+        let mut results = Vec::with_capacity(hooks.len());
+        for hook in &hooks {
+            let result = process_batch_item(&state, hook).await;
+            let failed = batch_item_failed(&result);
+            results.push(result);
+            if failed {
+                break;
+            }
+        }
+        results
+    } else {
+        // Items are processed with bounded concurrency, results keep the request order
+        stream::iter(hooks.iter())
+            .map(|hook| process_batch_item(&state, hook))
+            .buffered(state.batch_concurrency)
+            .collect()
+            .await
+    };
+    for result in &results {
+        if let Some(code) = result.response.code {
+            let secret_ref = SecretRef { name: result.secret_name.clone(), namespace: result.namespace.clone() };
+            record_validation_failure(&state, &req, code, Some(&secret_ref));
+        }
+    }
     
-    let payload = serde_json::json!({
-        "protocol": "https",
-        "ssl_cert": cert,
-        "ssl_key": key
-    });
+    let failed = results.iter().filter(|r| batch_item_failed(r)).count();
+    let succeeded = results.len() - failed;
+    let skipped = hooks.len() - results.len();
+    info!("Batch finished: {} succeeded, {} failed, {} skipped", succeeded, failed, skipped);
     
-    let response = client.put(&update_url)
-        .headers(headers)
-        .json(&payload)
-        .send()
-        .await?;
+    let failed_item = if fail_fast { results.iter().position(batch_item_failed) } else { None };
+    let (status, overall) = match (failed_item, succeeded, failed) {
+        // Fail fast answers with the failing item's own error status
+        (Some(index), _, _) => {
+            let item_status = StatusCode::from_u16(results[index].http_status)
+                .ok()
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            (item_status, "aborted")
+        }
+        (None, _, 0) => (StatusCode::OK, "success"),
+        (None, 0, _) => (StatusCode::MULTI_STATUS, "error"),
+        _ => (StatusCode::MULTI_STATUS, "partial"),
+    };
     
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        error!("Failed to update Linode config: {}", error_text);
-        return Err(format!("Failed to update config: {}", error_text).into());
+    Ok(HttpResponse::build(status).json(BatchResponse {
+        status: overall.to_string(),
+        mode: if fail_fast { "fail_fast" } else { "best_effort" }.to_string(),
+        succeeded,
+        failed,
+        skipped,
+        failed_item,
+        results,
+    }))
+}
+
+async fn process_batch_item(state: &AppState, hook: &CertManagerHook) -> BatchItemResult {
+    let ((status, response), timing) = metrics::timed(process_update(state, hook, UpdateSource::Webhook)).await;
+    BatchItemResult {
+        namespace: hook.secret_ref.namespace.clone(),
+        secret_name: hook.secret_ref.name.clone(),
+        http_status: status.as_u16(),
+        response,
+        timing: state.include_timing.then_some(timing),
     }
-    
-    info!("Successfully updated certificate in NodeBalancer config");
-    Ok(())
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    // Initialize logging with more verbose format
-    env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"))
-        .format_timestamp_millis()
-        .format_module_path(true)
-        .init();
-    
-    // Get configuration from environment
-    let linode_token = env::var("LINODE_TOKEN")
-        .expect("LINODE_TOKEN must be set");
-    let nodebalancer_id = env::var("NODEBALANCER_ID")
-        .expect("NODEBALANCER_ID must be set");
-    let https_config_id = env::var("HTTPS_CONFIG_ID")
-        .expect("HTTPS_CONFIG_ID must be set");
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let port = port.parse::<u16>().expect("PORT must be a number");
-    
-    // Initialize Kubernetes client
-    let kube_client = Client::try_default()
-        .await
-        .expect("Failed to create Kubernetes client");
-    
-    // Initialize HTTP client with timeouts and connection pooling
-    let http_client = ClientBuilder::new()
-        .timeout(Duration::from_secs(30))
-        .connect_timeout(Duration::from_secs(10))
-        .pool_max_idle_per_host(10)
-        .pool_idle_timeout(Duration::from_secs(60))
-        .build()
-        .expect("Failed to build HTTP client");
-    
-    let state = Arc::new(AppState {
-        kube_client,
-        http_client,
-        linode_token,
-        nodebalancer_id,
-        https_config_id,
+/// An item that only reached some of its configs counts as failed.
+fn batch_item_failed(result: &BatchItemResult) -> bool {
+    result.http_status >= 300 || result.http_status == StatusCode::MULTI_STATUS.as_u16()
+}
+
+/// Assigned to every request by the middleware in `main`, which also echoes
+/// it back as X-Request-Id.
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Id correlating a request with its log lines, as assigned on the way in.
+fn request_id(req: &HttpRequest) -> String {
+    match req.extensions().get::<RequestId>() {
+        Some(RequestId(id)) => id.clone(),
+        None => new_request_id(req),
+    }
+}
+
+/// The caller's X-Request-Id when it sent a usable one, otherwise a new one.
+fn new_request_id(req: &HttpRequest) -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    req.headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:x}-{:x}", cert::unix_now(), SEQUENCE.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// What an update found out along the way, beyond its response.
+#[derive(Default)]
+struct UpdateDetails {
+    // success, unchanged, partial or error, as counted in the updates metric
+    outcome: &'static str,
+    // notAfter of the leaf, once the secret was read
+    not_after: Option<i64>,
+    fingerprint: Option<String>,
+    config_ids: Vec<String>,
+    // Per-config results for the history, once the configs were pushed to
+    configs: Vec<history::ConfigResult>,
+}
+
+/// Runs the full validate, fetch and push flow for a single cert-manager hook.
+async fn process_update(
+    state: &AppState,
+    webhook_data: &CertManagerHook,
+    source: UpdateSource,
+) -> (StatusCode, ApiResponse) {
+    let (status, response, _) = process_update_detailed(state, webhook_data, source).await;
+    (status, response)
+}
+
+/// `process_update`, also returning the details gathered on the way.
+async fn process_update_detailed(
+    state: &AppState,
+    webhook_data: &CertManagerHook,
+    source: UpdateSource,
+) -> (StatusCode, ApiResponse, UpdateDetails) {
+    let mut details = UpdateDetails::default();
+    let (status, response) = run_update(state, webhook_data, &mut details).await;
+    let outcome = match status {
+        StatusCode::MULTI_STATUS => "partial",
+        StatusCode::ACCEPTED if response.status == "queued" => "queued",
+        _ if status.is_success() && response.status == "unchanged" => "unchanged",
+        _ if status.is_success() && response.status == "warning" => "unconfirmed",
+        _ if status.is_success() => "success",
+        _ => "error",
+    };
+    state.metrics.updates.with_label_values(&[source.as_str(), outcome]).inc();
+    if status.is_success() && status != StatusCode::MULTI_STATUS && outcome != "queued" {
+        state.synced.store(true, Ordering::SeqCst);
+    }
+    state.history.record(history::HistoryEntry {
+        at: cert::unix_now(),
+        namespace: webhook_data.secret_ref.namespace.clone(),
+        secret_name: webhook_data.secret_ref.name.clone(),
+        http_status: status.as_u16(),
+        status: response.status.clone(),
+        message: response.message.clone(),
+        not_after: details.not_after,
+        configs: std::mem::take(&mut details.configs),
+    });
+    details.outcome = outcome;
+    (status, response, details)
+}
+
+/// Holds back an update that arrived outside APPLY_WINDOW: queued with a 202
+/// when the pending ConfigMap can keep it across restarts, refused with a 503
+/// otherwise. A repeat for the same secret and config replaces the queued one,
+/// the secret is only read once the window opens.
+async fn defer_update(state: &AppState, webhook_data: &CertManagerHook) -> (StatusCode, ApiResponse) {
+    let secret = format!("{}/{}", webhook_data.secret_ref.namespace, webhook_data.secret_ref.name);
+    if !state.pending.persistent() {
+        info!("Outside the apply window, rejecting the update of {}", secret);
+        return (StatusCode::SERVICE_UNAVAILABLE, ApiResponse {
+            status: "error".to_string(),
+            message: Some("Outside apply window, retry once it opens".to_string()),
+            code: Some(ErrorCode::OutsideApplyWindow),
+        });
+    }
+
+    let body = match serde_json::to_string(webhook_data) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to serialize the update of {} for the apply window: {}", secret, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, ApiResponse {
+                status: "error".to_string(),
+                message: Some(format!("Outside apply window and failed to queue the update: {}", e)),
+                code: Some(ErrorCode::OutsideApplyWindow),
+            });
+        }
+    };
+    state.pending.insert(&pending_key(webhook_data), &body).await;
+    info!("Outside the apply window, queued the update of {}", secret);
+    (StatusCode::ACCEPTED, ApiResponse {
+        status: "queued".to_string(),
+        message: Some("Outside apply window, the update runs once it opens".to_string()),
+        code: None,
+    })
+}
+
+/// ConfigMap key of a queued update. Namespaces and secret names can't hold
+/// `_`, so it can't collide.
+fn pending_key(webhook_data: &CertManagerHook) -> String {
+    let mut key = format!("{}_{}", webhook_data.secret_ref.namespace, webhook_data.secret_ref.name);
+    if let Some(config_id) = &webhook_data.config_id {
+        key.push('_');
+        key.push_str(config_id);
+    }
+    key
+}
+
+/// Refuses an update while /admin/pause is in effect.
+fn check_paused(state: &AppState, webhook_data: &CertManagerHook) -> Result<(), (StatusCode, ApiResponse)> {
+    if let Some(until) = state.metrics.paused.until() {
+        debug!("Updates are paused, rejecting {}/{}", webhook_data.secret_ref.namespace, webhook_data.secret_ref.name);
+        return Err((StatusCode::SERVICE_UNAVAILABLE, ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!("Updates are paused for maintenance for another {}s", until - cert::unix_now())),
+            code: Some(ErrorCode::Paused),
+        }));
+    }
+    Ok(())
+}
+
+/// Checks a request against ALLOWED_SECRETS and ALLOWED_CONFIG_IDS, returning
+/// the response to send when it isn't allowed.
+fn check_allowed(state: &AppState, request: &HookRequest) -> Result<(), (StatusCode, ApiResponse)> {
+    if !secret_allowed(&state.allowed_secrets, &request.namespace, &request.secret_name) {
+        error!("Secret {}/{} is not in ALLOWED_SECRETS", request.namespace, request.secret_name);
+        return Err((StatusCode::FORBIDDEN, ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!("Secret {}/{} is not allowed", request.namespace, request.secret_name)),
+            code: Some(ErrorCode::SecretNotAllowed),
+        }));
+    }
+    
+    if let Some(config_id) = &request.config_id {
+        if !state.allowed_config_ids.is_empty() && !state.allowed_config_ids.contains(config_id) {
+            error!("Config {} is not in ALLOWED_CONFIG_IDS", config_id);
+            return Err((StatusCode::FORBIDDEN, ApiResponse {
+                status: "error".to_string(),
+                message: Some(format!("Config {} is not allowed", config_id)),
+                code: Some(ErrorCode::ConfigNotAllowed),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Checks what a secret holds before any of it is pushed, returning the parsed
+/// bundle and the leaf fingerprint. Shared by updates and scheduled revalidation.
+fn check_secret(
+    state: &AppState,
+    request: &HookRequest,
+    cert: &str,
+    key: &str,
+) -> Result<(provider::CertBundle, Option<String>), (StatusCode, ApiResponse)> {
+    let bundle = match provider::CertBundle::parse(cert, key) {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            error!("Secret {}/{} does not hold a usable certificate: {}", request.namespace, request.secret_name, e);
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+                status: "error".to_string(),
+                message: Some(e),
+                code: Some(ErrorCode::CertInvalid),
+            }));
+        }
+    };
+    if bundle.chain.len() > state.max_chain_certs {
+        error!(
+            "Secret {}/{} holds {} certificates, more than MAX_CHAIN_CERTS={}",
+            request.namespace, request.secret_name, bundle.chain.len(), state.max_chain_certs
+        );
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!(
+                "Certificate chain has {} certificates, the limit is {}",
+                bundle.chain.len(), state.max_chain_certs
+            )),
+            code: Some(ErrorCode::ChainTooLong),
+        }));
+    }
+    if !state.allow_self_signed {
+        if let Ok(Some(subject)) = cert::self_signed_subject(&bundle.chain[0]) {
+            error!(
+                "Secret {}/{} holds a self-signed leaf (subject and issuer '{}'), refusing to push it",
+                request.namespace, request.secret_name, subject
+            );
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+                status: "error".to_string(),
+                message: Some(format!(
+                    "Leaf certificate '{}' is self-signed, set ALLOW_SELF_SIGNED=true to push it anyway",
+                    subject
+                )),
+                code: Some(ErrorCode::SelfSigned),
+            }));
+        }
+    }
+    if state.chain_policy != ChainPolicy::Off {
+        if let Err(e) = cert::check_chain(&bundle.chain) {
+            if state.chain_policy == ChainPolicy::Strict {
+                error!("Secret {}/{} holds an incomplete chain: {}", request.namespace, request.secret_name, e);
+                return Err((StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+                    status: "error".to_string(),
+                    message: Some(format!("Certificate chain is incomplete: {}", e)),
+                    code: Some(ErrorCode::ChainIncomplete),
+                }));
+            }
+            warn!("Secret {}/{} holds an incomplete chain, pushing it anyway: {}", request.namespace, request.secret_name, e);
+        }
+    }
+    // A partial update keeps the other half as configured, so only a full one can be compared
+    if request.update_cert && request.update_key {
+        match cert::key_matches(&bundle.chain[0], key) {
+            Ok(true) => {}
+            Ok(false) => {
+                error!("Secret {}/{} holds a key that does not belong to its certificate", request.namespace, request.secret_name);
+                return Err((StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+                    status: "error".to_string(),
+                    message: Some("Private key does not belong to the leaf certificate".to_string()),
+                    code: Some(ErrorCode::KeyMismatch),
+                }));
+            }
+            Err(e) => warn!("Failed to compare the key in {}/{} with its certificate: {}", request.namespace, request.secret_name, e),
+        }
+    }
+    let fingerprint = match cert::leaf_fingerprint(cert) {
+        Ok(fingerprint) => Some(fingerprint),
+        Err(e) => {
+            warn!("Failed to fingerprint certificate, skipping deduplication: {}", e);
+            None
+        }
+    };
+    if !state.allowed_fingerprints.is_empty()
+        && !fingerprint.as_ref().is_some_and(|fp| state.allowed_fingerprints.contains(fp))
+    {
+        error!(
+            "Secret {}/{} holds a certificate ({}) that is not in ALLOWED_FINGERPRINTS",
+            request.namespace,
+            request.secret_name,
+            fingerprint.as_deref().unwrap_or("no fingerprint")
+        );
+        return Err((StatusCode::FORBIDDEN, ApiResponse {
+            status: "error".to_string(),
+            message: Some("Certificate fingerprint is not allowed".to_string()),
+            code: Some(ErrorCode::FingerprintNotAllowed),
+        }));
+    }
+    Ok((bundle, fingerprint))
+}
+
+/// Does the work of `process_update`, filling in `details` as it learns them.
+async fn run_update(
+    state: &AppState,
+    webhook_data: &CertManagerHook,
+    details: &mut UpdateDetails,
+) -> (StatusCode, ApiResponse) {
+    if let Err(rejection) = check_paused(state, webhook_data) {
+        return rejection;
+    }
+    // Convert cert-manager format to our internal format
+    let request = HookRequest::from(webhook_data);
+    let parts = provider::CertParts { cert: request.update_cert, key: request.update_key };
+    
+    info!("Processing certificate request for {}/{}", request.namespace, request.secret_name);
+    
+    // Validate request
+    if let Err(e) = validate_hook_request(&request).await {
+        error!("Validation error: {}", e);
+        return (StatusCode::BAD_REQUEST, ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!("Invalid request: {}", e)),
+            code: Some(ErrorCode::ValidationError),
+        });
+    }
+    
+    if let Err(rejection) = check_allowed(state, &request) {
+        return rejection;
+    }
+    
+    // After the checks, so a malformed or disallowed request is refused instead of queued
+    if !window::is_open(state) {
+        return defer_update(state, webhook_data).await;
+    }
+    let _inflight = metrics::GaugeGuard::new(&state.metrics.inflight_updates);
+    let _permit = match &state.update_permits {
+        Some(permits) => {
+            let _queued = metrics::GaugeGuard::new(&state.metrics.update_queue_depth);
+            Some(permits.acquire().await.expect("update semaphore is never closed"))
+        }
+        None => None,
+    };
+    
+    // Get the certificate data from Kubernetes with retries
+    let cert_result = fetch_secret(state, &request.namespace, &request.secret_name).await;
+    
+    match cert_result {
+        Ok(SecretData { cert, key }) => {
+            details.not_after = cert::leaf_validity(&cert).ok().map(|(_, not_after)| not_after);
+            let (bundle, fingerprint) = match check_secret(state, &request, &cert, &key) {
+                Ok(checked) => checked,
+                Err(rejection) => return rejection,
+            };
+            let config_ids = match &request.config_id {
+                Some(config_id) => vec![config_id.clone()],
+                None => resolve_config_ids(state, &cert),
+            };
+            details.fingerprint = fingerprint.clone();
+            details.config_ids = config_ids.clone();
+            // The annotation covers the configs the secret routes to, an explicit config may not have it yet
+            let annotations = state.annotations.as_deref().filter(|_| request.config_id.is_none() && parts.cert);
+            let secret_key = format!("{}/{}", request.namespace, request.secret_name);
+            let annotated = match annotations {
+                Some(annotations) => state::already_applied(annotations, &secret_key, fingerprint.as_deref()).await,
+                None => false,
+            };
+            if annotated {
+                info!("Secret {}/{} is annotated as already applied, skipping", request.namespace, request.secret_name);
+                state.metrics.config_updates.with_label_values(&["skipped"]).inc_by(config_ids.len() as u64);
+                details.configs = config_ids.iter()
+                    .map(|config_id| history::ConfigResult {
+                        config_id: config_id.clone(),
+                        outcome: "unchanged",
+                        fingerprint: fingerprint.clone(),
+                        message: None,
+                    })
+                    .collect();
+                return (StatusCode::OK, ApiResponse {
+                    status: "unchanged".to_string(),
+                    message: Some("Certificate is already applied".to_string()),
+                    code: None,
+                });
+            }
+            // Configs are independent, one failing doesn't stop the others
+            let mut outcomes: Vec<(usize, &String, ConfigOutcome)> =
+                stream::iter(config_ids.iter().enumerate())
+                    .map(|(index, config_id)| {
+                        let bundle = &bundle;
+                        let fingerprint = fingerprint.as_deref();
+                        async move {
+                            // A key-only update doesn't change the fingerprint, so it can't be deduplicated
+                            if parts.cert && state::already_applied(&state.last_applied, config_id, fingerprint).await {
+                                info!("Certificate already applied to config {}, skipping", config_id);
+                                return (index, config_id, ConfigOutcome::Unchanged);
+                            }
+                            let outcome = match push_to_config(state, config_id, bundle, parts, fingerprint).await {
+                                Ok(Pushed::AlreadyServed) => ConfigOutcome::Unchanged,
+                                Ok(Pushed::Confirmed) => ConfigOutcome::Applied,
+                                Ok(Pushed::Unconfirmed) => ConfigOutcome::Unconfirmed,
+                                Err(failure) => ConfigOutcome::Failed(failure),
+                            };
+                            (index, config_id, outcome)
+                        }
+                    })
+                    .buffer_unordered(state.max_parallel_config_updates)
+                    .collect()
+                    .await;
+            outcomes.sort_by_key(|(index, _, _)| *index);
+            for (_, _, outcome) in &outcomes {
+                let result = match outcome {
+                    ConfigOutcome::Unchanged => "skipped",
+                    ConfigOutcome::Applied | ConfigOutcome::Unconfirmed => "applied",
+                    ConfigOutcome::Failed(_) => continue,
+                };
+                state.metrics.config_updates.with_label_values(&[result]).inc();
+            }
+            // A key-only update leaves the served leaf as it was, whichever that is
+            let pushed_fingerprint = fingerprint.as_ref().filter(|_| parts.cert);
+            details.configs = outcomes.iter()
+                .map(|(_, config_id, outcome)| {
+                    let (outcome, fingerprint, message) = match outcome {
+                        ConfigOutcome::Unchanged => ("unchanged", pushed_fingerprint.cloned(), None),
+                        ConfigOutcome::Applied => ("applied", pushed_fingerprint.cloned(), None),
+                        ConfigOutcome::Unconfirmed => ("unconfirmed", pushed_fingerprint.cloned(), None),
+                        ConfigOutcome::Failed((_, response)) => ("failed", None, response.message.clone()),
+                    };
+                    history::ConfigResult { config_id: config_id.to_string(), outcome, fingerprint, message }
+                })
+                .collect();
+            
+            let applied: Vec<&String> = outcomes.iter()
+                .filter(|(_, _, outcome)| matches!(outcome, ConfigOutcome::Applied | ConfigOutcome::Unconfirmed))
+                .map(|(_, config_id, _)| *config_id)
+                .collect();
+            let unconfirmed: Vec<&str> = outcomes.iter()
+                .filter(|(_, _, outcome)| matches!(outcome, ConfigOutcome::Unconfirmed))
+                .map(|(_, config_id, _)| config_id.as_str())
+                .collect();
+            let mut failed: Vec<(&String, (StatusCode, ApiResponse))> = outcomes.into_iter()
+                .filter_map(|(_, config_id, outcome)| match outcome {
+                    ConfigOutcome::Failed(failure) => Some((config_id, failure)),
+                    _ => None,
+                })
+                .collect();
+            
+            if !failed.is_empty() {
+                return config_failures(&request, &applied, &mut failed);
+            }
+            
+            // Not annotated either, so the next update pushes again instead of deduplicating
+            if !unconfirmed.is_empty() {
+                warn!(
+                    "Certificate for {}/{} was accepted but not confirmed on config(s) {}",
+                    request.namespace, request.secret_name, unconfirmed.join(", ")
+                );
+                return (StatusCode::ACCEPTED, ApiResponse {
+                    status: "warning".to_string(),
+                    message: Some(format!(
+                        "Linode accepted the update but config(s) {} still report another certificate",
+                        unconfirmed.join(", ")
+                    )),
+                    code: Some(ErrorCode::PostVerifyUnconfirmed),
+                });
+            }
+            
+            if let (Some(annotations), Some(fingerprint)) = (annotations, fingerprint.as_deref()) {
+                annotations.set(&secret_key, fingerprint).await;
+            }
+            
+            if applied.is_empty() {
+                return (StatusCode::OK, ApiResponse {
+                    status: "unchanged".to_string(),
+                    message: Some("Certificate is already applied".to_string()),
+                    code: None,
+                });
+            }
+            
+            info!("Successfully updated certificate for {}/{}", request.namespace, request.secret_name);
+            (StatusCode::OK, ApiResponse {
+                status: "success".to_string(),
+                message: None,
+                code: None,
+            })
+        }
+        Err(e) if e.is::<SecretDecodeError>() => {
+            error!("Secret {}/{} is not usable: {}", request.namespace, request.secret_name, e);
+            (StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+                status: "error".to_string(),
+                message: Some(e.to_string()),
+                code: Some(ErrorCode::SecretDecodeFailed),
+            })
+        }
+        Err(e) if e.is::<SecretTooLargeError>() => {
+            error!("Secret {}/{} is not usable: {}", request.namespace, request.secret_name, e);
+            (StatusCode::UNPROCESSABLE_ENTITY, ApiResponse {
+                status: "error".to_string(),
+                message: Some(e.to_string()),
+                code: Some(ErrorCode::SecretTooLarge),
+            })
+        }
+        Err(e) => {
+            error!("Failed to retrieve certificate data after retries: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, ApiResponse {
+                status: "error".to_string(),
+                message: Some(format!("Failed to retrieve certificate data: {}", e)),
+                code: Some(secret_error_code(e.as_ref())),
+            })
+        }
+    }
+}
+
+enum ConfigOutcome {
+    Unchanged,
+    Applied,
+    // Applied, but POST_VERIFY never saw the config report it
+    Unconfirmed,
+    Failed((StatusCode, ApiResponse)),
+}
+
+/// Builds the response when at least one config failed. A single failing config
+/// keeps its own response; otherwise the message lists every outcome and the
+/// status is 207 when some configs did succeed.
+fn config_failures(
+    request: &HookRequest,
+    applied: &[&String],
+    failed: &mut Vec<(&String, (StatusCode, ApiResponse))>,
+) -> (StatusCode, ApiResponse) {
+    if failed.len() == 1 && applied.is_empty() {
+        return failed.remove(0).1;
+    }
+    
+    let failures: Vec<String> = failed.iter()
+        .map(|(config_id, (_, response))| format!("{} ({})", config_id, response.message.as_deref().unwrap_or("unknown error")))
+        .collect();
+    let applied_ids: Vec<&str> = applied.iter().map(|id| id.as_str()).collect();
+    let message = format!(
+        "updated: [{}]; failed: [{}]",
+        applied_ids.join(", "),
+        failures.join(", ")
+    );
+    let (first_status, first_response) = &failed[0].1;
+    
+    if applied.is_empty() {
+        error!("Every config failed for {}/{}: {}", request.namespace, request.secret_name, message);
+        return (*first_status, ApiResponse {
+            status: "error".to_string(),
+            message: Some(message),
+            code: first_response.code,
+        });
+    }
+    
+    warn!("Partially updated certificate for {}/{}: {}", request.namespace, request.secret_name, message);
+    (StatusCode::MULTI_STATUS, ApiResponse {
+        status: "partial".to_string(),
+        message: Some(message),
+        code: first_response.code,
+    })
+}
+
+/// Pushes the certificate to one config, verifies it if enabled and records it
+/// as applied. Failures come back as the response to send to the caller.
+async fn push_to_config(
+    state: &AppState,
+    config_id: &str,
+    bundle: &provider::CertBundle,
+    parts: provider::CertParts,
+    fingerprint: Option<&str>,
+) -> Result<Pushed, (StatusCode, ApiResponse)> {
+    let applied = match state.provider.update_cert(config_id, bundle, parts).await {
+        Ok(applied) => applied,
+        Err(e) => {
+            error!("{} provider failed to update config {}: {}", state.provider.name(), config_id, e.message);
+            return Err((e.status, ApiResponse {
+                status: "error".to_string(),
+                message: Some(e.message),
+                code: Some(e.code),
+            }));
+        }
+    };
+    
+    // Nothing was pushed, so there is nothing new to verify either
+    if applied.unchanged {
+        if let Some(fingerprint) = fingerprint {
+            state.last_applied.set(config_id, fingerprint).await;
+        }
+        return Ok(Pushed::AlreadyServed);
+    }
+    
+    // A key-only update leaves the fingerprint as it was, there is nothing to compare
+    if let Some(post_verify) = state.post_verify.as_ref().filter(|_| parts.cert) {
+        if !config_reports_cert(state, post_verify, config_id, &bundle.cert_pem).await {
+            warn!(
+                "Config {} accepted the update but did not report the new certificate after {} check(s)",
+                config_id, post_verify.attempts
+            );
+            state.metrics.post_verify_failures.inc();
+            return Ok(Pushed::Unconfirmed);
+        }
+    }
+    
+    if let Some(live_verify) = &state.live_verify {
+        match (fingerprint, applied.port) {
+            (Some(fingerprint), Some(port)) => {
+                if let Err(e) = verify::verify_served_cert(live_verify, port, fingerprint).await {
+                    error!("Live verification of config {} failed: {}", config_id, e);
+                    let (reason, code) = match e {
+                        verify::LiveVerifyError::Mismatch { .. } => ("mismatch", ErrorCode::LiveVerifyMismatch),
+                        verify::LiveVerifyError::Handshake(_) => ("handshake", ErrorCode::LiveVerifyFailed),
+                    };
+                    state.metrics.live_verify_failures.with_label_values(&[reason]).inc();
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, ApiResponse {
+                        status: "error".to_string(),
+                        message: Some(format!("Config {} was updated but live verification failed: {}", config_id, e)),
+                        code: Some(code),
+                    }));
+                }
+            }
+            _ => warn!("Skipping live verification of config {}: fingerprint or port unknown", config_id),
+        }
+    }
+    
+    if let Some(fingerprint) = fingerprint {
+        state.last_applied.set(config_id, fingerprint).await;
+    }
+    Ok(Pushed::Confirmed)
+}
+
+/// How a config update that Linode accepted, or didn't need, went.
+enum Pushed {
+    // The config already served the certificate, no update was sent
+    AlreadyServed,
+    // Also the outcome whenever POST_VERIFY is off
+    Confirmed,
+    // POST_VERIFY read the config back and it kept reporting another certificate
+    Unconfirmed,
+}
+
+/// POST_VERIFY: reads the config back until it reports the pushed
+/// certificate, as Linode can accept a PUT before the config reflects it.
+async fn config_reports_cert(
+    state: &AppState,
+    post_verify: &verify::PostVerifyConfig,
+    config_id: &str,
+    cert_pem: &str,
+) -> bool {
+    for attempt in 1..=post_verify.attempts {
+        match get_linode_config(&state.linode, config_id).await {
+            Ok(config) if config.served_fingerprint().is_some_and(|live| cert::fingerprint_matches(cert_pem, live)) => {
+                debug!("Config {} reports the pushed certificate", config_id);
+                return true;
+            }
+            Ok(config) => debug!(
+                "Config {} still reports {:?} (attempt {}/{})",
+                config_id, config.served_fingerprint(), attempt, post_verify.attempts
+            ),
+            Err(e) => warn!("Failed to read back config {} (attempt {}/{}): {}", config_id, attempt, post_verify.attempts, e),
+        }
+        if attempt < post_verify.attempts {
+            sleep(post_verify.delay).await;
+        }
+    }
+    false
+}
+
+async fn fetch_secret(
+    state: &AppState,
+    namespace: &str,
+    name: &str,
+) -> Result<SecretData, Box<dyn std::error::Error>> {
+    let kube_timer = state.metrics.kube_fetch_duration.start_timer();
+    let default_keys = SecretKeys::default();
+    let keys = state.secret_keys.get(&format!("{}/{}", namespace, name)).unwrap_or(&default_keys);
+    let attempts = state.kube_max_retries.saturating_add(1);
+    let policy = RetryPolicy { max_attempts: attempts, ..RetryPolicy::DEFAULT };
+    let result = retry_with_policy("kube_get_secret", &state.metrics.retries, policy, is_kube_retryable, || async {
+        get_secret_data(state, namespace, name, keys).await
+    }).await;
+    metrics::record_kube_time(kube_timer.stop_and_record());
+    result
+}
+
+fn resolve_config_ids(state: &AppState, cert: &str) -> Vec<String> {
+    if state.san_map.is_empty() {
+        return state.https_config_ids.clone();
+    }
+    
+    match cert::leaf_sans(cert) {
+        Ok(sans) => {
+            let config_ids = cert::route_config_ids(&sans, &state.san_map, &state.https_config_ids);
+            debug!("Certificate SANs {:?} routed to config(s) {:?}", sans, config_ids);
+            config_ids
+        }
+        Err(e) => {
+            warn!("Failed to read certificate SANs, using the default config(s): {}", e);
+            state.https_config_ids.clone()
+        }
+    }
+}
+
+/// Maps a secret retrieval failure to its error code: a missing secret or an
+/// apiserver failure come from kube, anything else is unusable secret content.
+fn secret_error_code(e: &(dyn std::error::Error + 'static)) -> ErrorCode {
+    match e.downcast_ref::<kube::Error>() {
+        Some(kube::Error::Api(resp)) if resp.code == 404 => ErrorCode::SecretNotFound,
+        Some(_) => ErrorCode::KubeError,
+        None if e.is::<SecretTooLargeError>() => ErrorCode::SecretTooLarge,
+        None => ErrorCode::CertInvalid,
+    }
+}
+
+/// Admin, validation and export routes, mounted on the metrics listener with
+/// the health checks when METRICS_PORT is set.
+fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/validate", web::post().to(validate::validate_cert))
+        .route("/verify", web::post().to(live::verify_live))
+        .route("/export", web::get().to(export::export_state))
+        .route("/admin/cache/clear", web::post().to(admin::clear_caches))
+        .route("/admin/pause", web::post().to(admin::pause_updates))
+        .route("/admin/resume", web::post().to(admin::resume_updates));
+}
+
+/// JSON extractor limits and errors, the same on both listeners.
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(MAX_JSON_BYTES)
+        .content_type(is_json_compatible)
+        .content_type_required(false)
+        .error_handler(json_error_handler)
+}
+
+/// Health checks and the status page, mounted on the metrics listener when
+/// METRICS_PORT is set.
+fn health_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/health", web::get().to(health_check))
+        .route("/ready", web::get().to(readiness_check))
+        .route("/health/deep", web::get().to(deep_health_check))
+        .route("/health/cert", web::get().to(cert_health_check))
+        .route("/", web::get().to(status::status_page));
+}
+
+/// Logs why startup failed and exits non-zero, without a panic backtrace.
+fn exit_on_startup_error(message: String) -> ! {
+    error!("{}", message);
+    std::process::exit(1);
+}
+
+fn request_logger(trusted_proxies: Arc<Vec<IpNet>>) -> Logger {
+    Logger::new(LOG_FORMAT)
+        .custom_request_replace("client_ip", move |req| {
+            let forwarded_for = req.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok());
+            resolve_client_ip(req.peer_addr().map(|addr| addr.ip()), forwarded_for, &trusted_proxies)
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        })
+        .custom_request_replace("request_id", |req| {
+            req.extensions().get::<RequestId>().map_or_else(|| "-".to_string(), |RequestId(id)| id.clone())
+        })
+}
+
+fn parse_trusted_proxies(raw: &str) -> Result<Vec<IpNet>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            entry.parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("'{}' is not a valid IP address or CIDR", entry))
+        })
+        .collect()
+}
+
+/// Resolves the real client address. X-Forwarded-For is only honoured when the
+/// peer is a trusted proxy, and is walked right to left so that entries added
+/// by further trusted proxies are skipped and a client can't spoof its address
+/// by sending its own header.
+fn resolve_client_ip(peer: Option<IpAddr>, forwarded_for: Option<&str>, trusted: &[IpNet]) -> Option<IpAddr> {
+    let peer = peer?;
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+    
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// Counts a rejected request by reason and, when alerting is enabled, against
+/// its client address. Codes that aren't about the request itself are ignored.
+fn record_validation_failure(state: &AppState, req: &HttpRequest, code: ErrorCode, secret_ref: Option<&SecretRef>) {
+    let reason = match code {
+        ErrorCode::ValidationError => "invalid_request",
+        ErrorCode::ConfigNotAllowed => "config_not_allowed",
+        ErrorCode::SecretNotAllowed => "secret_not_allowed",
+        ErrorCode::FingerprintNotAllowed => "fingerprint_not_allowed",
+        ErrorCode::InvalidJson => "invalid_json",
+        ErrorCode::PayloadTooLarge => "payload_too_large",
+        ErrorCode::InvalidSignature => "invalid_signature",
+        ErrorCode::SignatureExpired => "signature_expired",
+        _ => return,
+    };
+    state.metrics.validation_failures.with_label_values(&[reason]).inc();
+    
+    if let Some(alerts) = &state.alerts {
+        let forwarded_for = req.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok());
+        let source = resolve_client_ip(req.peer_addr().map(|addr| addr.ip()), forwarded_for, &state.trusted_proxies)
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        alerts.record(&source, reason, secret_ref.map(|s| (s.namespace.as_str(), s.name.as_str())));
+    }
+}
+
+/// Checks the bearer token of a request to an admin endpoint, returning the
+/// response to send instead when it doesn't match ADMIN_TOKEN.
+fn admin_rejection(state: &AppState, req: &HttpRequest) -> Option<HttpResponse> {
+    let Some(expected) = &state.admin_token else {
+        return Some(HttpResponse::Forbidden().json(ApiResponse {
+            status: "error".to_string(),
+            message: Some("Admin endpoints are disabled, set ADMIN_TOKEN to enable them".to_string()),
+            code: Some(ErrorCode::Unauthorized),
+        }));
+    };
+    
+    let provided = req.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compared in constant time so the token can't be guessed byte by byte
+    let matches = provided.len() == expected.len()
+        && provided.bytes().zip(expected.bytes()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if !matches {
+        warn!("Rejected admin request to {} with a missing or wrong token", req.path());
+        return Some(HttpResponse::Unauthorized().json(ApiResponse {
+            status: "error".to_string(),
+            message: Some("Missing or invalid bearer token".to_string()),
+            code: Some(ErrorCode::Unauthorized),
+        }));
+    }
+    None
+}
+
+/// Linode client errors (4xx other than 429) and unimplemented features won't
+/// succeed on retry. Transport errors are retried when they're connection
+/// level (DNS, connect, timeout), see `linode::classify_transport_error`, and
+/// anything else is retried too.
+fn is_retryable(e: &(dyn std::error::Error + 'static)) -> bool {
+    if e.is::<NotImplementedError>() || e.is::<SecretDecodeError>() || e.is::<SecretTooLargeError>() {
+        return false;
+    }
+    if let Some(err) = e.downcast_ref::<reqwest::Error>() {
+        return linode::classify_transport_error(err) == linode::ErrorClass::Retryable;
+    }
+    match e.downcast_ref::<LinodeApiError>() {
+        Some(err) => err.is_retryable(),
+        None => true,
+    }
+}
+
+/// Kubernetes API errors worth retrying: connection failures and 5xx or 429
+/// from the apiserver. A 404 or 403 won't change on retry, and neither will a
+/// secret missing its keys or holding undecodable data.
+fn is_kube_retryable(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<kube::Error>() {
+        Some(kube::Error::Api(resp)) => resp.code >= 500 || resp.code == 429,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Runs a call against `target` with exponential backoff, using the budget
+/// and base delay of its TARGET_PROFILES entry. `name` identifies it in logs
+/// and in the retry counter, which counts every attempt after the first.
+/// A 401 reloads LINODE_TOKEN_FILE and, when that yields a new token, runs the
+/// call once more with a fresh budget. A 403 never does, it's a permission
+/// problem the same account's token won't fix.
+async fn retry_target<F, Fut, T>(
+    name: &str,
+    retries: &prometheus::IntCounterVec,
+    linode: &LinodeClient,
+    target: &str,
+    operation: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let policy = RetryPolicy::for_target(&linode.profile(target));
+    match retry_with_policy(name, retries, policy, is_retryable, &operation).await {
+        Err(e) if is_unauthorized(e.as_ref()) && linode.token.reload() => {
+            info!("Retrying {} with the reloaded Linode token", name);
+            retry_with_policy(name, retries, policy, is_retryable, &operation).await
+        }
+        result => result,
+    }
+}
+
+fn is_unauthorized(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<LinodeApiError>().is_some_and(LinodeApiError::is_unauthorized)
+}
+
+/// Attempt budget and base backoff of a retried operation.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay_ms: u64,
+}
+
+impl RetryPolicy {
+    const DEFAULT: RetryPolicy = RetryPolicy { max_attempts: MAX_RETRIES, base_delay_ms: RETRY_DELAY_MS };
+    
+    fn for_target(profile: &linode::TargetProfile) -> Self {
+        RetryPolicy {
+            max_attempts: profile.max_retries.map_or(MAX_RETRIES, |retries| retries.saturating_add(1)),
+            base_delay_ms: profile.retry_base.map_or(RETRY_DELAY_MS, |base| base.as_millis() as u64),
+        }
+    }
+}
+
+/// `retry_target` with an explicit policy and retryability check.
+async fn retry_with_policy<F, Fut, T>(
+    name: &str,
+    retries: &prometheus::IntCounterVec,
+    policy: RetryPolicy,
+    is_retryable: fn(&(dyn std::error::Error + 'static)) -> bool,
+    operation: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let RetryPolicy { max_attempts, base_delay_ms } = policy;
+    let mut last_error = None;
+    
+    for attempt in 1..=max_attempts {
+        if attempt > 1 {
+            retries.with_label_values(&[name]).inc();
+            metrics::record_retry();
+        }
+        match operation().await {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                warn!("{} failed (attempt {}/{}): {}", name, attempt, max_attempts, e);
+                let retryable = is_retryable(e.as_ref());
+                last_error = Some(e);
+                
+                if !retryable {
+                    debug!("{} error is not retryable, giving up", name);
+                    break;
+                }
+                
+                if attempt < max_attempts {
+                    let backoff = base_delay_ms.saturating_mul(2u64.saturating_pow(attempt - 1));
+                    debug!("Retrying {} after {}ms", name, backoff);
+                    sleep(Duration::from_millis(backoff)).await;
+                }
+            }
+        }
+    }
+    
+    Err(last_error.unwrap_or_else(|| 
+        Box::new(std::io::Error::other("Unknown error during retry"))
+    ))
+}
+
+async fn get_secret_data(
+    state: &AppState,
+    namespace: &str,
+    name: &str,
+    keys: &SecretKeys,
+) -> Result<SecretData, Box<dyn std::error::Error>> {
+    debug!("Retrieving secret {}/{} from Kubernetes", namespace, name);
+    let secret = state.secrets.get(namespace, name).await?;
+    
+    let (cert, key) = match &state.secret_format {
+        SecretFormat::Keys => {
+            let cert_field = SecretField { name: &keys.cert, limit: state.max_secret_cert_bytes };
+            let key_field = SecretField { name: &keys.key, limit: state.max_secret_key_bytes };
+            (secret_field(&secret, cert_field, namespace, name)?, secret_field(&secret, key_field, namespace, name)?)
+        }
+        SecretFormat::Json(layout) => json_secret_pair(state, &secret, layout, namespace, name)?,
+    };
+    let mut cert = cert::normalize_pem(&cert);
+    let key = cert::normalize_pem(&key);
+    if state.append_ca_crt {
+        let ca_field = SecretField { name: CA_CRT_KEY, limit: state.max_secret_cert_bytes };
+        match secret_field(&secret, ca_field, namespace, name) {
+            Ok(ca) => {
+                let ca = cert::normalize_pem(&ca);
+                // Some issuers already put the CA in tls.crt, don't send it twice
+                if ca.trim().is_empty() || cert.contains(ca.trim()) {
+                    debug!("{} of {}/{} is empty or already in the chain", CA_CRT_KEY, namespace, name);
+                } else {
+                    debug!("Appending {} of {}/{} to the certificate", CA_CRT_KEY, namespace, name);
+                    if !cert.ends_with('\n') {
+                        cert.push('\n');
+                    }
+                    cert.push_str(&ca);
+                }
+            }
+            // Usually just absent, the certificate is sent as is either way
+            Err(e) if e.is::<SecretTooLargeError>() => return Err(e),
+            Err(e) => debug!("Not appending the CA of {}/{}: {}", namespace, name, e),
+        }
+    }
+    match cert::order_leaf_first(&cert) {
+        Ok(Some(reordered)) => {
+            info!("Certificate chain in {}/{} was not leaf-first, reordered it", namespace, name);
+            cert = reordered;
+        }
+        Ok(None) => {}
+        // Left as is, CertBundle::parse reports anything actually unusable
+        Err(e) => debug!("Could not check the chain order of {}/{}: {}", namespace, name, e),
+    }
+    Ok(SecretData { cert, key })
+}
+
+/// A secret data key and the most bytes its value may take before decoding.
+#[derive(Clone, Copy)]
+struct SecretField<'a> {
+    name: &'a str,
+    limit: usize,
+}
+
+/// Reads `field` from `data` (base64), falling back to `stringData` (raw).
+/// When both carry it, as can happen mid-edit, `data` wins. Values over the
+/// field's limit are rejected before anything is decoded.
+fn secret_field(
+    secret: &Secret,
+    field: SecretField,
+    namespace: &str,
+    name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let SecretField { name: field, limit } = field;
+    let from_data = secret.data.as_ref().and_then(|data| data.get(field));
+    let from_string_data = secret.string_data.as_ref().and_then(|data| data.get(field));
+    let size = from_data.map(|data| data.0.len()).or(from_string_data.map(String::len)).unwrap_or_default();
+    if size > limit {
+        return Err(Box::new(SecretTooLargeError {
+            field: field.to_string(),
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            size,
+            limit,
+        }));
+    }
+    
+    match (from_data, from_string_data) {
+        (Some(data), Some(_)) => {
+            info!("{} is set in both data and stringData of {}/{}, using data", field, namespace, name);
+            Ok(decode_secret_field(&data.0, field, namespace, name)?)
+        }
+        (Some(data), None) => {
+            debug!("Using {} from data of {}/{}", field, namespace, name);
+            Ok(decode_secret_field(&data.0, field, namespace, name)?)
+        }
+        (None, Some(raw)) => {
+            debug!("Using {} from stringData of {}/{}", field, namespace, name);
+            Ok(raw.clone())
+        }
+        (None, None) => Err(format!("{} not found in secret", field).into()),
+    }
+}
+
+/// Reads the certificate and key out of the JSON object stored under
+/// `layout.key`. The object may take as much as both PEMs together, each
+/// field is then held to its own limit.
+fn json_secret_pair(
+    state: &AppState,
+    secret: &Secret,
+    layout: &JsonSecretLayout,
+    namespace: &str,
+    name: &str,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let limit = state.max_secret_cert_bytes.saturating_add(state.max_secret_key_bytes);
+    let raw = secret_field(secret, SecretField { name: &layout.key, limit }, namespace, name)?;
+    let error = |detail: String| SecretDecodeError {
+        field: layout.key.clone(),
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action: "parse as JSON",
+        detail,
+    };
+    let value: serde_json::Value = serde_json::from_str(&raw).map_err(|e| error(e.to_string()))?;
+    let object = value.as_object().ok_or_else(|| error("expected an object".to_string()))?;
+    
+    let pem = |field: &str, limit: usize| -> Result<String, Box<dyn std::error::Error>> {
+        match object.get(field) {
+            Some(serde_json::Value::String(pem)) if pem.len() > limit => Err(Box::new(SecretTooLargeError {
+                field: format!("{}.{}", layout.key, field),
+                namespace: namespace.to_string(),
+                name: name.to_string(),
+                size: pem.len(),
+                limit,
+            })),
+            Some(serde_json::Value::String(pem)) => Ok(pem.clone()),
+            Some(_) => Err(Box::new(error(format!("field '{}' is not a string", field)))),
+            None => Err(Box::new(error(format!("field '{}' is missing", field)))),
+        }
+    };
+    Ok((pem(&layout.cert_field, state.max_secret_cert_bytes)?, pem(&layout.key_field, state.max_secret_key_bytes)?))
+}
+
+fn decode_secret_field(
+    data: &[u8],
+    field: &str,
+    namespace: &str,
+    name: &str,
+) -> Result<String, SecretDecodeError> {
+    let error = |action, detail: String| SecretDecodeError {
+        field: field.to_string(),
+        namespace: namespace.to_string(),
+        name: name.to_string(),
+        action,
+        detail,
+    };
+    let decoded = general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| error("base64-decode", e.to_string()))?;
+    String::from_utf8(decoded).map_err(|e| error("read as UTF-8", e.to_string()))
+}
+
+async fn update_linode_config(
+    linode: &LinodeClient,
+    https_config_id: &str,
+    cert_mode: CertMode,
+    cert: Option<&str>,
+    key: Option<&str>,
+) -> Result<Option<LinodeConfigDetail>, Box<dyn std::error::Error>> {
+    let mut headers = HeaderMap::new();
+    headers.insert(linode.auth_header.clone(), HeaderValue::from_str(&linode.auth_value())?);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    
+    // Update the existing HTTPS config using the provided ID
+    info!("Updating HTTPS config (ID: {})", https_config_id);
+    
+    let update_url = linode.config_url(https_config_id);
+    
+    let payload = cert_payload(cert_mode, cert, key)?;
+    
+    if let Some(chaos) = &linode.chaos {
+        chaos.inject(&format!("the update of config {}", https_config_id)).await?;
+    }
+    
+    if log_enabled!(log::Level::Trace) {
+        trace!("Linode request: PUT {} headers={:?} payload={}", update_url, redact_headers(&headers, &linode.auth_header), redact_payload(&payload));
+    }
+    
+    let response = linode.with_target_timeout(linode.http.put(&update_url), https_config_id)
+        .headers(headers)
+        .json(&payload)
+        .send()
+        .await
+        .map_err(|e| linode.transport_error(e))?;
+    linode.ratelimit.observe(response.headers());
+    
+    if !response.status().is_success() {
+        let err = linode.api_error(response).await;
+        error!("Failed to update Linode config: {}", err);
+        return Err(err.into());
+    }
+    
+    let status = response.status();
+    // The update already went through, a body we can't read only costs us the config details
+    let body = response.bytes().await.unwrap_or_default();
+    trace!("Linode response: {} {}", status, String::from_utf8_lossy(&body));
+    
+    info!("Successfully updated certificate in NodeBalancer config");
+    Ok(serde_json::from_slice::<LinodeConfigDetail>(&body).ok())
+}
+
+async fn get_linode_config(
+    linode: &LinodeClient,
+    config_id: &str,
+) -> Result<LinodeConfigDetail, Box<dyn std::error::Error>> {
+    let url = linode.config_url(config_id);
+    debug!("Fetching NodeBalancer config {}", config_id);
+    
+    let response = linode.authorize(linode.with_target_timeout(linode.http.get(&url), config_id))
+        .send()
+        .await
+        .map_err(|e| linode.transport_error(e))?;
+    linode.ratelimit.observe(response.headers());
+    
+    if !response.status().is_success() {
+        return Err(linode.api_error(response).await.into());
+    }
+    
+    Ok(response.json::<LinodeConfigDetail>().await?)
+}
+
+/// Builds the config update body carrying the certificate for the given mode.
+/// Linode leaves fields missing from a PUT untouched, so a partial update
+/// simply omits the cert or the key. The same goes for the rest of the config:
+/// `check`, `check_interval`, `check_path`, `check_body` and the other
+/// settings are never sent, so a cert update can't reset active health checks.
+fn cert_payload(
+    mode: CertMode,
+    cert: Option<&str>,
+    key: Option<&str>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    match mode {
+        CertMode::Inline => {
+            let mut payload = serde_json::json!({ "protocol": "https" });
+            if let Some(cert) = cert {
+                payload["ssl_cert"] = serde_json::Value::String(cert.to_string());
+            }
+            if let Some(key) = key {
+                payload["ssl_key"] = serde_json::Value::String(key.to_string());
+            }
+            Ok(payload)
+        }
+        // Upload the cert/key elsewhere and send a reference to it instead
+        CertMode::Reference => Err(Box::new(NotImplementedError(
+            "LINODE_CERT_MODE=reference is not implemented yet".to_string(),
+        ))),
+    }
+}
+
+fn redact_headers(headers: &HeaderMap, auth_header: &HeaderName) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|(name, value)| {
+            let value = if name == AUTHORIZATION || name == auth_header {
+                "<redacted>".to_string()
+            } else {
+                value.to_str().unwrap_or("<binary>").to_string()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn redact_payload(payload: &serde_json::Value) -> serde_json::Value {
+    let mut payload = payload.clone();
+    if let Some(key) = payload.get_mut("ssl_key") {
+        *key = serde_json::Value::String("<redacted>".to_string());
+    }
+    payload
+}
+
+/// Some forwarders label JSON bodies as `text/plain`, accept those alongside
+/// `application/json` and `+json` types (with or without a charset).
+fn is_json_compatible(mime: mime::Mime) -> bool {
+    mime.subtype() == mime::JSON
+        || mime.suffix() == Some(mime::JSON)
+        || (mime.type_() == mime::TEXT && mime.subtype() == mime::PLAIN)
+}
+
+/// Default service of both listeners, so unknown paths get an ApiResponse
+/// instead of actix's empty 404.
+async fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse {
+        status: "error".to_string(),
+        message: Some("not found".to_string()),
+        code: None,
+    })
+}
+
+fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> Error {
+    error!("JSON payload error: {}", err);
+    if let Some(state) = req.app_data::<web::Data<Arc<AppState>>>() {
+        let code = match &err {
+            JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => ErrorCode::PayloadTooLarge,
+            _ => ErrorCode::InvalidJson,
+        };
+        record_validation_failure(state, req, code, None);
+    }
+    let (status, response) = match &err {
+        JsonPayloadError::OverflowKnownLength { limit, .. } | JsonPayloadError::Overflow { limit } => {
+            (StatusCode::PAYLOAD_TOO_LARGE, ApiResponse {
+                status: "error".to_string(),
+                message: Some(format!("Payload too large, the limit is {} bytes", limit)),
+                code: Some(ErrorCode::PayloadTooLarge),
+            })
+        }
+        JsonPayloadError::ContentType => (StatusCode::UNSUPPORTED_MEDIA_TYPE, ApiResponse {
+            status: "error".to_string(),
+            message: Some("Unsupported content type, expected a JSON body".to_string()),
+            code: Some(ErrorCode::InvalidJson),
+        }),
+        _ => (StatusCode::BAD_REQUEST, ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!("Invalid JSON payload: {}", err)),
+            code: Some(ErrorCode::InvalidJson),
+        }),
+    };
+    let response = HttpResponse::build(status).json(IdentifiedResponse { response, request_id: request_id(req) });
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+/// Reads an update body like `web::Json` would, with the same limit, content
+/// type rule and error responses, but keeping the raw bytes so that with
+/// WEBHOOK_HMAC_SECRET set their X-Signature-256 can be checked before parsing.
+async fn read_signed_json<T: serde::de::DeserializeOwned>(
+    req: &HttpRequest,
+    state: &AppState,
+    mut payload: web::Payload,
+) -> Result<T, Error> {
+    // A missing content type is fine, like content_type_required(false)
+    let json = match req.mime_type() {
+        Ok(None) => true,
+        Ok(Some(mime)) => is_json_compatible(mime),
+        Err(_) => false,
+    };
+    if !json {
+        return Err(json_error_handler(JsonPayloadError::ContentType, req));
+    }
+    let length = req.headers().get(actix_web::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if let Some(length) = length.filter(|length| *length > MAX_JSON_BYTES) {
+        return Err(json_error_handler(JsonPayloadError::OverflowKnownLength { length, limit: MAX_JSON_BYTES }, req));
+    }
+    
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| json_error_handler(JsonPayloadError::Payload(e), req))?;
+        if body.len() + chunk.len() > MAX_JSON_BYTES {
+            return Err(json_error_handler(JsonPayloadError::Overflow { limit: MAX_JSON_BYTES }, req));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    
+    if let Some(secret) = &state.webhook_hmac_secret {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+        let signed = SignedRequest { body: &body, signature: header(SIGNATURE_HEADER), timestamp: header(TIMESTAMP_HEADER) };
+        if let Err((code, e)) = verify_signature(secret.as_bytes(), &signed, cert::unix_now(), state.signature_max_age_secs) {
+            warn!("Rejecting update request: {}", e);
+            record_validation_failure(state, req, code, None);
+            let response = HttpResponse::Unauthorized().json(IdentifiedResponse {
+                response: ApiResponse {
+                    status: "error".to_string(),
+                    message: Some(format!("Invalid request signature: {}", e)),
+                    code: Some(code),
+                },
+                request_id: request_id(req),
+            });
+            return Err(actix_web::error::InternalError::from_response(e, response).into());
+        }
+    }
+    
+    serde_json::from_slice(&body).map_err(|e| json_error_handler(JsonPayloadError::Deserialize(e), req))
+}
+
+/// What a signed update request carries besides its body.
+struct SignedRequest<'a> {
+    body: &'a [u8],
+    signature: Option<&'a str>,
+    timestamp: Option<&'a str>,
+}
+
+/// Checks an `X-Signature-256` value, the hex HMAC-SHA256 of
+/// `<X-Timestamp>.<body>` with an optional `sha256=` prefix, comparing in
+/// constant time. The timestamp has to be within `max_age_secs` of `now`.
+fn verify_signature(
+    secret: &[u8],
+    request: &SignedRequest,
+    now: i64,
+    max_age_secs: u64,
+) -> Result<(), (ErrorCode, &'static str)> {
+    let invalid = |e| (ErrorCode::InvalidSignature, e);
+    let signature = request.signature.ok_or(invalid("missing X-Signature-256 header"))?.trim();
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let provided = hex::decode(signature).map_err(|_| invalid("X-Signature-256 is not hex"))?;
+    let timestamp = request.timestamp.ok_or(invalid("missing X-Timestamp header"))?.trim();
+    let signed_at: i64 = timestamp.parse().map_err(|_| invalid("X-Timestamp is not unix seconds"))?;
+    
+    let key = openssl::pkey::PKey::hmac(secret).map_err(|_| invalid("failed to load the HMAC key"))?;
+    let expected = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)
+        .and_then(|mut signer| {
+            signer.update(timestamp.as_bytes())?;
+            signer.update(b".")?;
+            signer.update(request.body)?;
+            signer.sign_to_vec()
+        })
+        .map_err(|_| invalid("failed to compute the HMAC"))?;
+    if provided.len() != expected.len() || !openssl::memcmp::eq(&provided, &expected) {
+        return Err(invalid("signature does not match the body"));
+    }
+    // After the signature, so a forged timestamp is reported as a bad signature
+    if now.abs_diff(signed_at) > max_age_secs {
+        return Err((ErrorCode::SignatureExpired, "X-Timestamp is outside SIGNATURE_MAX_AGE_SECS"));
+    }
+    Ok(())
+}
+
+/// Builds the shared state from a loaded config, taking what it needs out of
+/// `config`. Startup-only settings such as ports and leader election are left.
+fn build_state(
+    config: &mut config::Config,
+    kube_client: Client,
+    secrets: Box<dyn secrets::SecretSource>,
+    http_client: reqwest::Client,
+    alert_http_client: reqwest::Client,
+    metrics: metrics::Metrics,
+    last_applied: state::StateStore,
+) -> AppState {
+    let linode = LinodeClient {
+        http: http_client,
+        api_url: std::mem::take(&mut config.linode_api_url),
+        token: Arc::new(linode::LinodeToken::new(
+            std::mem::take(&mut config.linode_token),
+            config.linode_token_file.take(),
+            metrics.linode_token_reloads.clone(),
+        )),
+        auth_header: config.linode_auth_header.clone(),
+        auth_scheme: std::mem::take(&mut config.linode_auth_scheme),
+        nodebalancer_id: std::mem::take(&mut config.nodebalancer_id),
+        lb_type: config.lb_type,
+        profiles: Arc::new(std::mem::take(&mut config.target_profiles)),
+        ratelimit: linode::RateLimits {
+            remaining: metrics.linode_ratelimit_remaining.clone(),
+            limit: metrics.linode_ratelimit_limit.clone(),
+            warn_below: config.linode_ratelimit_warn_below,
+        },
+        errors: metrics.linode_errors.clone(),
+        chaos: config.chaos,
+    };
+    let provider: Box<dyn provider::CertProvider> = match (config.provider, config.lb_type) {
+        (provider::ProviderKind::Linode, linode::LbType::Aglb) => Box::new(provider::AglbProvider {
+            client: linode.clone(),
+            update_duration: metrics.linode_update_duration.clone(),
+            retries: metrics.retries.clone(),
+        }),
+        (provider::ProviderKind::Linode, linode::LbType::NodeBalancer) => Box::new(provider::LinodeProvider {
+            client: linode.clone(),
+            cert_mode: config.cert_mode,
+            update_duration: metrics.linode_update_duration.clone(),
+            retries: metrics.retries.clone(),
+        }),
+        (provider::ProviderKind::Noop, _) => {
+            warn!("CERT_PROVIDER=noop: certificates are accepted but never pushed anywhere");
+            Box::new(provider::NoopProvider)
+        }
+    };
+    
+    let alerts = config.alert.take().map(|alert_config| {
+        info!(
+            "Alerting on repeated validation failures through {}{}",
+            alert_config.url,
+            if alert_config.template.is_some() { " with ALERT_TEMPLATE" } else { "" }
+        );
+        alert::FailureAlerts::new(alert_config, alert_http_client.clone())
     });
+    let annotations = config.dedup_annotation.then(|| {
+        Box::new(state::SecretAnnotations::new(kube_client.clone())) as Box<dyn state::FingerprintStore>
+    });
+    
+    AppState {
+        kube_client,
+        secrets,
+        linode,
+        https_config_ids: std::mem::take(&mut config.https_config_ids),
+        san_map: std::mem::take(&mut config.san_map),
+        allowed_config_ids: std::mem::take(&mut config.allowed_config_ids),
+        allowed_secrets: std::mem::take(&mut config.allowed_secrets),
+        allowed_fingerprints: std::mem::take(&mut config.allowed_fingerprints),
+        max_batch: config.max_batch,
+        batch_concurrency: config.batch_concurrency,
+        batch_fail_fast: config.batch_fail_fast,
+        max_parallel_config_updates: config.max_parallel_config_updates,
+        last_applied,
+        apply_window: config.apply_window.take(),
+        // Only durable when main loads it from the pending ConfigMap
+        pending: state::StateStore::memory(),
+        annotations,
+        secret_keys: std::mem::take(&mut config.secret_keys),
+        secret_format: std::mem::take(&mut config.secret_format),
+        append_ca_crt: config.append_ca_crt,
+        default_namespace: config.default_namespace.take(),
+        max_secret_cert_bytes: config.max_secret_cert_bytes,
+        max_secret_key_bytes: config.max_secret_key_bytes,
+        allow_self_signed: config.allow_self_signed,
+        history: history::History::new(),
+        metrics,
+        live_verify: std::mem::take(&mut config.live_verify),
+        post_verify: std::mem::take(&mut config.post_verify),
+        nodebalancer_host: std::mem::take(&mut config.nodebalancer_host),
+        prefer_ip: config.prefer_ip,
+        expiry_warn_days: config.expiry_warn_days,
+        deep_health_mode: config.deep_health_mode,
+        chain_policy: config.chain_policy,
+        max_chain_certs: config.max_chain_certs,
+        extra_health_urls: std::mem::take(&mut config.extra_health_urls),
+        extra_health_timeout: config.extra_health_timeout,
+        health_http: alert_http_client.clone(),
+        provider,
+        success_status: config.success_status,
+        apply_delay: config.apply_delay,
+        async_apply: config.async_apply,
+        trusted_proxies: Arc::new(std::mem::take(&mut config.trusted_proxies)),
+        alerts,
+        admin_token: std::mem::take(&mut config.admin_token),
+        webhook_hmac_secret: config.webhook_hmac_secret.take(),
+        signature_max_age_secs: config.signature_max_age_secs,
+        clock_skew_secs: config.clock_skew_secs,
+        kube_max_retries: config.kube_max_retries,
+        ready_requires_sync: config.ready_requires_sync,
+        include_timing: config.include_timing,
+        synced: AtomicBool::new(false),
+        update_permits: config.max_concurrent_updates.map(tokio::sync::Semaphore::new),
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Initialize logging with more verbose format
+    env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"))
+        .format_timestamp_millis()
+        .format_module_path(true)
+        .init();
+    
+    if env::args().nth(1).as_deref() == Some("selftest") {
+        if let Err(e) = selftest::run().await {
+            exit_on_startup_error(format!("selftest failed: {}", e));
+        }
+        return Ok(());
+    }
+    
+    // Settings come from the environment, optionally layered over CONFIG_PATH
+    let mut config = match config::Config::load().await {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in &errors {
+                error!("Invalid configuration: {}", e);
+            }
+            exit_on_startup_error(format!("{} configuration error(s), exiting", errors.len()));
+        }
+    };
+    if config.cert_mode == CertMode::Reference {
+        warn!("LINODE_CERT_MODE=reference is not implemented yet, certificate updates will fail");
+    }
+    
+    // Initialize Kubernetes client
+    let kube_client = Client::try_default()
+        .await
+        .unwrap_or_else(|e| exit_on_startup_error(format!("Failed to create Kubernetes client: {}", e)));
+    
+    // Initialize HTTP client with timeouts and connection pooling
+    let mut http_client_builder = ClientBuilder::new()
+        .timeout(config.http_timeout)
+        .connect_timeout(config.http_connect_timeout)
+        .user_agent(config.http_user_agent.as_str())
+        .pool_max_idle_per_host(10)
+        .pool_idle_timeout(Duration::from_secs(60));
+    
+    // Extra roots for clusters behind a TLS-inspecting proxy
+    if let Some(path) = &config.linode_ca_bundle {
+        let pem = std::fs::read(path)
+            .unwrap_or_else(|e| exit_on_startup_error(format!("Failed to read LINODE_CA_BUNDLE {}: {}", path, e)));
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .unwrap_or_else(|e| exit_on_startup_error(format!("Failed to parse LINODE_CA_BUNDLE {}: {}", path, e)));
+        info!("Loaded {} additional root certificate(s) from {}", certs.len(), path);
+        for cert in certs {
+            http_client_builder = http_client_builder.add_root_certificate(cert);
+        }
+    }
+    
+    if config.linode_tls_insecure {
+        warn!("!!! LINODE_TLS_INSECURE is set: TLS certificates of the Linode API are NOT verified !!!");
+        warn!("!!! This is only meant for debugging, never run it like this in production !!!");
+        http_client_builder = http_client_builder.danger_accept_invalid_certs(true);
+    }
+    
+    let http_client = http_client_builder
+        .build()
+        .expect("Failed to build HTTP client");
+    // Alerts go to arbitrary receivers, so they don't share the Linode roots or TLS overrides
+    let alert_http_client = ClientBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .user_agent(config.http_user_agent.as_str())
+        .build()
+        .expect("Failed to build HTTP client");
     
     // Set up Prometheus metrics
-    let prometheus = PrometheusMetricsBuilder::new("cert_webhook")
-        .endpoint("/metrics")
+    let registry = prometheus::Registry::new();
+    let metrics = metrics::Metrics::new(&registry, &config.metrics_namespace).expect("Failed to register metrics");
+    // /metrics is served by metrics_export, which negotiates the format, not by the middleware
+    let prometheus = PrometheusMetricsBuilder::new(&config.metrics_namespace)
+        .registry(registry.clone())
         .build()
         .unwrap();
     
+    let state_namespace = config.state_configmap_namespace.take()
+        .unwrap_or_else(|| kube_client.default_namespace().to_string());
+    let last_applied = match &config.state_configmap {
+        Some(name) => state::StateStore::load(kube_client.clone(), &state_namespace, name, "last-applied fingerprint(s)").await,
+        None => state::StateStore::memory(),
+    };
+    // Updates held back by APPLY_WINDOW, only queued when they survive a restart
+    let pending = match (&config.state_configmap, &config.apply_window) {
+        (Some(name), Some(_)) => {
+            let name = format!("{}-pending", name);
+            state::StateStore::load(kube_client.clone(), &state_namespace, &name, "pending update(s)").await
+        }
+        (None, Some(_)) => {
+            warn!("APPLY_WINDOW is set without STATE_CONFIGMAP, updates outside the window are refused with a 503");
+            state::StateStore::memory()
+        }
+        _ => state::StateStore::memory(),
+    };
+    
+    let secrets = Box::new(secrets::KubeSecrets { client: kube_client.clone() });
+    let state = Arc::new(AppState {
+        pending,
+        ..build_state(
+            &mut config,
+            kube_client,
+            secrets,
+            http_client,
+            alert_http_client,
+            metrics,
+            last_applied,
+        )
+    });
+    let trusted_proxies = state.trusted_proxies.clone();
+    if let Some(chaos) = state.linode.chaos {
+        warn!(
+            "ENABLE_CHAOS is on: config updates are delayed {}ms and {:.0}% of them fail on purpose",
+            chaos.latency.as_millis(),
+            chaos.fail_rate * 100.0
+        );
+    }
+    
+    // One line with everything that shapes this pod's behaviour, secrets redacted
+    let mut summary = export::effective_config(&state);
+    if let serde_json::Value::Object(fields) = &mut summary {
+        fields.extend([
+            ("cert_mode".to_string(), serde_json::json!(format!("{:?}", config.cert_mode).to_lowercase())),
+            ("port".to_string(), serde_json::json!(config.port)),
+            ("metrics_port".to_string(), serde_json::json!(config.metrics_port)),
+            ("route_prefix".to_string(), serde_json::json!(config.route_prefix)),
+            ("http_timeout_secs".to_string(), serde_json::json!(config.http_timeout.as_secs())),
+            ("http_connect_timeout_secs".to_string(), serde_json::json!(config.http_connect_timeout.as_secs())),
+            ("http_user_agent".to_string(), serde_json::json!(config.http_user_agent)),
+            ("linode_ca_bundle".to_string(), serde_json::json!(config.linode_ca_bundle)),
+            ("linode_tls_insecure".to_string(), serde_json::json!(config.linode_tls_insecure)),
+            ("state_configmap".to_string(), serde_json::json!(config.state_configmap)),
+            ("leader_election".to_string(), serde_json::json!(config.leader_election.as_ref().map(|election| serde_json::json!({
+                "lease_name": election.lease_name,
+                "lease_duration_secs": election.lease_duration.as_secs(),
+            })))),
+            ("revalidate".to_string(), serde_json::json!(config.revalidate.as_ref().map(|(interval, targets)| serde_json::json!({
+                "interval_secs": interval.as_secs(),
+                "targets": targets.iter().map(|target| format!("{}/{}", target.namespace, target.secret_name)).collect::<Vec<_>>(),
+            })))),
+            ("certificate_name".to_string(), serde_json::json!(config.certificate_name)),
+            ("watch_concurrency".to_string(), serde_json::json!(config.watch_concurrency)),
+            ("input_mode".to_string(), serde_json::json!(format!("{:?}", config.input_mode).to_lowercase())),
+            ("queue".to_string(), serde_json::json!(config.queue.as_ref().map(|queue| serde_json::json!({
+                "addr": queue.addr,
+                "db": queue.db,
+                "stream": queue.stream,
+                "group": queue.group,
+                "consumer": queue.consumer,
+            })))),
+        ]);
+    }
+    info!("Effective configuration: {}", summary);
+    
+    // Only the lease holder runs background reconciles, every replica serves requests
+    let leadership = if let Some(election) = config.leader_election {
+        let lease_config = leader::LeaderConfig {
+            lease_name: election.lease_name,
+            lease_namespace: election.lease_namespace
+                .unwrap_or_else(|| state.kube_client.default_namespace().to_string()),
+            identity: env::var("POD_NAME")
+                .or_else(|_| env::var("HOSTNAME"))
+                .unwrap_or_else(|_| "cert-webhook".to_string()),
+            lease_duration: election.lease_duration,
+            renew_interval: (election.lease_duration / 3).max(Duration::from_secs(1)),
+        };
+        leader::spawn(state.kube_client.clone(), lease_config, state.metrics.is_leader.clone())
+    } else {
+        leader::Leadership::always(&state.metrics.is_leader)
+    };
+    
+    if let Some((interval, targets)) = config.revalidate {
+        if targets.is_empty() {
+            warn!("REVALIDATE_INTERVAL_SECS is set but REVALIDATE_TARGETS is empty, not revalidating");
+        } else {
+            reconcile::spawn(state.clone(), targets, interval, leadership.clone());
+        }
+    }
+    
+    if state.apply_window.is_some() {
+        window::spawn(state.clone(), leadership.clone());
+    }
+    
+    let mut watch_queue = None;
+    if let Some(name) = config.certificate_name {
+        let target = watch::CertificateTarget {
+            namespace: config.certificate_namespace
+                .unwrap_or_else(|| state.kube_client.default_namespace().to_string()),
+            name,
+        };
+        let queue = watch::WorkQueue::new(config.watch_concurrency);
+        watch::spawn(state.clone(), target, leadership.clone(), queue.clone());
+        watch_queue = Some(queue);
+    }
+    // Every replica consumes, the consumer group hands each request to one of them
+    if let Some(queue) = config.queue {
+        queue::spawn(state.clone(), Box::new(queue::RedisStreamConsumer::new(queue)));
+    }
+    let input_mode = config.input_mode;
+    if !input_mode.serves_http() {
+        info!("INPUT_MODE=queue, not serving the update routes");
+    }
+    
+    let port = config.port;
+    let metrics_port = config.metrics_port;
     info!("Starting webhook server on port {}", port);
     
-    HttpServer::new(move || {
+    let route_prefix = config.route_prefix;
+    if !route_prefix.is_empty() {
+        info!("Serving every route under {}", route_prefix);
+    }
+    let server_prefix = route_prefix.clone();
+    let shutdown_state = state.clone();
+    let server_state = state.clone();
+    let server_proxies = trusted_proxies.clone();
+    let server_registry = registry.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(request_logger(server_proxies.clone()))
             .wrap(middleware::Compress::default())
             .wrap(prometheus.clone())
-            .app_data(web::Data::new(state.clone()))
-            .app_data(web::JsonConfig::default()
-                .limit(256 * 1024)  // 256k payload limit
-                .error_handler(|err, _| {
-                    error!("JSON payload error: {}", err);
-                    actix_web::error::InternalError::from_response(
-                        err, 
-                        HttpResponse::BadRequest().json(ApiResponse {
-                            status: "error".to_string(),
-                            message: Some("Invalid JSON payload".to_string()),
-                        })
-                    ).into()
+            // Outermost, so the logger and the handlers all see the same id
+            .wrap_fn(|req, srv| {
+                let id = new_request_id(req.request());
+                req.extensions_mut().insert(RequestId(id.clone()));
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&id) {
+                        response.headers_mut().insert(actix_web::http::header::HeaderName::from_static("x-request-id"), value);
+                    }
+                    Ok(response)
+                }
+            })
+            .app_data(web::Data::new(server_state.clone()))
+            .app_data(web::Data::new(server_registry.clone()))
+            .app_data(json_config())
+            .service(web::scope(&server_prefix)
+                .configure(|cfg| {
+                    // With a separate metrics port, observability and admin stay off the public listener
+                    if metrics_port.is_none() {
+                        health_routes(cfg);
+                        admin_routes(cfg);
+                        cfg.route("/metrics", web::get().to(metrics_export));
+                    }
+                })
+                .configure(|cfg| {
+                    if input_mode.serves_http() {
+                        // PUT is accepted too for clients that want idempotent semantics, a repeat is deduplicated
+                        cfg.route("/update-nodebalancer-cert", web::post().to(update_nodebalancer_cert))
+                            .route("/update-nodebalancer-cert", web::put().to(update_nodebalancer_cert))
+                            .route("/update-nodebalancer-cert", web::method(actix_web::http::Method::OPTIONS).to(describe_update_endpoint))
+                            .route("/update-batch", web::post().to(update_batch));
+                    }
                 }))
-            .route("/health", web::get().to(health_check))
-            .route("/health/deep", web::get().to(deep_health_check))
-            .route("/metrics", web::get().to(|| async { HttpResponse::Ok().body("") }))
-            .route("/update-nodebalancer-cert", web::post().to(update_nodebalancer_cert))
+            .default_service(web::to(not_found))
     })
     .keep_alive(Duration::from_secs(75))  // Keep-alive timeout
     .workers(num_cpus::get())  // Use number of CPU cores for worker threads
     .shutdown_timeout(30)  // Allow 30 seconds for graceful shutdown
+    .disable_signals()
     .bind(("0.0.0.0", port))?
-    .run()
-    .await
-}
\ No newline at end of file
+    .run();
+    
+    let metrics_server = match metrics_port {
+        Some(metrics_port) => {
+            info!("Serving /metrics, health checks and admin routes on port {}", metrics_port);
+            let metrics_server = HttpServer::new(move || {
+                App::new()
+                    .wrap(request_logger(trusted_proxies.clone()))
+                    .app_data(web::Data::new(state.clone()))
+                    .app_data(web::Data::new(registry.clone()))
+                    .app_data(json_config())
+                    .service(web::scope(&route_prefix)
+                        .configure(health_routes)
+                        .configure(admin_routes)
+                        .route("/metrics", web::get().to(metrics_export)))
+                    .default_service(web::to(not_found))
+            })
+            .workers(1)
+            .shutdown_timeout(30)
+            .disable_signals()
+            .bind(("0.0.0.0", metrics_port))?
+            .run();
+            Some(metrics_server)
+        }
+        None => None,
+    };
+    
+    // Handled here rather than by the servers, so the watch queue stops taking pushes before they stop
+    let mut handles = vec![server.handle()];
+    handles.extend(metrics_server.as_ref().map(|metrics_server| metrics_server.handle()));
+    let signal_queue = watch_queue.clone();
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    actix_web::rt::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        info!("Shutdown signal received, stopping the server");
+        if let Some(queue) = signal_queue {
+            queue.close();
+        }
+        futures::future::join_all(handles.iter().map(|handle| handle.stop(true))).await;
+    });
+    
+    match metrics_server {
+        Some(metrics_server) => {
+            futures::try_join!(server, metrics_server)?;
+        }
+        None => server.await?,
+    }
+    
+    // Watch pushes finish before the state they record is flushed
+    if let Some(queue) = watch_queue {
+        queue.drain(Duration::from_secs(SHUTDOWN_DRAIN_TIMEOUT_SECS)).await;
+    }
+    
+    // In-flight requests have drained, make sure the latest applied state is persisted
+    info!("Server stopped, flushing state");
+    let flush_deadline = Duration::from_secs(SHUTDOWN_FLUSH_TIMEOUT_SECS);
+    let flush = async { futures::join!(shutdown_state.last_applied.flush(), shutdown_state.pending.flush()) };
+    if tokio::time::timeout(flush_deadline, flush).await.is_err() {
+        warn!("State flush did not finish within {}s", SHUTDOWN_FLUSH_TIMEOUT_SECS);
+    }
+    info!("Shutdown complete");
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const SECRET: &[u8] = b"test-secret";
+    const NOW: i64 = 1_700_000_000;
+
+    fn sign(timestamp: &str, body: &[u8]) -> String {
+        let key = openssl::pkey::PKey::hmac(SECRET).unwrap();
+        let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key).unwrap();
+        format!("sha256={}", hex::encode(signer.sign_oneshot_to_vec(&[timestamp.as_bytes(), b".", body].concat()).unwrap()))
+    }
+
+    fn verify(body: &[u8], signature: Option<&str>, timestamp: Option<&str>) -> Result<(), ErrorCode> {
+        verify_signature(SECRET, &SignedRequest { body, signature, timestamp }, NOW, 300).map_err(|(code, _)| code)
+    }
+
+    #[test]
+    fn signature_over_timestamp_and_body_is_accepted() {
+        let timestamp = NOW.to_string();
+        let signature = sign(&timestamp, b"{}");
+        assert!(verify(b"{}", Some(&signature), Some(&timestamp)).is_ok());
+        // The prefix is optional
+        assert!(verify(b"{}", Some(signature.trim_start_matches("sha256=")), Some(&timestamp)).is_ok());
+    }
+
+    #[test]
+    fn replayed_signature_is_expired() {
+        let timestamp = (NOW - 301).to_string();
+        let signature = sign(&timestamp, b"{}");
+        assert_eq!(verify(b"{}", Some(&signature), Some(&timestamp)), Err(ErrorCode::SignatureExpired));
+        let timestamp = (NOW + 301).to_string();
+        let signature = sign(&timestamp, b"{}");
+        assert_eq!(verify(b"{}", Some(&signature), Some(&timestamp)), Err(ErrorCode::SignatureExpired));
+    }
+
+    #[test]
+    fn timestamp_is_part_of_the_signature() {
+        let signature = sign(&(NOW - 3600).to_string(), b"{}");
+        assert_eq!(verify(b"{}", Some(&signature), Some(&NOW.to_string())), Err(ErrorCode::InvalidSignature));
+    }
+
+    #[test]
+    fn missing_timestamp_is_rejected() {
+        let signature = sign(&NOW.to_string(), b"{}");
+        assert_eq!(verify(b"{}", Some(&signature), None), Err(ErrorCode::InvalidSignature));
+        assert_eq!(verify(b"{}", Some(&signature), Some("yesterday")), Err(ErrorCode::InvalidSignature));
+    }
+
+    async fn post_update(state: Arc<AppState>, body: Vec<u8>, headers: &[(&str, String)]) -> (StatusCode, serde_json::Value) {
+        let app = actix_web::test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .route("/update-nodebalancer-cert", web::post().to(update_nodebalancer_cert))).await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/update-nodebalancer-cert")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(body);
+        let req = headers.iter().fold(req, |req, header| req.insert_header(header.clone())).to_request();
+        let response = actix_web::test::call_service(&app, req).await;
+        let status = response.status();
+        (status, actix_web::test::read_body_json(response).await)
+    }
+
+    #[actix_web::test]
+    async fn body_over_the_limit_is_413() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, Default::default());
+
+        let (status, body) = post_update(state.clone(), vec![b' '; MAX_JSON_BYTES + 1], &[]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["code"], "PAYLOAD_TOO_LARGE");
+
+        // At the limit the body is read and parsed
+        let (status, body) = post_update(state, vec![b' '; MAX_JSON_BYTES], &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "INVALID_JSON");
+        mock.stop().await;
+    }
+
+    fn secret_with(data: Option<&str>, string_data: Option<&str>) -> Secret {
+        Secret {
+            data: data.map(|value| {
+                [("tls.crt".to_string(), k8s_openapi::ByteString(general_purpose::STANDARD.encode(value).into_bytes()))].into()
+            }),
+            string_data: string_data.map(|value| [("tls.crt".to_string(), value.to_string())].into()),
+            ..Default::default()
+        }
+    }
+
+    fn read_field(secret: &Secret, limit: usize) -> Result<String, String> {
+        secret_field(secret, SecretField { name: "tls.crt", limit }, "ns", "name").map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn secret_field_reads_data_or_string_data() {
+        assert_eq!(read_field(&secret_with(Some("from data"), None), 1024).unwrap(), "from data");
+        assert_eq!(read_field(&secret_with(None, Some("from stringData")), 1024).unwrap(), "from stringData");
+        assert!(read_field(&secret_with(None, None), 1024).is_err());
+    }
+
+    #[test]
+    fn secret_field_prefers_data_over_string_data() {
+        let secret = secret_with(Some("from data"), Some("from stringData"));
+        assert_eq!(read_field(&secret, 1024).unwrap(), "from data");
+    }
+
+    #[test]
+    fn secret_field_over_the_limit_is_rejected() {
+        assert!(read_field(&secret_with(None, Some("0123456789")), 9).unwrap_err().contains("is 10 bytes, over the 9-byte limit"));
+        assert!(read_field(&secret_with(None, Some("0123456789")), 10).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_reloads_the_token_and_forbidden_does_not() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let token_file = std::env::temp_dir().join(format!("cert-webhook-test-{}-reload.token", std::process::id()));
+        std::fs::write(&token_file, "expired").unwrap();
+        let config = testutil::config(&mock, &[
+            ("LINODE_TOKEN", ""),
+            ("LINODE_TOKEN_FILE", token_file.to_str().unwrap()),
+        ]).await;
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+
+        // Rotated after startup, so the first call is rejected with the old token
+        std::fs::write(&token_file, selftest::TOKEN).unwrap();
+        let (status, response) = process_update(&state, &selftest::hook(selftest::SECRET_NAME), UpdateSource::Webhook).await;
+        assert_eq!((status, response.status.as_str()), (StatusCode::OK, "success"));
+        assert_eq!(state.metrics.linode_token_reloads.get(), 1);
+
+        let mut forbidden = selftest::hook(selftest::SECRET_NAME);
+        forbidden.config_id = Some(selftest::FORBIDDEN_CONFIG_ID.to_string());
+        let (_, response) = process_update(&state, &forbidden, UpdateSource::Webhook).await;
+        assert_eq!(response.status, "error");
+        assert_eq!(state.metrics.linode_token_reloads.get(), 1);
+
+        // The forbidden config is read before the update, and both are refused
+        let errors = ["unauthorized", "forbidden"].map(|reason| state.metrics.linode_errors.with_label_values(&[reason]).get());
+        assert_eq!(errors, [1, 2]);
+        mock.stop().await;
+        std::fs::remove_file(&token_file).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn scheduled_update_is_refused_before_the_202() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config = testutil::config(&mock, &[("ASYNC_APPLY", "true"), ("ALLOWED_SECRETS", "selftest=selftest-*")]).await;
+        let state = testutil::state(config, Default::default());
+        let req = actix_web::test::TestRequest::post().to_http_request();
+        
+        let response = schedule_update(&req, &state, selftest::hook("other-tls")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        state.metrics.paused.pause(cert::unix_now() + 60);
+        let response = schedule_update(&req, &state, selftest::hook(selftest::SECRET_NAME)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        state.metrics.paused.resume();
+        let response = schedule_update(&req, &state, selftest::hook(selftest::SECRET_NAME)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        mock.stop().await;
+    }
+    
+    #[actix_web::test]
+    async fn key_from_another_certificate_is_rejected() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (cert_pem, _) = selftest::generate_chain().unwrap();
+        let (_, other_key) = selftest::generate_chain().unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &other_key)]));
+        
+        let (status, response) = process_update(&state, &selftest::hook(selftest::SECRET_NAME), UpdateSource::Webhook).await;
+        assert_eq!((status, response.code), (StatusCode::UNPROCESSABLE_ENTITY, Some(ErrorCode::KeyMismatch)));
+        assert!(mock.configs.lock().unwrap()[selftest::CONFIG_ID].get("ssl_cert").is_none());
+        mock.stop().await;
+    }
+    
+    #[actix_web::test]
+    async fn certificate_outside_allowed_fingerprints_is_rejected() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (pinned_cert, pinned_key) = selftest::generate_chain().unwrap();
+        let (other_cert, other_key) = selftest::generate_chain().unwrap();
+        // In the colon-separated uppercase form openssl prints, to cover the normalization
+        let pinned = cert::leaf_fingerprint(&pinned_cert).unwrap().to_ascii_uppercase();
+        let pinned: Vec<&str> = (0..pinned.len()).step_by(2).map(|i| &pinned[i..i + 2]).collect();
+        let config = testutil::config(&mock, &[("ALLOWED_FINGERPRINTS", &pinned.join(":"))]).await;
+        let state = testutil::state(config, testutil::secrets(&[
+            (selftest::SECRET_NAME, &pinned_cert, &pinned_key),
+            ("other-tls", &other_cert, &other_key),
+        ]));
+
+        let (status, response) = process_update(&state, &selftest::hook("other-tls"), UpdateSource::Webhook).await;
+        assert_eq!((status, response.code), (StatusCode::FORBIDDEN, Some(ErrorCode::FingerprintNotAllowed)));
+        assert!(mock.configs.lock().unwrap()[selftest::CONFIG_ID].get("ssl_cert").is_none());
+
+        let (status, response) = process_update(&state, &selftest::hook(selftest::SECRET_NAME), UpdateSource::Webhook).await;
+        assert_eq!((status, response.status.as_str()), (StatusCode::OK, "success"));
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn connection_errors_are_retried_the_configured_number_of_times() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, Default::default());
+        // A port nothing listens on, so every attempt fails before any response
+        let closed_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let profile = linode::TargetProfile { max_retries: Some(2), retry_base: Some(Duration::from_millis(1)), ..Default::default() };
+        let unreachable = LinodeClient {
+            api_url: format!("http://{}/v4", closed_addr),
+            profiles: Arc::new(HashMap::from([(selftest::CONFIG_ID.to_string(), profile)])),
+            ..state.linode.clone()
+        };
+
+        let result = retry_target("linode_get_config", &state.metrics.retries, &unreachable, selftest::CONFIG_ID, || {
+            get_linode_config(&unreachable, selftest::CONFIG_ID)
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(state.metrics.retries.with_label_values(&["linode_get_config"]).get(), 2);
+        assert_eq!(state.metrics.linode_errors.with_label_values(&["connect"]).get(), 3);
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn update_handler_checks_the_signature() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config = testutil::config(&mock, &[("WEBHOOK_HMAC_SECRET", std::str::from_utf8(SECRET).unwrap())]).await;
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+        let body = serde_json::to_vec(&selftest::hook(selftest::SECRET_NAME)).unwrap();
+        let signed = |timestamp: i64| {
+            let timestamp = timestamp.to_string();
+            vec![("x-signature-256", sign(&timestamp, &body)), ("x-timestamp", timestamp)]
+        };
+
+        let tampered = String::from_utf8(body.clone()).unwrap().replace(selftest::SECRET_NAME, "other-tls");
+        let (status, response) = post_update(state.clone(), tampered.into_bytes(), &signed(cert::unix_now())).await;
+        assert_eq!((status, response["code"].as_str()), (StatusCode::UNAUTHORIZED, Some("INVALID_SIGNATURE")));
+
+        let (status, response) = post_update(state.clone(), body.clone(), &[]).await;
+        assert_eq!((status, response["code"].as_str()), (StatusCode::UNAUTHORIZED, Some("INVALID_SIGNATURE")));
+
+        let (status, response) = post_update(state.clone(), body.clone(), &signed(cert::unix_now() - 3600)).await;
+        assert_eq!((status, response["code"].as_str()), (StatusCode::UNAUTHORIZED, Some("SIGNATURE_EXPIRED")));
+        assert!(mock.configs.lock().unwrap()[selftest::CONFIG_ID].get("ssl_cert").is_none());
+
+        let (status, response) = post_update(state, body.clone(), &signed(cert::unix_now())).await;
+        assert_eq!((status, response["status"].as_str()), (StatusCode::OK, Some("success")));
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn a_config_failing_among_several_is_a_partial_success() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config_ids = format!("{},{}", selftest::CONFIG_ID, selftest::FORBIDDEN_CONFIG_ID);
+        let config = testutil::config(&mock, &[("HTTPS_CONFIG_ID", &config_ids)]).await;
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+
+        let (status, response) = process_update(&state, &selftest::hook(selftest::SECRET_NAME), UpdateSource::Webhook).await;
+        assert_eq!((status, response.status.as_str()), (StatusCode::MULTI_STATUS, "partial"));
+        let message = response.message.unwrap_or_default();
+        let prefix = format!("updated: [{}]; failed: [{} (", selftest::CONFIG_ID, selftest::FORBIDDEN_CONFIG_ID);
+        assert!(message.starts_with(&prefix), "{}", message);
+        let served = mock.configs.lock().unwrap()[selftest::CONFIG_ID]["ssl_fingerprint"].clone();
+        assert_eq!(served, serde_json::json!(cert::leaf_fingerprint(&cert_pem).unwrap()));
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn a_config_already_serving_the_certificate_is_skipped() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+        let hook = selftest::hook(selftest::SECRET_NAME);
+        let (status, response) = process_update(&state, &hook, UpdateSource::Webhook).await;
+        assert_eq!((status, response.status.as_str()), (StatusCode::OK, "success"));
+
+        // Forgotten locally, as after a restart without STATE_CONFIGMAP, so only the config's fingerprint can tell
+        state.last_applied.remove_if(selftest::CONFIG_ID, &cert::leaf_fingerprint(&cert_pem).unwrap()).await;
+        // A PUT would store the new value, a skipped update leaves it alone
+        if let Some(config) = mock.configs.lock().unwrap().get_mut(selftest::CONFIG_ID) {
+            config["ssl_key"] = serde_json::json!("<REDACTED>");
+        }
+        let (status, response) = process_update(&state, &hook, UpdateSource::Webhook).await;
+        assert_eq!((status, response.status.as_str()), (StatusCode::OK, "unchanged"));
+        assert_eq!(state.metrics.config_updates.with_label_values(&["skipped"]).get(), 1);
+        assert_eq!(mock.configs.lock().unwrap()[selftest::CONFIG_ID]["ssl_key"], "<REDACTED>");
+        mock.stop().await;
+    }
+
+    async fn describe(state: Arc<AppState>) -> serde_json::Value {
+        let body = actix_web::body::to_bytes(describe_update_endpoint(web::Data::new(state)).await.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn options_reports_the_signature_requirement() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let body = describe(testutil::state(testutil::config(&mock, &[]).await, Default::default())).await;
+        assert_eq!((&body["auth_required"], &body["signature"]), (&serde_json::json!(false), &serde_json::Value::Null));
+
+        let config = testutil::config(&mock, &[("WEBHOOK_HMAC_SECRET", "secret")]).await;
+        let body = describe(testutil::state(config, Default::default())).await;
+        assert_eq!(body["auth_required"], true);
+        assert_eq!(body["signature"]["header"], "x-signature-256");
+        assert_eq!(body["signature"]["timestamp_header"], "x-timestamp");
+        mock.stop().await;
+    }
+
+    fn hook_request(namespace: String, secret_name: String, config_id: Option<String>) -> HookRequest {
+        HookRequest { namespace, secret_name, config_id, update_cert: true, update_key: true }
+    }
+
+    proptest! {
+        #[test]
+        fn validate_hook_request_never_panics(
+            namespace in any::<String>(),
+            secret_name in any::<String>(),
+            config_id in proptest::option::of(any::<String>()),
+        ) {
+            let request = hook_request(namespace, secret_name, config_id);
+            let result = futures::executor::block_on(validate_hook_request(&request));
+            prop_assert!(result.is_ok() || result.is_err_and(|e| !e.is_empty()));
+        }
+
+        #[test]
+        fn accepted_names_are_kube_names(
+            namespace in "[a-z0-9.-]{0,70}",
+            secret_name in "[a-z0-9._-]{0,260}",
+            config_id in proptest::option::of("[0-9a-z]{0,6}"),
+        ) {
+            let request = hook_request(namespace, secret_name, config_id);
+            if futures::executor::block_on(validate_hook_request(&request)).is_ok() {
+                let kube_chars = |name: &str, dots: bool| {
+                    name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || (dots && c == '.'))
+                };
+                prop_assert!(!request.namespace.is_empty() && request.namespace.len() <= MAX_NAMESPACE_LEN);
+                prop_assert!(kube_chars(&request.namespace, false));
+                prop_assert!(!request.secret_name.is_empty() && request.secret_name.len() <= MAX_SECRET_NAME_LEN);
+                prop_assert!(kube_chars(&request.secret_name, true));
+                prop_assert!(request.config_id.iter().all(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit())));
+            }
+        }
+
+        #[test]
+        fn decode_secret_field_returns_a_typed_error(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            match decode_secret_field(&data, "tls.crt", "ns", "name") {
+                Ok(decoded) => prop_assert_eq!(general_purpose::STANDARD.decode(&data).ok(), Some(decoded.into_bytes())),
+                Err(e) => prop_assert!(e.action == "base64-decode" || e.action == "read as UTF-8"),
+            }
+        }
+
+        #[test]
+        fn decode_secret_field_round_trips(text in any::<String>()) {
+            let encoded = general_purpose::STANDARD.encode(&text);
+            prop_assert_eq!(decode_secret_field(encoded.as_bytes(), "tls.crt", "ns", "name").ok(), Some(text));
+        }
+    }
+}
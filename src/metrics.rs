@@ -0,0 +1,464 @@
+use crate::cert;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+/// Prefix of every metric name unless METRICS_NAMESPACE says otherwise.
+pub const DEFAULT_NAMESPACE: &str = "cert_webhook";
+
+/// Custom collectors registered alongside the actix-web-prom request metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    pub is_leader: IntGauge,
+    // Both durations cover the whole retried operation, backoff included
+    pub kube_fetch_duration: Histogram,
+    pub linode_update_duration: Histogram,
+    pub live_verify_failures: IntCounterVec,
+    pub post_verify_failures: IntCounter,
+    pub linode_token_reloads: IntCounter,
+    pub linode_errors: IntCounterVec,
+    pub drift_detected: IntCounter,
+    pub drift_corrected: IntCounter,
+    pub inflight_updates: IntGauge,
+    pub update_queue_depth: IntGauge,
+    pub retries: IntCounterVec,
+    pub validation_failures: IntCounterVec,
+    pub last_request: SinceLastRequest,
+    pub paused: UpdatePause,
+    // Set by window::is_open, stays 1 without APPLY_WINDOW
+    pub apply_window_open: IntGauge,
+    pub updates: IntCounterVec,
+    // Per config, whether the certificate was pushed or the config already had it
+    pub config_updates: IntCounterVec,
+    // From the X-RateLimit-* headers of the latest Linode response, -1 until one carried them
+    pub linode_ratelimit_remaining: IntGauge,
+    pub linode_ratelimit_limit: IntGauge,
+}
+
+impl Metrics {
+    pub fn new(registry: &Registry, namespace: &str) -> Result<Self, prometheus::Error> {
+        let is_leader = IntGauge::with_opts(
+            Opts::new("is_leader", "Whether this replica holds the leader lease (1) or not (0)")
+                .namespace(namespace),
+        )?;
+        registry.register(Box::new(is_leader.clone()))?;
+
+        let kube_fetch_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "kube_fetch_duration_seconds",
+                "Time spent fetching the certificate secret from Kubernetes, including retries",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(kube_fetch_duration.clone()))?;
+
+        let linode_update_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "linode_update_duration_seconds",
+                "Time spent updating a NodeBalancer config through the Linode API, including retries",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(linode_update_duration.clone()))?;
+
+        let live_verify_failures = IntCounterVec::new(
+            Opts::new(
+                "live_verify_failures_total",
+                "Live TLS verifications of the NodeBalancer that failed, by reason (mismatch or handshake)",
+            )
+            .namespace(namespace),
+            &["reason"],
+        )?;
+        registry.register(Box::new(live_verify_failures.clone()))?;
+
+        let post_verify_failures = IntCounter::with_opts(
+            Opts::new(
+                "post_verify_failures_total",
+                "Config updates Linode accepted but whose config never reported the pushed certificate",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(post_verify_failures.clone()))?;
+
+        let linode_errors = IntCounterVec::new(
+            Opts::new(
+                "linode_errors_total",
+                "Failed Linode API calls, by normalized reason from the error envelope (other for unknown ones) or dns, connect, timeout, decode and transport when there was no usable response",
+            )
+            .namespace(namespace),
+            &["reason"],
+        )?;
+        registry.register(Box::new(linode_errors.clone()))?;
+
+        let linode_token_reloads = IntCounter::with_opts(
+            Opts::new(
+                "linode_token_reloads_total",
+                "Times a 401 from Linode led to a new token being read from LINODE_TOKEN_FILE",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(linode_token_reloads.clone()))?;
+
+        let drift_detected = IntCounter::with_opts(
+            Opts::new(
+                "drift_detected_total",
+                "Scheduled revalidations that found the live certificate differing from the secret",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(drift_detected.clone()))?;
+
+        let drift_corrected = IntCounter::with_opts(
+            Opts::new(
+                "drift_corrected_total",
+                "Drifted configs that were successfully re-pushed by a scheduled revalidation",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(drift_corrected.clone()))?;
+
+        let inflight_updates = IntGauge::with_opts(
+            Opts::new("inflight_updates", "Certificate update requests currently being processed")
+                .namespace(namespace),
+        )?;
+        registry.register(Box::new(inflight_updates.clone()))?;
+
+        let update_queue_depth = IntGauge::with_opts(
+            Opts::new(
+                "update_queue_depth",
+                "Certificate updates waiting for a MAX_CONCURRENT_UPDATES permit",
+            )
+            .namespace(namespace),
+        )?;
+        registry.register(Box::new(update_queue_depth.clone()))?;
+
+        let retries = IntCounterVec::new(
+            Opts::new(
+                "operation_retries_total",
+                "Retries of a failed kube or Linode call, by operation",
+            )
+            .namespace(namespace),
+            &["operation"],
+        )?;
+        registry.register(Box::new(retries.clone()))?;
+
+        let validation_failures = IntCounterVec::new(
+            Opts::new(
+                "validation_failures_total",
+                "Update requests rejected because of the request itself, by reason",
+            )
+            .namespace(namespace),
+            &["reason"],
+        )?;
+        registry.register(Box::new(validation_failures.clone()))?;
+
+        let last_request = SinceLastRequest {
+            last: Arc::new(AtomicI64::new(cert::unix_now())),
+            gauge: IntGauge::with_opts(
+                Opts::new(
+                    "seconds_since_last_request",
+                    "Seconds since the last update request was received, or since startup when none was",
+                )
+                .namespace(namespace),
+            )?,
+        };
+        registry.register(Box::new(last_request.clone()))?;
+
+        let paused = UpdatePause {
+            until: Arc::new(AtomicI64::new(0)),
+            gauge: IntGauge::with_opts(
+                Opts::new("updates_paused", "Whether updates are paused through /admin/pause (1) or not (0)")
+                    .namespace(namespace),
+            )?,
+        };
+        registry.register(Box::new(paused.clone()))?;
+
+        let apply_window_open = IntGauge::with_opts(
+            Opts::new("apply_window_open", "Whether APPLY_WINDOW currently allows pushes (1) or not (0), 1 without a window")
+                .namespace(namespace),
+        )?;
+        apply_window_open.set(1);
+        registry.register(Box::new(apply_window_open.clone()))?;
+
+        let updates = IntCounterVec::new(
+            Opts::new(
+                "updates_total",
+                "Processed certificate updates by entry point (webhook, watch, schedule, queue or apply_window) and outcome",
+            )
+            .namespace(namespace),
+            &["source", "outcome"],
+        )?;
+        registry.register(Box::new(updates.clone()))?;
+
+        let config_updates = IntCounterVec::new(
+            Opts::new(
+                "config_updates_total",
+                "Config updates by result, applied when the certificate was pushed and skipped when the config already had it",
+            )
+            .namespace(namespace),
+            &["result"],
+        )?;
+        registry.register(Box::new(config_updates.clone()))?;
+
+        let linode_ratelimit_remaining = IntGauge::with_opts(
+            Opts::new(
+                "linode_ratelimit_remaining",
+                "Requests left in the current Linode rate limit window (X-RateLimit-Remaining), -1 when unknown",
+            )
+            .namespace(namespace),
+        )?;
+        linode_ratelimit_remaining.set(-1);
+        registry.register(Box::new(linode_ratelimit_remaining.clone()))?;
+
+        let linode_ratelimit_limit = IntGauge::with_opts(
+            Opts::new(
+                "linode_ratelimit_limit",
+                "Requests allowed per Linode rate limit window (X-RateLimit-Limit), -1 when unknown",
+            )
+            .namespace(namespace),
+        )?;
+        linode_ratelimit_limit.set(-1);
+        registry.register(Box::new(linode_ratelimit_limit.clone()))?;
+
+        Ok(Metrics {
+            is_leader,
+            kube_fetch_duration,
+            linode_update_duration,
+            live_verify_failures,
+            post_verify_failures,
+            linode_token_reloads,
+            linode_errors,
+            drift_detected,
+            drift_corrected,
+            inflight_updates,
+            update_queue_depth,
+            retries,
+            validation_failures,
+            last_request,
+            paused,
+            apply_window_open,
+            updates,
+            config_updates,
+            linode_ratelimit_remaining,
+            linode_ratelimit_limit,
+        })
+    }
+}
+
+/// Increments a gauge for as long as the guard is alive, so early returns
+/// can't leave it off by one.
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Age of the last update request, computed when scraped. Unlike the update
+/// metrics it keeps moving when cert-manager stops calling altogether.
+#[derive(Clone)]
+pub struct SinceLastRequest {
+    last: Arc<AtomicI64>,
+    gauge: IntGauge,
+}
+
+impl SinceLastRequest {
+    pub fn touch(&self) {
+        self.last.store(cert::unix_now(), Ordering::Relaxed);
+    }
+}
+
+impl Collector for SinceLastRequest {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.gauge.set(cert::unix_now().saturating_sub(self.last.load(Ordering::Relaxed)));
+        self.gauge.collect()
+    }
+}
+
+/// Maintenance pause set through /admin/pause. It lifts itself once its TTL
+/// is up, so the gauge is computed on every scrape like `SinceLastRequest`.
+#[derive(Clone)]
+pub struct UpdatePause {
+    // Unix time the pause ends at, 0 when not paused
+    until: Arc<AtomicI64>,
+    gauge: IntGauge,
+}
+
+impl UpdatePause {
+    pub fn pause(&self, until: i64) {
+        self.until.store(until, Ordering::SeqCst);
+    }
+
+    /// Lifts the pause, returning whether one was in effect.
+    pub fn resume(&self) -> bool {
+        self.until.swap(0, Ordering::SeqCst) > cert::unix_now()
+    }
+
+    /// Unix time the current pause ends at, `None` when not paused.
+    pub fn until(&self) -> Option<i64> {
+        let until = self.until.load(Ordering::SeqCst);
+        (until > cert::unix_now()).then_some(until)
+    }
+}
+
+impl Collector for UpdatePause {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.gauge.set(i64::from(self.until().is_some()));
+        self.gauge.collect()
+    }
+}
+
+/// Where the time of one update request went, returned with INCLUDE_TIMING.
+/// Linode time adds up across configs, so it can exceed the wall clock when
+/// they are updated in parallel.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Timing {
+    pub kube_ms: u64,
+    pub linode_ms: u64,
+    pub retries: u64,
+}
+
+tokio::task_local! {
+    static TIMING: RefCell<Timing>;
+}
+
+/// Runs `future` while collecting its `Timing`. The recorders below are no-ops
+/// outside of it.
+pub async fn timed<F: Future>(future: F) -> (F::Output, Timing) {
+    TIMING
+        .scope(RefCell::new(Timing::default()), async {
+            let output = future.await;
+            (output, TIMING.with(RefCell::take))
+        })
+        .await
+}
+
+pub fn record_kube_time(secs: f64) {
+    let _ = TIMING.try_with(|timing| timing.borrow_mut().kube_ms += (secs * 1000.0) as u64);
+}
+
+pub fn record_linode_time(secs: f64) {
+    let _ = TIMING.try_with(|timing| timing.borrow_mut().linode_ms += (secs * 1000.0) as u64);
+}
+
+pub fn record_retry() {
+    let _ = TIMING.try_with(|timing| timing.borrow_mut().retries += 1);
+}
+
+/// Content type of `encode_openmetrics` output.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encodes `families` in the OpenMetrics text format, which the prometheus
+/// crate has no encoder for. Counter families drop their `_total` suffix as
+/// the format requires, and `_seconds`/`_bytes` names get a `# UNIT` line.
+/// Exemplars aren't recorded anywhere, so none are emitted.
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    use prometheus::proto::MetricType;
+    use std::fmt::Write as _;
+
+    let mut out = String::new();
+    for family in families {
+        let name = family.get_name();
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family_name, kind);
+        if let Some(unit) = ["seconds", "bytes"].into_iter().find(|unit| family_name.ends_with(&format!("_{}", unit))) {
+            let _ = writeln!(out, "# UNIT {} {}", family_name, unit);
+        }
+        if !family.get_help().is_empty() {
+            let help = family.get_help().replace('\\', "\\\\").replace('\n', "\\n");
+            let _ = writeln!(out, "# HELP {} {}", family_name, help);
+        }
+
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, String)> =
+                metric.get_label().iter().map(|pair| (pair.get_name(), pair.get_value().to_string())).collect();
+            let mut sample = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut all = labels.clone();
+                all.extend(extra);
+                let _ = writeln!(out, "{}{}{} {}", family_name, suffix, label_set(&all), openmetrics_value(value));
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => sample("_total", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => sample("", None, metric.get_gauge().get_value()),
+                // Nothing registers untyped metrics, and their accessors are deprecated
+                MetricType::UNTYPED => {}
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let le = openmetrics_value(bucket.get_upper_bound());
+                        sample("_bucket", Some(("le", le)), bucket.get_cumulative_count() as f64);
+                    }
+                    // The prometheus crate leaves the +Inf bucket implicit, OpenMetrics requires it
+                    let count = histogram.get_sample_count() as f64;
+                    sample("_bucket", Some(("le", "+Inf".to_string())), count);
+                    sample("_count", None, count);
+                    sample("_sum", None, histogram.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = openmetrics_value(quantile.get_quantile());
+                        sample("", Some(("quantile", q)), quantile.get_value());
+                    }
+                    sample("_count", None, summary.get_sample_count() as f64);
+                    sample("_sum", None, summary.get_sample_sum());
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn label_set(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn openmetrics_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,443 @@
+use crate::aglb::{self, CertificateRef};
+use crate::linode::{LinodeClient, LinodeConfigDetail};
+use crate::metrics;
+use crate::{cert, get_linode_config, retry_target, update_linode_config, CertMode, ErrorCode};
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use log::{info, warn};
+use prometheus::{Histogram, IntCounterVec};
+use x509_parser::pem::Pem;
+
+/// A certificate chain and private key, parsed once before being handed to a
+/// provider. The PEM text is kept for APIs that take it as is.
+pub struct CertBundle {
+    pub cert_pem: String,
+    pub key_pem: String,
+    // DER of every certificate in the chain, leaf first
+    pub chain: Vec<Vec<u8>>,
+    pub key_der: Vec<u8>,
+    // PEM label of the key, e.g. "PRIVATE KEY" or "RSA PRIVATE KEY"
+    pub key_label: String,
+}
+
+impl CertBundle {
+    pub fn parse(cert_pem: &str, key_pem: &str) -> Result<Self, String> {
+        let mut chain = Vec::new();
+        for pem in Pem::iter_from_buffer(cert_pem.as_bytes()) {
+            let pem = pem.map_err(|e| format!("invalid PEM in certificate: {}", e))?;
+            if pem.label == "CERTIFICATE" {
+                chain.push(pem.contents);
+            }
+        }
+        if chain.is_empty() {
+            return Err("no certificate found in tls.crt".to_string());
+        }
+
+        let key = Pem::iter_from_buffer(key_pem.as_bytes())
+            .filter_map(Result::ok)
+            .find(|pem| pem.label.ends_with("PRIVATE KEY"))
+            .ok_or("no private key found in tls.key")?;
+
+        Ok(CertBundle {
+            cert_pem: cert_pem.to_string(),
+            key_pem: key_pem.to_string(),
+            chain,
+            key_der: key.contents,
+            key_label: key.label,
+        })
+    }
+}
+
+/// Which halves of the bundle to install. A partial update leaves the other
+/// one as currently configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertParts {
+    pub cert: bool,
+    pub key: bool,
+}
+
+impl CertParts {
+    pub const BOTH: CertParts = CertParts { cert: true, key: true };
+
+    pub fn is_partial(&self) -> bool {
+        !(self.cert && self.key)
+    }
+}
+
+/// What a provider reports after installing a certificate.
+#[derive(Debug, Default)]
+pub struct Applied {
+    // Port now serving the certificate, used for live verification
+    pub port: Option<u16>,
+    // The target already served this certificate, so nothing was pushed
+    pub unchanged: bool,
+}
+
+/// A failed update, already mapped to the response the handler sends.
+#[derive(Debug)]
+pub struct ProviderError {
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Somewhere certificates get installed. Implementations do their own
+/// retrying, the handler only maps the final result to HTTP.
+#[async_trait(?Send)]
+pub trait CertProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Installs `parts` of the bundle on `target`, a provider-specific id such
+    /// as a NodeBalancer config id.
+    async fn update_cert(&self, target: &str, bundle: &CertBundle, parts: CertParts) -> Result<Applied, ProviderError>;
+}
+
+/// Which provider certificates are pushed to, from `CERT_PROVIDER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderKind {
+    Linode,
+    Noop,
+}
+
+impl std::str::FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linode" => Ok(ProviderKind::Linode),
+            "noop" => Ok(ProviderKind::Noop),
+            other => Err(format!("unknown provider '{}', expected linode or noop", other)),
+        }
+    }
+}
+
+/// Updates NodeBalancer configs through the Linode API.
+pub struct LinodeProvider {
+    pub client: LinodeClient,
+    pub cert_mode: CertMode,
+    pub update_duration: Histogram,
+    pub retries: IntCounterVec,
+}
+
+#[async_trait(?Send)]
+impl CertProvider for LinodeProvider {
+    fn name(&self) -> &'static str {
+        "linode"
+    }
+
+    async fn update_cert(&self, target: &str, bundle: &CertBundle, parts: CertParts) -> Result<Applied, ProviderError> {
+        // A partial update needs the other half already there, and a config
+        // serving this certificate doesn't need the PUT at all
+        let live = retry_target("linode_get_config", &self.retries, &self.client, target, || async {
+            get_linode_config(&self.client, target).await
+        })
+        .await;
+        if parts.is_partial() {
+            check_partial_update(target, &live)?;
+        }
+        if parts.cert {
+            match &live {
+                Ok(config) if config.served_fingerprint().is_some_and(|live| cert::fingerprint_matches(&bundle.cert_pem, live)) => {
+                    info!("NodeBalancer config {} already serves this certificate, skipping the update", target);
+                    return Ok(Applied { port: Some(config.port), unchanged: true });
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to read NodeBalancer config {} before updating it, pushing anyway: {}", target, e),
+            }
+        }
+
+        let cert = parts.cert.then_some(bundle.cert_pem.as_str());
+        let key = parts.key.then_some(bundle.key_pem.as_str());
+        let timer = self.update_duration.start_timer();
+        let result = retry_target("linode_update_config", &self.retries, &self.client, target, || async {
+            update_linode_config(&self.client, target, self.cert_mode, cert, key).await
+        })
+        .await;
+        metrics::record_linode_time(timer.stop_and_record());
+
+        match result {
+            Ok(config) => Ok(Applied { port: config.map(|config| config.port), unchanged: false }),
+            Err(e) => Err(ProviderError {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                code: ErrorCode::LinodeError,
+                message: format!("Failed to update NodeBalancer config {}: {}", target, e),
+            }),
+        }
+    }
+}
+
+/// The half being left out of a partial update has to already be on the
+/// config, otherwise Linode would end up with a cert and no key (or the other
+/// way around).
+fn check_partial_update(
+    target: &str,
+    live: &Result<LinodeConfigDetail, Box<dyn std::error::Error>>,
+) -> Result<(), ProviderError> {
+    let live = live.as_ref().map_err(|e| ProviderError {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        code: ErrorCode::LinodeError,
+        message: format!("Failed to read NodeBalancer config {} before a partial update: {}", target, e),
+    })?;
+
+    if live.protocol.as_deref() != Some("https") || live.served_fingerprint().is_none() {
+        return Err(ProviderError {
+            status: StatusCode::CONFLICT,
+            code: ErrorCode::PartialUpdateRejected,
+            message: format!(
+                "NodeBalancer config {} has no certificate yet, a partial update would leave it incomplete",
+                target
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Labels of uploaded certificates are "<prefix><config id>-<unix time>"
+const LABEL_PREFIX: &str = "cert-webhook-";
+
+/// Uploads certificates to an Application Load Balancer and points a
+/// configuration at them. The target is the AGLB configuration id.
+pub struct AglbProvider {
+    pub client: LinodeClient,
+    pub update_duration: Histogram,
+    pub retries: IntCounterVec,
+}
+
+#[async_trait(?Send)]
+impl CertProvider for AglbProvider {
+    fn name(&self) -> &'static str {
+        "aglb"
+    }
+
+    async fn update_cert(&self, target: &str, bundle: &CertBundle, parts: CertParts) -> Result<Applied, ProviderError> {
+        if parts.is_partial() {
+            return Err(ProviderError {
+                status: StatusCode::CONFLICT,
+                code: ErrorCode::PartialUpdateRejected,
+                message: "AGLB certificates are uploaded as a cert and key pair, partial updates are not supported"
+                    .to_string(),
+            });
+        }
+
+        let timer = self.update_duration.start_timer();
+        let result = self.replace_certificate(target, bundle).await;
+        metrics::record_linode_time(timer.stop_and_record());
+        result
+    }
+}
+
+impl AglbProvider {
+    async fn replace_certificate(&self, target: &str, bundle: &CertBundle) -> Result<Applied, ProviderError> {
+        let linode_error = |action: &str, e: Box<dyn std::error::Error>| ProviderError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            code: ErrorCode::LinodeError,
+            message: format!("Failed to {} for AGLB configuration {}: {}", action, target, e),
+        };
+
+        let current = retry_target("aglb_get_configuration", &self.retries, &self.client, target, || async {
+            aglb::get_configuration(&self.client, target).await
+        })
+        .await
+        .map_err(|e| linode_error("read the configuration", e))?;
+
+        let sans = cert::leaf_sans(&bundle.cert_pem).unwrap_or_default();
+        if current.certificates.is_empty() && sans.is_empty() {
+            return Err(ProviderError {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                code: ErrorCode::CertInvalid,
+                message: format!(
+                    "AGLB configuration {} serves no hostnames yet and the certificate has no SANs to use",
+                    target
+                ),
+            });
+        }
+        if !current.certificates.is_empty() && !current.certificates.iter().any(|c| cert::sans_cover(&sans, &c.hostname)) {
+            return Err(ProviderError {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                code: ErrorCode::CertInvalid,
+                message: format!("The certificate covers none of the hostnames AGLB configuration {} serves", target),
+            });
+        }
+
+        let label_prefix = format!("{}{}-", LABEL_PREFIX, target);
+        let label = format!("{}{}", label_prefix, cert::unix_now());
+        let attempted = std::cell::Cell::new(false);
+        let certificate_id = retry_target("aglb_upload_cert", &self.retries, &self.client, target, || async {
+            // The upload isn't idempotent, a failed attempt may still have created the certificate
+            if attempted.replace(true) {
+                if let Some(id) = aglb::find_certificate(&self.client, target, &label).await? {
+                    info!("Certificate {} ({}) was uploaded by an earlier attempt", id, label);
+                    return Ok(id);
+                }
+            }
+            aglb::upload_certificate(&self.client, target, &label, &bundle.cert_pem, &bundle.key_pem).await
+        })
+        .await
+        .map_err(|e| linode_error("upload the certificate", e))?;
+
+        // A first push serves the SANs, a renewal only repoints the hostnames the leaf covers
+        let references: Vec<CertificateRef> = if current.certificates.is_empty() {
+            sans.into_iter().map(|hostname| CertificateRef { id: certificate_id, hostname }).collect()
+        } else {
+            repoint(&current.certificates, &sans, certificate_id)
+        };
+        let updated = retry_target("aglb_update_configuration", &self.retries, &self.client, target, || async {
+            aglb::set_certificates(&self.client, target, &references).await
+        })
+        .await
+        .map_err(|e| linode_error(&format!("attach certificate {}", certificate_id), e))?;
+
+        self.delete_replaced(target, &current.certificates, &references, &label_prefix).await;
+        Ok(Applied { port: Some(updated.port), unchanged: false })
+    }
+
+    /// Best-effort cleanup of certificates this webhook uploaded earlier for
+    /// the same configuration and that no hostname serves any more. Ones
+    /// uploaded by anything else are left alone, since they may be shared with
+    /// other configurations.
+    async fn delete_replaced(&self, target: &str, previous: &[CertificateRef], current: &[CertificateRef], label_prefix: &str) {
+        let mut ids: Vec<u64> = previous
+            .iter()
+            .map(|c| c.id)
+            .filter(|id| !current.iter().any(|c| c.id == *id))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        for id in ids {
+            match aglb::certificate_label(&self.client, target, id).await {
+                Ok(label) if label.starts_with(label_prefix) => {
+                    if let Err(e) = aglb::delete_certificate(&self.client, target, id).await {
+                        warn!("Failed to delete replaced certificate {}: {}", id, e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to look up replaced certificate {}: {}", id, e),
+            }
+        }
+    }
+}
+
+/// The references of a configuration once `certificate_id` serves every
+/// hostname the leaf's SANs cover. The other hostnames keep their certificate.
+fn repoint(current: &[CertificateRef], sans: &[String], certificate_id: u64) -> Vec<CertificateRef> {
+    current
+        .iter()
+        .map(|c| {
+            if cert::sans_cover(sans, &c.hostname) {
+                CertificateRef { id: certificate_id, hostname: c.hostname.clone() }
+            } else {
+                c.clone()
+            }
+        })
+        .collect()
+}
+
+/// Accepts every update without pushing anything, for tests and dry runs.
+pub struct NoopProvider;
+
+#[async_trait(?Send)]
+impl CertProvider for NoopProvider {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    async fn update_cert(&self, target: &str, bundle: &CertBundle, _parts: CertParts) -> Result<Applied, ProviderError> {
+        info!(
+            "Noop provider: not pushing {} certificate(s) and a {} ({} bytes) to {}",
+            bundle.chain.len(),
+            bundle.key_label,
+            bundle.key_der.len(),
+            target
+        );
+        Ok(Applied::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, testutil};
+    use proptest::prelude::*;
+
+    /// A provider pointed at `mock`, whose CONFIG_ID has no certificate yet
+    /// and reports it with an empty fingerprint.
+    async fn empty_config(mock: &selftest::MockLinode) -> (std::sync::Arc<crate::AppState>, CertBundle) {
+        if let Some(config) = mock.configs.lock().unwrap().get_mut(selftest::CONFIG_ID) {
+            config["ssl_cert"] = serde_json::Value::Null;
+            config["ssl_key"] = serde_json::Value::Null;
+            config["ssl_fingerprint"] = serde_json::json!("");
+        }
+        let state = testutil::state(testutil::config(mock, &[]).await, Default::default());
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        (state, CertBundle::parse(&cert_pem, &key_pem).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn empty_current_config_is_pushed() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (state, bundle) = empty_config(&mock).await;
+
+        let applied = state.provider.update_cert(selftest::CONFIG_ID, &bundle, CertParts::BOTH).await.unwrap();
+        assert!(!applied.unchanged);
+        let served = mock.configs.lock().unwrap()[selftest::CONFIG_ID]["ssl_fingerprint"].clone();
+        assert_eq!(served, serde_json::json!(cert::leaf_fingerprint(&bundle.cert_pem).unwrap()));
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn health_check_settings_survive_an_update() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, Default::default());
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+
+        let bundle = CertBundle::parse(&cert_pem, &key_pem).unwrap();
+        state.provider.update_cert(selftest::CONFIG_ID, &bundle, CertParts::BOTH).await.unwrap();
+        // Losing active health checks on a renewal would take the backends out of rotation
+        let health_check = {
+            let configs = mock.configs.lock().unwrap();
+            let config = &configs[selftest::CONFIG_ID];
+            ["check", "check_interval", "check_path", "check_body"].map(|field| config[field].clone())
+        };
+        assert_eq!(
+            health_check,
+            [serde_json::json!("http_body"), serde_json::json!(15), serde_json::json!("/healthz"), serde_json::json!("ok")]
+        );
+        mock.stop().await;
+    }
+
+    #[test]
+    fn renewal_keeps_hostnames_the_leaf_does_not_cover() {
+        let current = [
+            CertificateRef { id: 1, hostname: "www.example.com".to_string() },
+            CertificateRef { id: 2, hostname: "other.example.org".to_string() },
+        ];
+        let refs = repoint(&current, &["*.example.com".to_string()], 3);
+        let refs: Vec<(u64, &str)> = refs.iter().map(|c| (c.id, c.hostname.as_str())).collect();
+        assert_eq!(refs, [(3, "www.example.com"), (2, "other.example.org")]);
+    }
+
+    #[actix_web::test]
+    async fn partial_update_of_an_empty_config_is_rejected() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (state, bundle) = empty_config(&mock).await;
+
+        let parts = CertParts { cert: true, key: false };
+        let error = state.provider.update_cert(selftest::CONFIG_ID, &bundle, parts).await.unwrap_err();
+        assert_eq!((error.status, error.code), (StatusCode::CONFLICT, ErrorCode::PartialUpdateRejected));
+        assert!(mock.configs.lock().unwrap()[selftest::CONFIG_ID]["ssl_cert"].is_null());
+        mock.stop().await;
+    }
+
+    proptest! {
+        #[test]
+        fn bundle_parse_rejects_text_without_pem(cert in "[^-]*", key in "[^-]*") {
+            prop_assert!(CertBundle::parse(&cert, &key).is_err());
+        }
+
+        #[test]
+        fn bundle_parse_never_panics(cert in any::<String>(), key in any::<String>()) {
+            let _ = CertBundle::parse(&cert, &key);
+        }
+    }
+}
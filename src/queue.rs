@@ -0,0 +1,387 @@
+use crate::{process_update, window, AppState, CertManagerHook, UpdateSource};
+use async_trait::async_trait;
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::{debug, error, info, warn};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long one XREADGROUP waits for a message before asking again
+const BLOCK_MS: u64 = 5_000;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// How often a consumer paused by APPLY_WINDOW checks whether it opened
+const WINDOW_POLL: Duration = Duration::from_secs(30);
+// Stream entry field holding the request body
+const PAYLOAD_FIELD: &[u8] = b"payload";
+
+/// INPUT_MODE: where update requests come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputMode {
+    // POST /update-nodebalancer-cert and /update-batch
+    Http,
+    // A queue only, the update routes aren't served
+    Queue,
+    Both,
+}
+
+impl InputMode {
+    pub fn serves_http(self) -> bool {
+        self != InputMode::Queue
+    }
+
+    pub fn consumes_queue(self) -> bool {
+        self != InputMode::Http
+    }
+}
+
+impl FromStr for InputMode {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.to_lowercase().as_str() {
+            "http" => Ok(InputMode::Http),
+            "queue" => Ok(InputMode::Queue),
+            "both" => Ok(InputMode::Both),
+            other => Err(format!("unknown input mode '{}', expected http, queue or both", other)),
+        }
+    }
+}
+
+/// One message taken off a queue.
+pub struct Delivery {
+    // Queue-specific id, handed back to `ack`
+    pub id: String,
+    // Same JSON body as POST /update-nodebalancer-cert
+    pub payload: Vec<u8>,
+}
+
+/// A source of update requests. `next` waits until a message is available,
+/// and a message that is never acked may be delivered again, possibly to
+/// another replica.
+#[async_trait(?Send)]
+pub trait QueueConsumer {
+    /// Where messages come from, for logs.
+    fn describe(&self) -> String;
+
+    async fn next(&mut self) -> Result<Delivery, Box<dyn std::error::Error>>;
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// QUEUE_URL and friends, for a Redis stream read through a consumer group.
+#[derive(Debug, Clone)]
+pub struct RedisStreamConfig {
+    // host:port
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+}
+
+impl RedisStreamConfig {
+    /// Parses `redis://[[user]:password@]host[:port][/db]`.
+    pub fn parse_url(raw: &str, stream: String, group: String, consumer: String) -> Result<Self, String> {
+        let url = reqwest::Url::parse(raw.trim()).map_err(|e| format!("is not a valid URL ({})", e))?;
+        if url.scheme() != "redis" {
+            return Err(format!("has scheme '{}', only redis:// is supported", url.scheme()));
+        }
+        let host = url.host_str().filter(|host| !host.is_empty()).ok_or("has no host")?;
+        let db = match url.path().trim_matches('/') {
+            "" => None,
+            db => Some(db.parse::<u32>().map_err(|_| format!("has '{}' as database, expected a number", db))?),
+        };
+        Ok(RedisStreamConfig {
+            addr: format!("{}:{}", host, url.port().unwrap_or(6379)),
+            username: Some(url.username().to_string()).filter(|user| !user.is_empty()),
+            password: url.password().map(str::to_string),
+            db,
+            stream,
+            group,
+            consumer,
+        })
+    }
+}
+
+/// Consumes a Redis stream with XREADGROUP, acking each entry with XACK once
+/// it was handled. After every (re)connect the entries this consumer read
+/// but never acked are handled first, so a crash mid-update doesn't lose one.
+pub struct RedisStreamConsumer {
+    config: RedisStreamConfig,
+    conn: Option<BufReader<TcpStream>>,
+    // Whether this connection already caught up on unacked entries
+    caught_up: bool,
+}
+
+impl RedisStreamConsumer {
+    pub fn new(config: RedisStreamConfig) -> Self {
+        RedisStreamConsumer { config, conn: None, caught_up: false }
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, Box<dyn std::error::Error>> {
+        let tcp = timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.config.addr))
+            .await
+            .map_err(|_| format!("timed out connecting to {}", self.config.addr))??;
+        let mut conn = BufReader::new(tcp);
+
+        if let Some(password) = &self.config.password {
+            let mut auth = vec!["AUTH"];
+            auth.extend(self.config.username.as_deref());
+            auth.push(password);
+            command(&mut conn, &auth).await?.into_result()?;
+        }
+        if let Some(db) = self.config.db {
+            command(&mut conn, &["SELECT", &db.to_string()]).await?.into_result()?;
+        }
+        // New groups start at the end of the stream, only requests sent from now on count
+        let create = ["XGROUP", "CREATE", &self.config.stream, &self.config.group, "$", "MKSTREAM"];
+        match command(&mut conn, &create).await? {
+            Reply::Error(e) if e.starts_with("BUSYGROUP") => {}
+            reply => {
+                reply.into_result()?;
+                info!("Created consumer group {} on stream {}", self.config.group, self.config.stream);
+            }
+        }
+        Ok(conn)
+    }
+
+    async fn read(&mut self) -> Result<Option<Delivery>, Box<dyn std::error::Error>> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect().await?);
+            self.caught_up = false;
+        }
+        let conn = self.conn.as_mut().expect("connected above");
+        // "0" replays this consumer's unacked entries, ">" asks for new ones
+        let start = if self.caught_up { ">" } else { "0" };
+        let block = BLOCK_MS.to_string();
+        let reply = command(conn, &[
+            "XREADGROUP", "GROUP", &self.config.group, &self.config.consumer,
+            "COUNT", "1", "BLOCK", &block, "STREAMS", &self.config.stream, start,
+        ])
+        .await?
+        .into_result()?;
+
+        // [[stream, [[id, [field, value, ...]], ...]]], or nil when BLOCK ran out
+        let entry = reply
+            .into_array()
+            .and_then(|streams| streams.into_iter().next())
+            .and_then(Reply::into_array)
+            .and_then(|stream| stream.into_iter().nth(1))
+            .and_then(Reply::into_array)
+            .and_then(|entries| entries.into_iter().next());
+        let Some(entry) = entry else {
+            if !self.caught_up {
+                debug!("No unacked entries left on {}, reading new ones", self.config.stream);
+                self.caught_up = true;
+            }
+            return Ok(None);
+        };
+
+        let mut entry = entry.into_array().unwrap_or_default().into_iter();
+        let id = entry.next().and_then(Reply::into_bytes).ok_or("stream entry without an id")?;
+        // Fields are nil for an entry deleted after being delivered
+        let fields = entry.next().and_then(Reply::into_array).unwrap_or_default();
+        let mut payload = Vec::new();
+        let mut fields = fields.into_iter();
+        while let (Some(field), Some(value)) = (fields.next(), fields.next()) {
+            if field.into_bytes().as_deref() == Some(PAYLOAD_FIELD) {
+                payload = value.into_bytes().unwrap_or_default();
+            }
+        }
+        Ok(Some(Delivery { id: String::from_utf8_lossy(&id).into_owned(), payload }))
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueConsumer for RedisStreamConsumer {
+    fn describe(&self) -> String {
+        format!(
+            "Redis stream {} at {} (group {}, consumer {})",
+            self.config.stream, self.config.addr, self.config.group, self.config.consumer
+        )
+    }
+
+    async fn next(&mut self) -> Result<Delivery, Box<dyn std::error::Error>> {
+        loop {
+            match self.read().await {
+                Ok(Some(delivery)) => return Ok(delivery),
+                Ok(None) => continue,
+                Err(e) => {
+                    // The connection may be mid-reply, start over with a new one
+                    self.conn = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.as_mut().ok_or("not connected")?;
+        let result = command(conn, &["XACK", &self.config.stream, &self.config.group, &delivery.id]).await;
+        match result.map(Reply::into_result) {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(e) => {
+                self.conn = None;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// A RESP reply.
+#[derive(Debug)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn into_result(self) -> Result<Reply, String> {
+        match self {
+            Reply::Error(e) => Err(format!("Redis answered: {}", e)),
+            reply => Ok(reply),
+        }
+    }
+
+    fn into_array(self) -> Option<Vec<Reply>> {
+        match self {
+            Reply::Array(items) => items,
+            _ => None,
+        }
+    }
+
+    fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Reply::Bulk(bytes) => bytes,
+            Reply::Simple(text) => Some(text.into_bytes()),
+            Reply::Integer(n) => Some(n.to_string().into_bytes()),
+            _ => None,
+        }
+    }
+}
+
+async fn command(conn: &mut BufReader<TcpStream>, args: &[&str]) -> std::io::Result<Reply> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg.as_bytes());
+        request.extend_from_slice(b"\r\n");
+    }
+    conn.get_mut().write_all(&request).await?;
+    read_reply(conn).await
+}
+
+fn read_reply(conn: &mut BufReader<TcpStream>) -> LocalBoxFuture<'_, std::io::Result<Reply>> {
+    async move {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Redis closed the connection"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unexpected Redis reply '{}'", line));
+        let (kind, rest) = line.split_at_checked(1).ok_or_else(invalid)?;
+        match kind {
+            "+" => Ok(Reply::Simple(rest.to_string())),
+            "-" => Ok(Reply::Error(rest.to_string())),
+            ":" => Ok(Reply::Integer(rest.parse().map_err(|_| invalid())?)),
+            "$" => {
+                let len: i64 = rest.parse().map_err(|_| invalid())?;
+                if len < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut bytes = vec![0; len as usize + 2];
+                conn.read_exact(&mut bytes).await?;
+                bytes.truncate(len as usize);
+                Ok(Reply::Bulk(Some(bytes)))
+            }
+            "*" => {
+                let len: i64 = rest.parse().map_err(|_| invalid())?;
+                if len < 0 {
+                    return Ok(Reply::Array(None));
+                }
+                let mut items = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    items.push(read_reply(conn).await?);
+                }
+                Ok(Reply::Array(Some(items)))
+            }
+            _ => Err(invalid()),
+        }
+    }
+    .boxed_local()
+}
+
+/// Feeds every message from `consumer` through the regular update path, one
+/// at a time. A message is acked once its update ran, whatever the outcome:
+/// the update already retried what was worth retrying, and redelivering a
+/// request that failed validation would only fail again.
+pub fn spawn(state: Arc<AppState>, mut consumer: Box<dyn QueueConsumer>) {
+    info!("Consuming update requests from {}", consumer.describe());
+
+    actix_web::rt::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            // Left in the stream until the apply window opens, rather than read and queued again
+            if !window::is_open(&state) {
+                sleep(WINDOW_POLL).await;
+                continue;
+            }
+            let delivery = match consumer.next().await {
+                Ok(delivery) => {
+                    backoff = MIN_BACKOFF;
+                    delivery
+                }
+                Err(e) => {
+                    warn!("Reading from {} failed, retrying in {}s: {}", consumer.describe(), backoff.as_secs(), e);
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            handle(&state, &delivery).await;
+            if let Err(e) = consumer.ack(&delivery).await {
+                warn!("Failed to ack queue message {}, it may be delivered again: {}", delivery.id, e);
+            }
+        }
+    });
+}
+
+async fn handle(state: &AppState, delivery: &Delivery) {
+    let mut hook: CertManagerHook = match serde_json::from_slice(&delivery.payload) {
+        Ok(hook) => hook,
+        Err(e) => {
+            error!("Dropping queue message {}, it isn't an update request: {}", delivery.id, e);
+            return;
+        }
+    };
+    hook.secret_ref.apply_default_namespace(state);
+    state.metrics.last_request.touch();
+
+    let (status, response) = process_update(state, &hook, UpdateSource::Queue).await;
+    if status.is_success() {
+        info!(
+            "Queue message {} for {}/{}: {}",
+            delivery.id, hook.secret_ref.namespace, hook.secret_ref.name, response.status
+        );
+    } else {
+        error!(
+            "Queue message {} for {}/{} failed with {}: {}",
+            delivery.id,
+            hook.secret_ref.namespace,
+            hook.secret_ref.name,
+            status.as_u16(),
+            response.message.unwrap_or_default()
+        );
+    }
+}
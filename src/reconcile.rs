@@ -0,0 +1,209 @@
+use crate::leader::Leadership;
+use crate::provider::CertParts;
+use crate::{cert, check_allowed, check_secret, fetch_secret, get_linode_config, push_to_config, resolve_config_ids, retry_target, SecretData};
+use crate::state::FingerprintStore as _;
+use crate::{validate_hook_request, window, AppState, HookRequest, UpdateSource};
+use log::{debug, error, info, warn};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A secret the scheduled revalidation keeps in sync with the NodeBalancer.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub namespace: String,
+    pub secret_name: String,
+}
+
+/// Parses a comma-separated list of `namespace/secret` targets.
+pub async fn parse_targets(raw: &str) -> Result<Vec<Target>, String> {
+    let mut targets = Vec::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (namespace, secret_name) = entry
+            .split_once('/')
+            .ok_or_else(|| format!("invalid target '{}', expected namespace/secret", entry))?;
+        let request = HookRequest {
+            namespace: namespace.to_string(),
+            secret_name: secret_name.to_string(),
+            config_id: None,
+            update_cert: true,
+            update_key: true,
+        };
+        validate_hook_request(&request)
+            .await
+            .map_err(|e| format!("invalid target '{}': {}", entry, e))?;
+        targets.push(Target {
+            namespace: request.namespace,
+            secret_name: request.secret_name,
+        });
+    }
+    Ok(targets)
+}
+
+/// Periodically re-checks every target and re-pushes it when the live
+/// certificate differs from the secret. Only the leader does any work, the
+/// first round runs right away to catch anything missed while down. Runs on
+/// the main arbiter as the update path isn't `Send`.
+pub fn spawn(state: Arc<AppState>, targets: Vec<Target>, interval: Duration, leadership: Leadership) {
+    info!("Revalidating {} target(s) every {}s", targets.len(), interval.as_secs());
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            if !leadership.is_leader() {
+                debug!("Not the leader, skipping revalidation");
+                continue;
+            }
+            if state.metrics.paused.until().is_some() {
+                debug!("Updates are paused, skipping revalidation");
+                continue;
+            }
+            if !window::is_open(&state) {
+                debug!("Outside the apply window, skipping revalidation");
+                continue;
+            }
+            for target in &targets {
+                revalidate(&state, target).await;
+            }
+        }
+    });
+}
+
+async fn revalidate(state: &AppState, target: &Target) {
+    debug!("Revalidating {}/{}", target.namespace, target.secret_name);
+
+    // Held to the same checks as a webhook update, the allowlists may have changed since the target was set
+    let request = HookRequest {
+        namespace: target.namespace.clone(),
+        secret_name: target.secret_name.clone(),
+        config_id: None,
+        update_cert: true,
+        update_key: true,
+    };
+    if let Err((_, response)) = check_allowed(state, &request) {
+        error!("Revalidation of {}/{} is not allowed: {}", target.namespace, target.secret_name, response.message.unwrap_or_default());
+        return;
+    }
+
+    let SecretData { cert, key, .. } = match fetch_secret(state, &target.namespace, &target.secret_name).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Revalidation of {}/{} failed to read the secret: {}", target.namespace, target.secret_name, e);
+            return;
+        }
+    };
+
+    if let Err(e) = cert::check_validity(&cert, cert::unix_now(), state.clock_skew_secs) {
+        error!(
+            "Certificate in {}/{} is not currently valid, not pushing it: {}",
+            target.namespace, target.secret_name, e
+        );
+        return;
+    }
+
+    let (bundle, fingerprint) = match check_secret(state, &request, &cert, &key) {
+        Ok((bundle, Some(fingerprint))) => (bundle, fingerprint),
+        Ok((_, None)) => {
+            error!("Failed to fingerprint certificate in {}/{}, not pushing it", target.namespace, target.secret_name);
+            return;
+        }
+        Err((_, response)) => {
+            error!(
+                "Revalidation of {}/{} refused the secret: {}",
+                target.namespace, target.secret_name, response.message.unwrap_or_default()
+            );
+            return;
+        }
+    };
+
+    for config_id in resolve_config_ids(state, &cert) {
+        let live = retry_target("linode_get_config", &state.metrics.retries, &state.linode, &config_id, || async {
+            get_linode_config(&state.linode, &config_id).await
+        }).await;
+
+        let live_fingerprint = match live {
+            Ok(config) => config.served_fingerprint().map(str::to_string),
+            Err(e) => {
+                warn!("Failed to read live config {}, skipping it this round: {}", config_id, e);
+                continue;
+            }
+        };
+
+        if live_fingerprint.as_deref().is_some_and(|live| cert::fingerprint_matches(&cert, live)) {
+            debug!("Config {} already serves the certificate from {}/{}", config_id, target.namespace, target.secret_name);
+            state.last_applied.set(&config_id, &fingerprint).await;
+            state.synced.store(true, Ordering::SeqCst);
+            state.metrics.updates.with_label_values(&[UpdateSource::Schedule.as_str(), "unchanged"]).inc();
+            continue;
+        }
+
+        match &live_fingerprint {
+            // A fresh config, nothing to compare against, so this is a first push rather than drift
+            None => info!("Config {} has no certificate yet, pushing {}/{}", config_id, target.namespace, target.secret_name),
+            Some(live) => {
+                warn!(
+                    "Config {} drifted from {}/{} (live fingerprint {}), re-pushing",
+                    config_id, target.namespace, target.secret_name, live
+                );
+                state.metrics.drift_detected.inc();
+            }
+        }
+
+        match push_to_config(state, &config_id, &bundle, CertParts::BOTH, Some(&fingerprint)).await {
+            Ok(_) if live_fingerprint.is_none() => {
+                info!("Applied the first certificate to config {}", config_id);
+                state.metrics.updates.with_label_values(&[UpdateSource::Schedule.as_str(), "success"]).inc();
+                state.synced.store(true, Ordering::SeqCst);
+            }
+            Ok(_) => {
+                info!("Corrected drift on config {}", config_id);
+                state.metrics.drift_corrected.inc();
+                state.metrics.updates.with_label_values(&[UpdateSource::Schedule.as_str(), "success"]).inc();
+                state.synced.store(true, Ordering::SeqCst);
+            }
+            Err((_, response)) => {
+                state.metrics.updates.with_label_values(&[UpdateSource::Schedule.as_str(), "error"]).inc();
+                error!("Failed to correct drift on config {}: {}", config_id, response.message.unwrap_or_default());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, testutil};
+
+    fn target() -> Target {
+        Target { namespace: selftest::NAMESPACE.to_string(), secret_name: selftest::SECRET_NAME.to_string() }
+    }
+
+    #[actix_web::test]
+    async fn revalidation_keeps_to_allowed_fingerprints() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (pinned_cert, _) = selftest::generate_chain().unwrap();
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let pinned = cert::leaf_fingerprint(&pinned_cert).unwrap();
+        let config = testutil::config(&mock, &[("ALLOWED_FINGERPRINTS", &pinned)]).await;
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+
+        revalidate(&state, &target()).await;
+        assert!(mock.configs.lock().unwrap()[selftest::CONFIG_ID].get("ssl_cert").is_none());
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn revalidation_refuses_a_mismatched_key() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (cert_pem, _) = selftest::generate_chain().unwrap();
+        let (_, other_key) = selftest::generate_chain().unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &other_key)]));
+
+        revalidate(&state, &target()).await;
+        assert!(mock.configs.lock().unwrap()[selftest::CONFIG_ID].get("ssl_cert").is_none());
+        mock.stop().await;
+    }
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::Api;
+use kube::core::ErrorResponse;
+use kube::Client;
+use std::collections::HashMap;
+
+/// Where TLS secrets are read from. The cluster in production, an in-memory
+/// map for `selftest`.
+#[async_trait(?Send)]
+pub trait SecretSource: Send + Sync {
+    /// Errors are `kube::Error` so callers can tell a missing secret apart.
+    async fn get(&self, namespace: &str, name: &str) -> Result<Secret, kube::Error>;
+}
+
+/// Reads secrets through the Kubernetes API.
+pub struct KubeSecrets {
+    pub client: Client,
+}
+
+#[async_trait(?Send)]
+impl SecretSource for KubeSecrets {
+    async fn get(&self, namespace: &str, name: &str) -> Result<Secret, kube::Error> {
+        let secrets: Api<Secret> = Api::namespaced(self.client.clone(), namespace);
+        secrets.get(name).await
+    }
+}
+
+/// Fixed set of secrets keyed by (namespace, name), answering a 404 like the
+/// API server for anything else.
+#[derive(Default)]
+pub struct MemorySecrets {
+    pub secrets: HashMap<(String, String), Secret>,
+}
+
+#[async_trait(?Send)]
+impl SecretSource for MemorySecrets {
+    async fn get(&self, namespace: &str, name: &str) -> Result<Secret, kube::Error> {
+        self.secrets
+            .get(&(namespace.to_string(), name.to_string()))
+            .cloned()
+            .ok_or_else(|| {
+                kube::Error::Api(ErrorResponse {
+                    status: "Failure".to_string(),
+                    message: format!("secrets \"{}\" not found", name),
+                    reason: "NotFound".to_string(),
+                    code: 404,
+                })
+            })
+    }
+}
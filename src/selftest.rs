@@ -0,0 +1,318 @@
+use crate::secrets::MemorySecrets;
+use crate::{build_state, cert, config, live, metrics, state, CertManagerHook, ErrorCode, SecretRef, UpdateSource};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
+use log::{error, info};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509Name, X509};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const NAMESPACE: &str = "selftest";
+pub const SECRET_NAME: &str = "selftest-tls";
+const NODEBALANCER_ID: &str = "1";
+pub const CONFIG_ID: &str = "2";
+const HOSTNAME: &str = "selftest.example";
+// The only token the mock accepts
+pub const TOKEN: &str = "selftest";
+// Config the mock's token has no grant for, answered with a 403
+pub const FORBIDDEN_CONFIG_ID: &str = "3";
+// Config the mock fails with a bodyless 500, like Linode's edge sometimes does
+pub const ERROR_CONFIG_ID: &str = "4";
+// Bearer token for the admin endpoints the selftest calls
+const ADMIN_TOKEN: &str = "selftest-admin";
+
+/// NodeBalancer configs by id, as the mock Linode API holds them.
+pub type MockConfigs = Mutex<HashMap<String, serde_json::Value>>;
+
+/// An in-process Linode API holding one HTTPS config, CONFIG_ID, and
+/// answering for FORBIDDEN_CONFIG_ID and ERROR_CONFIG_ID like Linode would.
+pub struct MockLinode {
+    // Without the API version, like LINODE_API_URL
+    pub api_url: String,
+    pub configs: web::Data<MockConfigs>,
+    handle: actix_web::dev::ServerHandle,
+}
+
+impl MockLinode {
+    pub async fn start() -> std::io::Result<Self> {
+        let configs = web::Data::new(MockConfigs::new(HashMap::from([(
+            CONFIG_ID.to_string(),
+            serde_json::json!({
+                "id": 2,
+                "port": 443,
+                "protocol": "https",
+                "nodebalancer_id": 1,
+                "check": "http_body",
+                "check_interval": 15,
+                "check_path": "/healthz",
+                "check_body": "ok",
+            }),
+        )])));
+        let server_configs = configs.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_configs.clone())
+                .route("/v4/nodebalancers/{nodebalancer}/configs/{config}", web::get().to(get_config))
+                .route("/v4/nodebalancers/{nodebalancer}/configs/{config}", web::put().to(put_config))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))?;
+        let api_url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        info!("Mock Linode API listening on {}", api_url);
+        Ok(MockLinode { api_url, configs, handle })
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+/// `cert-webhook selftest`: runs the update flow against an in-process Linode
+/// mock and an in-memory secret, without a cluster or a token. Each step
+/// below is also the shortest description of what an update does.
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mock = MockLinode::start().await?;
+    let (cert_pem, key_pem) = generate_chain()?;
+    let result = run_steps(&mock.api_url, &mock.configs, &cert_pem, &key_pem).await;
+    mock.stop().await;
+    result
+}
+
+async fn run_steps(
+    api_url: &str,
+    configs: &MockConfigs,
+    cert_pem: &str,
+    key_pem: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The regular config loader, pointed at the mock
+    std::env::set_var("LINODE_TOKEN", TOKEN);
+    std::env::set_var("NODEBALANCER_ID", NODEBALANCER_ID);
+    std::env::set_var("HTTPS_CONFIG_ID", CONFIG_ID);
+    std::env::set_var("LINODE_API_URL", api_url);
+    std::env::set_var("LINODE_API_VERSION", "v4");
+    std::env::set_var("ALLOWED_SECRETS", format!("{}=selftest-*|missing-tls", NAMESPACE));
+    std::env::set_var("ADMIN_TOKEN", ADMIN_TOKEN);
+    let mut config = config::Config::load()
+        .await
+        .map_err(|errors| format!("invalid configuration: {}", errors.join("; ")))?;
+
+    // Never contacted, secrets come from memory and annotations are off
+    let kube_client = kube::Client::try_from(kube::Config::new("http://127.0.0.1:9".parse()?))?;
+    let mut secrets = MemorySecrets::default();
+    secrets.secrets.insert((NAMESPACE.to_string(), SECRET_NAME.to_string()), tls_secret(cert_pem, key_pem));
+
+    let http_client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let registry = prometheus::Registry::new();
+    let metrics = metrics::Metrics::new(&registry, &config.metrics_namespace)?;
+    let state = Arc::new(build_state(
+        &mut config,
+        kube_client,
+        Box::new(secrets),
+        http_client.clone(),
+        http_client,
+        metrics,
+        state::StateStore::memory(),
+    ));
+
+    let mut failures = 0;
+    let mut check = |step: &str, passed: bool, detail: String| {
+        if passed {
+            info!("selftest: {} ... ok", step);
+        } else {
+            error!("selftest: {} ... FAILED: {}", step, detail);
+            failures += 1;
+        }
+    };
+
+    let (status, response) = crate::process_update(&state, &hook(SECRET_NAME), UpdateSource::Webhook).await;
+    check("first update is applied", status == StatusCode::OK && response.status == "success", format!("{} {:?}", status, response));
+
+    let applied = configs.lock().unwrap()[CONFIG_ID]["ssl_cert"].as_str().map(cert::leaf_fingerprint);
+    let expected = cert::leaf_fingerprint(cert_pem)?;
+    check(
+        "mock config serves the secret's certificate",
+        matches!(&applied, Some(Ok(fingerprint)) if *fingerprint == expected),
+        format!("expected {}, config has {:?}", expected, applied),
+    );
+
+    let recorded: Vec<(String, &str, Option<String>)> = state.history.entries()[0]
+        .configs
+        .iter()
+        .map(|config| (config.config_id.clone(), config.outcome, config.fingerprint.clone()))
+        .collect();
+    check(
+        "history records the fingerprint applied per config",
+        recorded == [(CONFIG_ID.to_string(), "applied", Some(expected.clone()))],
+        format!("history has {:?}", recorded),
+    );
+
+    let (status, response) = crate::process_update(&state, &hook(SECRET_NAME), UpdateSource::Webhook).await;
+    check("repeat update is deduplicated", status == StatusCode::OK && response.status == "unchanged", format!("{} {:?}", status, response));
+
+    let request = serde_json::from_value(serde_json::json!({
+        "secretRef": { "name": SECRET_NAME, "namespace": NAMESPACE },
+    }))?;
+    let req = actix_web::test::TestRequest::post()
+        .uri("/verify")
+        .insert_header((AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN)))
+        .to_http_request();
+    let response = live::verify_live(web::Data::new(state.clone()), req, web::Json(request)).await;
+    check("/verify reports the config in sync", response.status() == StatusCode::OK, format!("{}", response.status()));
+
+    let (status, response) = crate::process_update(&state, &hook("other-tls"), UpdateSource::Webhook).await;
+    check(
+        "secret outside ALLOWED_SECRETS is rejected",
+        status == StatusCode::FORBIDDEN && response.code == Some(ErrorCode::SecretNotAllowed),
+        format!("{} {:?}", status, response),
+    );
+
+    let (status, response) = crate::process_update(&state, &hook("missing-tls"), UpdateSource::Webhook).await;
+    check(
+        "missing secret is reported as such",
+        response.code == Some(ErrorCode::SecretNotFound),
+        format!("{} {:?}", status, response),
+    );
+
+    if failures > 0 {
+        return Err(format!("{} selftest step(s) failed", failures).into());
+    }
+    info!("selftest passed");
+    Ok(())
+}
+
+pub fn hook(secret_name: &str) -> CertManagerHook {
+    CertManagerHook {
+        secret_ref: SecretRef { name: secret_name.to_string(), namespace: NAMESPACE.to_string() },
+        config_id: None,
+        update_cert: true,
+        update_key: true,
+    }
+}
+
+pub fn tls_secret(cert_pem: &str, key_pem: &str) -> Secret {
+    use base64::Engine as _;
+    // Values go through decode_secret_field, so they are stored base64-encoded
+    let encode = |pem: &str| ByteString(base64::engine::general_purpose::STANDARD.encode(pem).into_bytes());
+    let mut secret = Secret::default();
+    secret.metadata.name = Some(SECRET_NAME.to_string());
+    secret.metadata.namespace = Some(NAMESPACE.to_string());
+    secret.data = Some(BTreeMap::from([
+        ("tls.crt".to_string(), encode(cert_pem)),
+        ("tls.key".to_string(), encode(key_pem)),
+    ]));
+    secret
+}
+
+/// A throwaway CA and a leaf for HOSTNAME signed by it, as the leaf + CA
+/// bundle and the leaf's key.
+pub fn generate_chain() -> Result<(String, String), openssl::error::ErrorStack> {
+    let ca_key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let ca = build_cert("cert-webhook selftest CA", &ca_key, None)?;
+    let leaf_key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let leaf = build_cert(HOSTNAME, &leaf_key, Some((&ca, &ca_key)))?;
+
+    let mut bundle = String::from_utf8_lossy(&leaf.to_pem()?).into_owned();
+    bundle.push_str(&String::from_utf8_lossy(&ca.to_pem()?));
+    let key = String::from_utf8_lossy(&leaf_key.private_key_to_pem_pkcs8()?).into_owned();
+    Ok((bundle, key))
+}
+
+fn build_cert(
+    common_name: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+) -> Result<X509, openssl::error::ErrorStack> {
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_text("CN", common_name)?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(30)?.as_ref())?;
+    match issuer {
+        Some((issuer_cert, issuer_key)) => {
+            builder.set_issuer_name(issuer_cert.subject_name())?;
+            let san = SubjectAlternativeName::new().dns(common_name).build(&builder.x509v3_context(Some(issuer_cert), None))?;
+            builder.append_extension(san)?;
+            builder.sign(issuer_key, MessageDigest::sha256())?;
+        }
+        None => {
+            builder.set_issuer_name(&name)?;
+            builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+            builder.sign(key, MessageDigest::sha256())?;
+        }
+    }
+    Ok(builder.build())
+}
+
+/// Linode's answer to a request its token can't make, if any.
+fn auth_rejection(req: &HttpRequest, config_id: &str) -> Option<HttpResponse> {
+    let authorization = req.headers().get("authorization").and_then(|value| value.to_str().ok());
+    if authorization != Some(format!("Bearer {}", TOKEN).as_str()) {
+        return Some(HttpResponse::Unauthorized().json(serde_json::json!({ "errors": [{ "reason": "Invalid Token" }] })));
+    }
+    if config_id == FORBIDDEN_CONFIG_ID {
+        return Some(HttpResponse::Forbidden().json(serde_json::json!({ "errors": [{ "reason": "Unauthorized" }] })));
+    }
+    None
+}
+
+async fn get_config(req: HttpRequest, configs: web::Data<MockConfigs>, path: web::Path<(String, String)>) -> HttpResponse {
+    if let Some(response) = auth_rejection(&req, &path.1) {
+        return response;
+    }
+    if path.1 == ERROR_CONFIG_ID {
+        return HttpResponse::InternalServerError().finish();
+    }
+    match configs.lock().unwrap().get(&path.1) {
+        Some(config) => HttpResponse::Ok().json(config),
+        None => not_found(),
+    }
+}
+
+/// Applies the update like Linode does: fields sent replace the stored ones,
+/// everything else is kept.
+async fn put_config(
+    req: HttpRequest,
+    configs: web::Data<MockConfigs>,
+    path: web::Path<(String, String)>,
+    body: web::Json<serde_json::Map<String, serde_json::Value>>,
+) -> HttpResponse {
+    if let Some(response) = auth_rejection(&req, &path.1) {
+        return response;
+    }
+    if path.1 == ERROR_CONFIG_ID {
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut configs = configs.lock().unwrap();
+    let Some(config) = configs.get_mut(&path.1) else {
+        return not_found();
+    };
+    for (field, value) in body.into_inner() {
+        config[field] = value;
+    }
+    // Linode reports the fingerprint of what it now serves, POST_VERIFY reads it back
+    if let Some(Ok(fingerprint)) = config["ssl_cert"].as_str().map(cert::leaf_fingerprint) {
+        config["ssl_fingerprint"] = serde_json::Value::String(fingerprint);
+    }
+    HttpResponse::Ok().json(config)
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({ "errors": [{ "reason": "Not found" }] }))
+}
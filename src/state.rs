@@ -0,0 +1,440 @@
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{Api, Patch, PatchParams, PostParams};
+use kube::Client;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const FIELD_MANAGER: &str = "cert-webhook";
+
+/// Annotation holding the leaf fingerprint last pushed from a secret.
+pub const LAST_APPLIED_ANNOTATION: &str = "cert-webhook/last-applied-fingerprint";
+
+/// Where deduplication looks up the leaf fingerprint last pushed for a key.
+/// Failing to read or write only costs a redundant push later, so errors are
+/// logged by the store and never surface.
+#[async_trait(?Send)]
+pub trait FingerprintStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<String>;
+
+    async fn set(&self, key: &str, fingerprint: &str);
+
+    /// Forgets every fingerprint, returning how many were removed.
+    async fn clear(&self) -> usize;
+}
+
+/// Whether `fingerprint` is what `store` last recorded for `key`. An
+/// unknown fingerprint never is, so it can't skip a push.
+pub async fn already_applied(store: &dyn FingerprintStore, key: &str, fingerprint: Option<&str>) -> bool {
+    match fingerprint {
+        Some(fingerprint) => store.get(key).await.as_deref() == Some(fingerprint),
+        None => false,
+    }
+}
+
+/// DEDUP_ANNOTATION: fingerprints kept on the secrets themselves, keyed by
+/// `namespace/name`, so deduplication survives restarts without a ConfigMap.
+pub struct SecretAnnotations {
+    client: Client,
+    // Secrets seen carrying the annotation, the ones clear() removes it from
+    annotated: Mutex<HashSet<String>>,
+    // Set by clear(), the keys annotated since. Any other annotation may be on
+    // a secret this process never saw, so it is ignored until rewritten
+    written_since_clear: Mutex<Option<HashSet<String>>>,
+}
+
+impl SecretAnnotations {
+    pub fn new(client: Client) -> Self {
+        SecretAnnotations {
+            client,
+            annotated: Mutex::new(HashSet::new()),
+            written_since_clear: Mutex::new(None),
+        }
+    }
+
+    fn api(&self, key: &str) -> Option<(Api<Secret>, String)> {
+        let (namespace, name) = key.split_once('/')?;
+        Some((Api::namespaced(self.client.clone(), namespace), name.to_string()))
+    }
+}
+
+#[async_trait(?Send)]
+impl FingerprintStore for SecretAnnotations {
+    async fn get(&self, key: &str) -> Option<String> {
+        if self.written_since_clear.lock().unwrap().as_ref().is_some_and(|written| !written.contains(key)) {
+            debug!("Ignoring the last-applied annotation of {}, it predates the last clear", key);
+            return None;
+        }
+        let (api, name) = self.api(key)?;
+        match api.get_metadata(&name).await {
+            Ok(secret) => {
+                let fingerprint = secret.metadata.annotations?.remove(LAST_APPLIED_ANNOTATION)?;
+                self.annotated.lock().unwrap().insert(key.to_string());
+                Some(fingerprint)
+            }
+            Err(e) => {
+                warn!("Failed to read the last-applied annotation of {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, fingerprint: &str) {
+        let Some((api, name)) = self.api(key) else {
+            return;
+        };
+        let patch = serde_json::json!({
+            "metadata": { "annotations": { LAST_APPLIED_ANNOTATION: fingerprint } },
+        });
+
+        match api.patch(&name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+            Ok(_) => {
+                debug!("Annotated {} with its last-applied fingerprint", key);
+                self.annotated.lock().unwrap().insert(key.to_string());
+                if let Some(written) = self.written_since_clear.lock().unwrap().as_mut() {
+                    written.insert(key.to_string());
+                }
+            }
+            Err(e) => warn!("Failed to annotate {} with its last-applied fingerprint: {}", key, e),
+        }
+    }
+
+    /// Removes the annotation from every secret seen carrying it. Those on
+    /// secrets this process hasn't seen are ignored from now on instead.
+    async fn clear(&self) -> usize {
+        *self.written_since_clear.lock().unwrap() = Some(HashSet::new());
+        let keys = std::mem::take(&mut *self.annotated.lock().unwrap());
+        let patch = serde_json::json!({
+            "metadata": { "annotations": { LAST_APPLIED_ANNOTATION: null } },
+        });
+
+        let mut cleared = 0;
+        for key in keys {
+            let Some((api, name)) = self.api(&key) else {
+                continue;
+            };
+            match api.patch(&name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+                Ok(_) => cleared += 1,
+                Err(e) => warn!("Failed to remove the last-applied annotation of {}: {}", key, e),
+            }
+        }
+        cleared
+    }
+}
+
+/// Last-applied leaf fingerprint per NodeBalancer config id. Always kept in
+/// memory, and optionally mirrored to a ConfigMap so a restart doesn't cause a
+/// redundant push. APPLY_WINDOW keeps its queued updates in a second one.
+pub struct StateStore {
+    entries: Mutex<Entries>,
+    configmap: Option<(Api<ConfigMap>, String)>,
+    // What the entries are, for logs
+    what: &'static str,
+    persist: AtomicBool,
+    // Held from reading the values to write until the patch is answered, so
+    // writes reach the API server in order and never with a stale value
+    writing: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct Entries {
+    values: HashMap<String, String>,
+    // Keys whose value in memory the ConfigMap may not have yet
+    dirty: HashSet<String>,
+}
+
+impl Entries {
+    fn set(&mut self, key: &str, value: Option<&str>) {
+        match value {
+            Some(value) => self.values.insert(key.to_string(), value.to_string()),
+            None => self.values.remove(key),
+        };
+        self.dirty.insert(key.to_string());
+    }
+}
+
+/// What a persist sends: the named keys at their value in memory, or no
+/// data at all, which removes every key.
+enum Write {
+    Keys(Vec<String>),
+    Clear(Vec<String>),
+}
+
+impl StateStore {
+    pub fn memory() -> Self {
+        StateStore {
+            entries: Mutex::new(Entries::default()),
+            configmap: None,
+            what: "last-applied fingerprint(s)",
+            persist: AtomicBool::new(false),
+            writing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Loads previously persisted entries, `what` naming them in logs. Any
+    /// failure, including RBAC denying access, leaves the store usable in
+    /// memory only.
+    pub async fn load(client: Client, namespace: &str, name: &str, what: &'static str) -> Self {
+        let api: Api<ConfigMap> = Api::namespaced(client, namespace);
+        let mut store = StateStore { what, ..StateStore::memory() };
+
+        match api.get_opt(name).await {
+            Ok(configmap) => {
+                let data = configmap.and_then(|cm| cm.data).unwrap_or_default();
+                info!("Loaded {} {} from ConfigMap {}/{}", data.len(), what, namespace, name);
+                store.entries = Mutex::new(Entries { values: data.into_iter().collect(), ..Entries::default() });
+                store.persist = AtomicBool::new(true);
+            }
+            Err(e) => {
+                warn!("Failed to read state ConfigMap {}/{}, keeping state in memory only: {}", namespace, name, e);
+            }
+        }
+
+        store.configmap = Some((api, name.to_string()));
+        store
+    }
+
+    /// Whether entries are mirrored to a ConfigMap and so survive a restart.
+    pub fn persistent(&self) -> bool {
+        self.persist.load(Ordering::SeqCst)
+    }
+
+    /// Every config id and its last-applied fingerprint, sorted by config id.
+    pub fn snapshot(&self) -> BTreeMap<String, String> {
+        self.entries.lock().unwrap().values.clone().into_iter().collect()
+    }
+
+    /// Re-reads the ConfigMap, which other replicas write to as well, and
+    /// returns every entry. Entries that haven't been persisted yet are kept
+    /// as they are in memory. Without a readable ConfigMap this is `snapshot`.
+    pub async fn refresh(&self) -> BTreeMap<String, String> {
+        let Some((api, name)) = self.configmap.as_ref().filter(|_| self.persist.load(Ordering::SeqCst)) else {
+            return self.snapshot();
+        };
+        match api.get_opt(name).await {
+            Ok(configmap) => {
+                let mut values: HashMap<String, String> = configmap.and_then(|cm| cm.data).unwrap_or_default().into_iter().collect();
+                let mut entries = self.entries.lock().unwrap();
+                for key in &entries.dirty {
+                    match entries.values.get(key) {
+                        Some(value) => values.insert(key.clone(), value.clone()),
+                        None => values.remove(key),
+                    };
+                }
+                entries.values = values;
+            }
+            Err(e) => warn!("Failed to re-read {} from ConfigMap {}, using the ones in memory: {}", self.what, name, e),
+        }
+        self.snapshot()
+    }
+
+    /// Forgets every fingerprint, persisted ones included, so the next update
+    /// of each config is pushed even when unchanged. Returns how many were held.
+    pub async fn clear(&self) -> usize {
+        let keys: Vec<String> = {
+            let mut entries = self.entries.lock().unwrap();
+            let keys: Vec<String> = entries.values.keys().cloned().collect();
+            for key in &keys {
+                entries.set(key, None);
+            }
+            keys
+        };
+        let cleared = keys.len();
+        self.persist(Write::Clear(keys)).await;
+        cleared
+    }
+
+    /// Writes out any fingerprints a failed persist left behind, used on shutdown.
+    pub async fn flush(&self) {
+        let dirty: Vec<String> = self.entries.lock().unwrap().dirty.iter().cloned().collect();
+        if self.configmap.is_none() || !self.persist.load(Ordering::SeqCst) || dirty.is_empty() {
+            debug!("No pending state to flush");
+            return;
+        }
+
+        info!("Flushing {} {} to ConfigMap", dirty.len(), self.what);
+        self.persist(Write::Keys(dirty)).await;
+    }
+
+    /// Sets `key` in memory and in the ConfigMap, if persisting.
+    pub async fn insert(&self, key: &str, value: &str) {
+        self.entries.lock().unwrap().set(key, Some(value));
+        self.persist(Write::Keys(vec![key.to_string()])).await;
+    }
+
+    /// Drops `key` only while it still holds `value`, so an entry replaced in
+    /// the meantime is kept. Returns whether it was dropped.
+    pub async fn remove_if(&self, key: &str, value: &str) -> bool {
+        let removed = {
+            let mut entries = self.entries.lock().unwrap();
+            let held = entries.values.get(key).is_some_and(|held| held == value);
+            if held {
+                entries.set(key, None);
+            }
+            held
+        };
+        if removed {
+            self.persist(Write::Keys(vec![key.to_string()])).await;
+        }
+        removed
+    }
+
+    /// Merge-patches the ConfigMap, a key missing from memory being removed.
+    /// Keys the write doesn't name are left alone, so replicas sharing the
+    /// ConfigMap don't drop each other's.
+    async fn persist(&self, write: Write) {
+        let Some((api, name)) = &self.configmap else {
+            return;
+        };
+        if !self.persist.load(Ordering::SeqCst) {
+            return;
+        }
+        let _writing = self.writing.lock().await;
+
+        let (sent, data): (BTreeMap<String, Option<String>>, _) = match write {
+            Write::Keys(keys) => {
+                let entries = self.entries.lock().unwrap();
+                let sent: BTreeMap<_, _> = keys.into_iter().map(|key| {
+                    let value = entries.values.get(&key).cloned();
+                    (key, value)
+                }).collect();
+                (sent.clone(), Some(sent))
+            }
+            Write::Clear(keys) => (keys.into_iter().map(|key| (key, None)).collect(), None),
+        };
+        let params = PatchParams { field_manager: Some(FIELD_MANAGER.to_string()), ..Default::default() };
+        let patch = serde_json::json!({ "data": data });
+        let result = match api.patch(name, &params, &Patch::Merge(&patch)).await {
+            Err(kube::Error::Api(resp)) if resp.code == 404 => {
+                let configmap = ConfigMap {
+                    metadata: ObjectMeta { name: Some(name.clone()), ..Default::default() },
+                    data: Some(data.unwrap_or_default().into_iter().filter_map(|(key, value)| Some((key, value?))).collect()),
+                    ..Default::default()
+                };
+                match api.create(&PostParams::default(), &configmap).await {
+                    // Created by another replica in the meantime
+                    Err(kube::Error::Api(resp)) if resp.code == 409 => api.patch(name, &params, &Patch::Merge(&patch)).await,
+                    result => result,
+                }
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(_) => {
+                debug!("Persisted {} to ConfigMap {}", self.what, name);
+                // A key changed since it was read stays dirty, its own write follows this one
+                let mut entries = self.entries.lock().unwrap();
+                for (key, value) in sent {
+                    if entries.values.get(&key) == value.as_ref() {
+                        entries.dirty.remove(&key);
+                    }
+                }
+            }
+            Err(kube::Error::Api(resp)) if resp.code == 403 => {
+                warn!("Not allowed to write state ConfigMap {}, disabling persistence: {}", name, resp.message);
+                self.persist.store(false, Ordering::SeqCst);
+            }
+            Err(e) => warn!("Failed to persist {} to ConfigMap {}: {}", self.what, name, e),
+        }
+    }
+}
+
+/// Keyed by NodeBalancer config id.
+#[async_trait(?Send)]
+impl FingerprintStore for StateStore {
+    async fn get(&self, config_id: &str) -> Option<String> {
+        self.entries.lock().unwrap().values.get(config_id).cloned()
+    }
+
+    async fn set(&self, config_id: &str, fingerprint: &str) {
+        self.insert(config_id, fingerprint).await;
+    }
+
+    async fn clear(&self) -> usize {
+        StateStore::clear(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::MockKube;
+
+    const NAME: &str = "cert-webhook-pending";
+
+    async fn replica(kube: &MockKube) -> StateStore {
+        StateStore::load(kube.client(), "default", NAME, "pending update(s)").await
+    }
+
+    #[actix_web::test]
+    async fn replicas_share_the_configmap() {
+        let kube = MockKube::start().await.unwrap();
+        let (leader, other) = (replica(&kube).await, replica(&kube).await);
+
+        // The first write creates the ConfigMap, later ones only touch their key
+        leader.insert("a", "1").await;
+        other.insert("b", "2").await;
+        let stored = kube.configmaps.lock().unwrap()[NAME].clone();
+        assert_eq!(stored, BTreeMap::from([("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())]));
+        assert_eq!(leader.refresh().await, stored);
+
+        assert!(leader.remove_if("b", "2").await);
+        other.insert("c", "3").await;
+        assert_eq!(kube.configmaps.lock().unwrap()[NAME], BTreeMap::from([
+            ("a".to_string(), "1".to_string()),
+            ("c".to_string(), "3".to_string()),
+        ]));
+
+        // Clearing drops the other replica's keys as well
+        assert_eq!(leader.clear().await, 1);
+        assert!(kube.configmaps.lock().unwrap()[NAME].is_empty());
+        kube.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn concurrent_writes_keep_every_key() {
+        let kube = MockKube::start().await.unwrap();
+        let store = replica(&kube).await;
+        let keys: Vec<String> = (0..20).map(|i| format!("config-{}", i)).collect();
+        futures::future::join_all(keys.iter().map(|key| store.insert(key, "ab"))).await;
+        futures::future::join_all((0..5).map(|i| store.insert("config-0", ["1", "2", "3", "4", "5"][i]))).await;
+
+        assert_eq!(kube.configmaps.lock().unwrap()[NAME], store.snapshot());
+        assert_eq!(store.snapshot().len(), 20);
+        kube.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn flush_writes_what_a_failed_persist_left_behind() {
+        let kube = MockKube::start().await.unwrap();
+        let store = replica(&kube).await;
+        store.insert("a", "1").await;
+        kube.failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        store.insert("b", "2").await;
+        assert!(store.remove_if("a", "1").await);
+        kube.failing.store(false, std::sync::atomic::Ordering::SeqCst);
+
+        // A later write succeeding on its own key doesn't count as persisting the others
+        store.insert("c", "3").await;
+        assert_eq!(kube.configmaps.lock().unwrap()[NAME].len(), 2);
+        store.flush().await;
+        assert_eq!(kube.configmaps.lock().unwrap()[NAME], store.snapshot());
+        assert_eq!(store.snapshot().into_keys().collect::<Vec<_>>(), ["b", "c"]);
+        kube.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn a_replaced_entry_is_not_removed() {
+        let store = StateStore::memory();
+        store.insert("a", "old").await;
+        store.insert("a", "new").await;
+        assert!(!store.remove_if("a", "old").await);
+        assert_eq!(store.snapshot().get("a").map(String::as_str), Some("new"));
+        assert!(store.remove_if("a", "new").await);
+        assert!(store.snapshot().is_empty());
+    }
+}
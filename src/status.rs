@@ -0,0 +1,194 @@
+use crate::cert;
+use crate::history::{ConfigResult, HistoryEntry};
+use crate::{admin_rejection, AppState};
+use actix_web::{web, HttpRequest, HttpResponse};
+use k8s_openapi::chrono::DateTime;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Read-only HTML overview for operators without Prometheus: the latest
+/// update, the expiry of the last pushed certificate, dependency health and
+/// the recent history. Behind ADMIN_TOKEN like /export, as it names secrets
+/// and configs.
+pub async fn status_page(req: HttpRequest, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if let Some(response) = admin_rejection(&state, &req) {
+        return response;
+    }
+
+    let (kube, linode) = futures::join!(check_kube(&state), check_linode(&state));
+    let entries = state.history.entries();
+
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>cert-webhook</title>",
+        "<style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}",
+        "td,th{border:1px solid #ccc;padding:4px 8px;text-align:left}.ok{color:#080}.bad{color:#b00}</style>",
+        "</head><body><h1>cert-webhook</h1>",
+    ));
+
+    html.push_str("<h2>Last update</h2>");
+    match entries.first() {
+        Some(last) => {
+            let _ = write!(
+                html,
+                "<p>{} for {}/{}: <span class=\"{}\">{}</span> ({}){}</p>",
+                format_time(last.at),
+                escape(&last.namespace),
+                escape(&last.secret_name),
+                status_class(last),
+                escape(&last.status),
+                last.http_status,
+                last.message.as_deref().map(|m| format!(" &mdash; {}", escape(m))).unwrap_or_default(),
+            );
+        }
+        None => html.push_str("<p>No update processed since startup.</p>"),
+    }
+
+    html.push_str("<h2>Current certificate</h2>");
+    match entries.iter().find(|entry| entry.http_status < 300).and_then(|entry| entry.not_after) {
+        Some(not_after) => {
+            let days = (not_after - cert::unix_now()) / 86_400;
+            let class = if days > state.expiry_warn_days { "ok" } else { "bad" };
+            let _ = write!(
+                html,
+                "<p>Expires {} (<span class=\"{}\">{} day(s)</span>)</p>",
+                format_time(not_after),
+                class,
+                days
+            );
+        }
+        None => html.push_str("<p>Unknown, no certificate applied since startup.</p>"),
+    }
+
+    // Per config, an update of several configs can leave them on different leaves
+    html.push_str("<h2>Configs</h2>");
+    let last_applied = state.last_applied.snapshot();
+    if last_applied.is_empty() {
+        html.push_str("<p>No certificate applied since startup.</p>");
+    } else {
+        html.push_str("<table><tr><th>Config</th><th>Last applied fingerprint</th><th>Latest result</th></tr>");
+        for (config_id, fingerprint) in &last_applied {
+            let latest = entries.iter().flat_map(|entry| &entry.configs).find(|config| &config.config_id == config_id);
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td><code>{}</code></td><td>{}</td></tr>",
+                escape(config_id),
+                escape(fingerprint),
+                latest.map(config_summary).unwrap_or_default(),
+            );
+        }
+        html.push_str("</table>");
+    }
+
+    html.push_str("<h2>Dependencies</h2><table>");
+    for (name, result) in [("Kubernetes API", &kube), ("Linode API", &linode)] {
+        let _ = match result {
+            Ok(()) => write!(html, "<tr><td>{}</td><td class=\"ok\">healthy</td></tr>", name),
+            Err(e) => write!(html, "<tr><td>{}</td><td class=\"bad\">{}</td></tr>", name, escape(e)),
+        };
+    }
+    html.push_str("</table>");
+
+    html.push_str(
+        "<h2>Recent updates</h2><table><tr><th>Time</th><th>Secret</th><th>Result</th><th>Message</th><th>Configs</th></tr>",
+    );
+    for entry in &entries {
+        let configs: Vec<String> = entry.configs.iter().map(config_summary).collect();
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{}/{}</td><td class=\"{}\">{} ({})</td><td>{}</td><td>{}</td></tr>",
+            format_time(entry.at),
+            escape(&entry.namespace),
+            escape(&entry.secret_name),
+            status_class(entry),
+            escape(&entry.status),
+            entry.http_status,
+            escape(entry.message.as_deref().unwrap_or("")),
+            configs.join("<br>"),
+        );
+    }
+    html.push_str("</table></body></html>");
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html)
+}
+
+async fn check_kube(state: &AppState) -> Result<(), String> {
+    state
+        .kube_client
+        .apiserver_version()
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("unreachable: {}", e))
+}
+
+async fn check_linode(state: &AppState) -> Result<(), String> {
+    let response = state
+        .linode
+        .authorize(state.linode.http.get(state.linode.load_balancer_url()))
+        .send()
+        .await
+        .map_err(|e| format!("unreachable: {}", e))?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("responded with status {}", response.status()))
+    }
+}
+
+fn status_class(entry: &HistoryEntry) -> &'static str {
+    if entry.http_status < 300 {
+        "ok"
+    } else {
+        "bad"
+    }
+}
+
+/// `id: outcome` with the start of the fingerprint, HTML-escaped.
+fn config_summary(config: &ConfigResult) -> String {
+    let class = if config.outcome == "failed" { "bad" } else { "ok" };
+    let detail = match (&config.fingerprint, &config.message) {
+        (Some(fingerprint), _) => format!(" <code>{}</code>", escape(&fingerprint[..fingerprint.len().min(16)])),
+        (None, Some(message)) => format!(" &mdash; {}", escape(message)),
+        (None, None) => String::new(),
+    };
+    format!("{}: <span class=\"{}\">{}</span>{}", escape(&config.config_id), class, config.outcome, detail)
+}
+
+fn format_time(unix: i64) -> String {
+    DateTime::from_timestamp(unix, 0)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| unix.to_string())
+}
+
+fn escape(raw: &str) -> String {
+    raw.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, testutil};
+    use actix_web::http::StatusCode;
+
+    #[actix_web::test]
+    async fn status_page_takes_the_admin_token() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config = testutil::config(&mock, &[("ADMIN_TOKEN", "admin")]).await;
+        let app = actix_web::test::init_service(actix_web::App::new()
+            .app_data(web::Data::new(testutil::state(config, Default::default())))
+            .route("/", web::get().to(status_page))).await;
+
+        for (authorization, expected) in [(None, StatusCode::UNAUTHORIZED), (Some("Bearer admin"), StatusCode::OK)] {
+            let mut req = actix_web::test::TestRequest::get().uri("/");
+            if let Some(authorization) = authorization {
+                req = req.insert_header(("authorization", authorization));
+            }
+            let response = actix_web::test::call_service(&app, req.to_request()).await;
+            assert_eq!(response.status(), expected);
+        }
+        mock.stop().await;
+    }
+}
@@ -0,0 +1,176 @@
+//! Fixtures shared by the unit tests: the selftest's Linode mock, a mock of
+//! the ConfigMap API, and state built from explicit settings so that tests
+//! never touch the environment.
+
+use crate::secrets::MemorySecrets;
+use crate::selftest::{self, MockLinode};
+use crate::{build_state, config, metrics, state, AppState};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A config pointed at `mock`, with `extra` overriding or adding settings.
+/// An empty value counts as unset, e.g. `("LINODE_TOKEN", "")` next to a
+/// LINODE_TOKEN_FILE.
+pub async fn config(mock: &MockLinode, extra: &[(&str, &str)]) -> config::Config {
+    let mut settings = vec![
+        ("LINODE_TOKEN", selftest::TOKEN),
+        ("NODEBALANCER_ID", "1"),
+        ("HTTPS_CONFIG_ID", selftest::CONFIG_ID),
+        ("LINODE_API_URL", mock.api_url.as_str()),
+        ("LINODE_API_VERSION", "v4"),
+    ];
+    settings.retain(|(key, _)| !extra.iter().any(|(extra_key, _)| extra_key == key));
+    settings.extend_from_slice(extra);
+    config::Config::from_pairs(&settings)
+        .await
+        .unwrap_or_else(|errors| panic!("invalid test settings: {}", errors.join("; ")))
+}
+
+/// TLS secrets in the selftest namespace, from (name, cert, key).
+pub fn secrets(pairs: &[(&str, &str, &str)]) -> MemorySecrets {
+    let mut secrets = MemorySecrets::default();
+    for (name, cert_pem, key_pem) in pairs {
+        secrets.secrets.insert((selftest::NAMESPACE.to_string(), name.to_string()), selftest::tls_secret(cert_pem, key_pem));
+    }
+    secrets
+}
+
+/// State for `config`, reading secrets from memory and with its own metrics
+/// registry. The kube client is never contacted.
+pub fn state(mut config: config::Config, secrets: MemorySecrets) -> Arc<AppState> {
+    let kube_client = kube::Client::try_from(kube::Config::new("http://127.0.0.1:9".parse().unwrap())).unwrap();
+    let http_client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+    let metrics = metrics::Metrics::new(&prometheus::Registry::new(), &config.metrics_namespace).unwrap();
+    Arc::new(build_state(
+        &mut config,
+        kube_client,
+        Box::new(secrets),
+        http_client.clone(),
+        http_client,
+        metrics,
+        state::StateStore::memory(),
+    ))
+}
+
+/// ConfigMap data by name, as the mock API server holds them.
+pub type MockConfigMaps = Mutex<HashMap<String, BTreeMap<String, String>>>;
+
+/// An in-process API server answering get, create and merge patch of
+/// ConfigMaps in any namespace, enough for `StateStore`.
+pub struct MockKube {
+    pub configmaps: web::Data<MockConfigMaps>,
+    // While set, every write is answered with a 500
+    pub failing: web::Data<AtomicBool>,
+    url: String,
+    handle: actix_web::dev::ServerHandle,
+}
+
+impl MockKube {
+    pub async fn start() -> std::io::Result<Self> {
+        let configmaps = web::Data::new(MockConfigMaps::default());
+        let failing = web::Data::new(AtomicBool::new(false));
+        let (server_configmaps, server_failing) = (configmaps.clone(), failing.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_configmaps.clone())
+                .app_data(server_failing.clone())
+                .route("/api/v1/namespaces/{namespace}/configmaps", web::post().to(create_configmap))
+                .route("/api/v1/namespaces/{namespace}/configmaps/{name}", web::get().to(get_configmap))
+                .route("/api/v1/namespaces/{namespace}/configmaps/{name}", web::patch().to(patch_configmap))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))?;
+        let url = format!("http://{}", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        Ok(MockKube { configmaps, failing, url, handle })
+    }
+
+    pub fn client(&self) -> kube::Client {
+        kube::Client::try_from(kube::Config::new(self.url.parse().unwrap())).unwrap()
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+fn configmap(name: &str, data: &BTreeMap<String, String>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": { "name": name },
+        "data": data,
+    }))
+}
+
+fn status(code: u16, reason: &str) -> HttpResponse {
+    HttpResponse::build(actix_web::http::StatusCode::from_u16(code).unwrap()).json(serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Status",
+        "status": "Failure",
+        "message": reason,
+        "reason": reason,
+        "code": code,
+    }))
+}
+
+async fn get_configmap(configmaps: web::Data<MockConfigMaps>, path: web::Path<(String, String)>) -> HttpResponse {
+    match configmaps.lock().unwrap().get(&path.1) {
+        Some(data) => configmap(&path.1, data),
+        None => status(404, "NotFound"),
+    }
+}
+
+async fn create_configmap(
+    configmaps: web::Data<MockConfigMaps>,
+    failing: web::Data<AtomicBool>,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    if failing.load(Ordering::SeqCst) {
+        return status(500, "InternalError");
+    }
+    let name = body["metadata"]["name"].as_str().unwrap_or_default().to_string();
+    let data: BTreeMap<String, String> = serde_json::from_value(body["data"].clone()).unwrap_or_default();
+    let mut configmaps = configmaps.lock().unwrap();
+    if configmaps.contains_key(&name) {
+        return status(409, "AlreadyExists");
+    }
+    let response = configmap(&name, &data);
+    configmaps.insert(name, data);
+    response
+}
+
+/// A JSON merge patch of `data`, the only field `StateStore` writes.
+async fn patch_configmap(
+    configmaps: web::Data<MockConfigMaps>,
+    failing: web::Data<AtomicBool>,
+    path: web::Path<(String, String)>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if failing.load(Ordering::SeqCst) {
+        return status(500, "InternalError");
+    }
+    let patch: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    let mut configmaps = configmaps.lock().unwrap();
+    let Some(data) = configmaps.get_mut(&path.1) else {
+        return status(404, "NotFound");
+    };
+    match &patch["data"] {
+        serde_json::Value::Null => data.clear(),
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                match value.as_str() {
+                    Some(value) => data.insert(key.clone(), value.to_string()),
+                    None => data.remove(key),
+                };
+            }
+        }
+        _ => return status(400, "BadRequest"),
+    }
+    configmap(&path.1, data)
+}
@@ -0,0 +1,300 @@
+use crate::provider::CertBundle;
+use crate::{admin_rejection, cert, check_allowed, fetch_secret, secret_error_code, validate_hook_request, ApiResponse, AppState, ChainPolicy, ErrorCode, HookRequest, SecretData, SecretRef};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Body of `POST /validate`: either an inline pair or a secret to read it from.
+#[derive(Debug, Deserialize)]
+pub struct ValidateRequest {
+    #[serde(default)]
+    cert: Option<String>,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(rename = "secretRef", default)]
+    secret_ref: Option<SecretRef>,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidationReport {
+    valid: bool,
+    checks: Vec<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    sans: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_after: Option<i64>,
+}
+
+/// Runs the checks a certificate goes through before being pushed and
+/// reports each one, without touching Linode. Answers 200 when every check
+/// passes and 422 otherwise, so CI can gate on the status alone. Reading a
+/// secret needs ADMIN_TOKEN, an inline pair doesn't.
+pub async fn validate_cert(state: web::Data<Arc<AppState>>, req: HttpRequest, body: web::Json<ValidateRequest>) -> HttpResponse {
+    let mut body = body.into_inner();
+    if let Some(secret_ref) = &mut body.secret_ref {
+        secret_ref.apply_default_namespace(&state);
+    }
+    let (cert, key) = match (body.cert, body.key, body.secret_ref) {
+        (Some(cert), Some(key), None) => (cert::normalize_pem(&cert), cert::normalize_pem(&key)),
+        (None, None, Some(secret_ref)) => {
+            if let Some(response) = admin_rejection(&state, &req) {
+                return response;
+            }
+            match read_secret(&state, &secret_ref, None).await {
+                Ok(pair) => pair,
+                Err(response) => return response,
+            }
+        }
+        _ => {
+            return HttpResponse::BadRequest().json(ApiResponse {
+                status: "error".to_string(),
+                message: Some("Invalid request: provide either cert and key, or secretRef".to_string()),
+                code: Some(ErrorCode::ValidationError),
+            })
+        }
+    };
+
+    let report = build_report(&state, &cert, &key);
+    info!(
+        "Validation {}: {}",
+        if report.valid { "passed" } else { "failed" },
+        report
+            .checks
+            .iter()
+            .map(|check| format!("{}={}", check.name, check.passed))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if report.valid {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::UnprocessableEntity().json(report)
+    }
+}
+
+/// Reads the pair from a secret like an update would, answering the error
+/// response an update would give when that fails, ALLOWED_SECRETS included.
+/// `config_id` is only checked against ALLOWED_CONFIG_IDS, for callers that
+/// take one alongside the secret.
+pub async fn read_secret(
+    state: &AppState,
+    secret_ref: &SecretRef,
+    config_id: Option<&str>,
+) -> Result<(String, String), HttpResponse> {
+    let request = HookRequest {
+        namespace: secret_ref.namespace.clone(),
+        secret_name: secret_ref.name.clone(),
+        config_id: config_id.map(str::to_string),
+        update_cert: true,
+        update_key: true,
+    };
+    if let Err(e) = validate_hook_request(&request).await {
+        return Err(HttpResponse::BadRequest().json(ApiResponse {
+            status: "error".to_string(),
+            message: Some(format!("Invalid request: {}", e)),
+            code: Some(ErrorCode::ValidationError),
+        }));
+    }
+    if let Err((status, response)) = check_allowed(state, &request) {
+        return Err(HttpResponse::build(status).json(response));
+    }
+
+    match fetch_secret(state, &request.namespace, &request.secret_name).await {
+        Ok(SecretData { cert, key, .. }) => Ok((cert, key)),
+        Err(e) => {
+            error!("Failed to read {}/{} for validation: {}", request.namespace, request.secret_name, e);
+            let code = secret_error_code(e.as_ref());
+            let response = ApiResponse {
+                status: "error".to_string(),
+                message: Some(format!("Failed to retrieve certificate data: {}", e)),
+                code: Some(code),
+            };
+            Err(match code {
+                ErrorCode::SecretNotFound => HttpResponse::NotFound().json(response),
+                ErrorCode::SecretTooLarge => HttpResponse::UnprocessableEntity().json(response),
+                _ => HttpResponse::InternalServerError().json(response),
+            })
+        }
+    }
+}
+
+fn build_report(state: &AppState, cert_pem: &str, key_pem: &str) -> ValidationReport {
+    let mut checks = Vec::new();
+    let mut report = ValidationReport {
+        valid: false,
+        checks: Vec::new(),
+        fingerprint: cert::leaf_fingerprint(cert_pem).ok(),
+        sans: cert::leaf_sans(cert_pem).unwrap_or_default(),
+        not_after: cert::leaf_validity(cert_pem).ok().map(|(_, not_after)| not_after),
+    };
+
+    // Everything else needs the parsed bundle
+    let bundle = match CertBundle::parse(cert_pem, key_pem) {
+        Ok(bundle) => {
+            checks.push(Check {
+                name: "pem",
+                passed: true,
+                detail: format!("{} certificate(s) and a {}", bundle.chain.len(), bundle.key_label),
+            });
+            bundle
+        }
+        Err(e) => {
+            checks.push(Check { name: "pem", passed: false, detail: e });
+            report.checks = checks;
+            return report;
+        }
+    };
+    let leaf = &bundle.chain[0];
+
+    checks.push(if bundle.chain.len() > state.max_chain_certs {
+        Check {
+            name: "chain_length",
+            passed: false,
+            detail: format!("{} certificates, more than MAX_CHAIN_CERTS={}", bundle.chain.len(), state.max_chain_certs),
+        }
+    } else {
+        Check { name: "chain_length", passed: true, detail: format!("{} certificate(s)", bundle.chain.len()) }
+    });
+
+    checks.push(match cert::key_matches(leaf, key_pem) {
+        Ok(true) => Check { name: "key_pair", passed: true, detail: "private key matches the leaf".to_string() },
+        Ok(false) => Check {
+            name: "key_pair",
+            passed: false,
+            detail: "private key does not belong to the leaf certificate".to_string(),
+        },
+        Err(e) => Check { name: "key_pair", passed: false, detail: format!("failed to compare keys: {}", e) },
+    });
+
+    // Reported like updates treat it, CHAIN_POLICY=off doesn't look at the chain at all
+    if state.chain_policy != ChainPolicy::Off {
+        checks.push(match cert::check_chain(&bundle.chain) {
+            Ok(()) => Check { name: "chain", passed: true, detail: "each certificate is issued by the next".to_string() },
+            Err(e) if state.chain_policy == ChainPolicy::Warn => {
+                Check { name: "chain", passed: true, detail: format!("{} (allowed by CHAIN_POLICY=warn)", e) }
+            }
+            Err(e) => Check { name: "chain", passed: false, detail: e },
+        });
+    }
+
+    let now = cert::unix_now();
+    checks.push(match cert::check_validity(cert_pem, now, state.clock_skew_secs) {
+        Ok(()) => {
+            let days = report.not_after.map(|not_after| (not_after - now) / 86_400).unwrap_or_default();
+            let mut detail = format!("expires in {} day(s)", days);
+            if days <= state.expiry_warn_days {
+                detail.push_str(&format!(", within the {}-day warning window", state.expiry_warn_days));
+            }
+            Check { name: "expiry", passed: true, detail }
+        }
+        Err(e) => Check { name: "expiry", passed: false, detail: e.to_string() },
+    });
+
+    checks.push(match cert::self_signed_subject(leaf) {
+        Ok(Some(subject)) if !state.allow_self_signed => Check {
+            name: "self_signed",
+            passed: false,
+            detail: format!("leaf '{}' is self-signed and ALLOW_SELF_SIGNED is off", subject),
+        },
+        Ok(Some(_)) => Check { name: "self_signed", passed: true, detail: "self-signed, allowed".to_string() },
+        Ok(None) => Check { name: "self_signed", passed: true, detail: "issued by a CA".to_string() },
+        Err(detail) => Check { name: "self_signed", passed: false, detail },
+    });
+
+    checks.push(match cert::check_key_strength(leaf) {
+        Ok(detail) => Check { name: "key_strength", passed: true, detail },
+        Err(detail) => Check { name: "key_strength", passed: false, detail },
+    });
+
+    if !state.allowed_fingerprints.is_empty() {
+        checks.push(match &report.fingerprint {
+            Some(fingerprint) if state.allowed_fingerprints.contains(fingerprint) => {
+                Check { name: "fingerprint", passed: true, detail: "listed in ALLOWED_FINGERPRINTS".to_string() }
+            }
+            Some(fingerprint) => Check {
+                name: "fingerprint",
+                passed: false,
+                detail: format!("{} is not in ALLOWED_FINGERPRINTS", fingerprint),
+            },
+            None => Check { name: "fingerprint", passed: false, detail: "failed to fingerprint the leaf".to_string() },
+        });
+    }
+
+    report.valid = checks.iter().all(|check| check.passed);
+    report.checks = checks;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, testutil};
+    use actix_web::http::StatusCode;
+
+    #[actix_web::test]
+    async fn secret_outside_allowed_secrets_is_not_read() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config = testutil::config(&mock, &[("ALLOWED_SECRETS", "selftest=selftest-*")]).await;
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(config, testutil::secrets(&[
+            (selftest::SECRET_NAME, &cert_pem, &key_pem),
+            ("other-tls", &cert_pem, &key_pem),
+        ]));
+        let secret_ref = |name: &str| SecretRef { name: name.to_string(), namespace: selftest::NAMESPACE.to_string() };
+
+        assert_eq!(read_secret(&state, &secret_ref(selftest::SECRET_NAME), None).await.ok(), Some((cert_pem, key_pem)));
+        let response = read_secret(&state, &secret_ref("other-tls"), None).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"], "SECRET_NOT_ALLOWED");
+        mock.stop().await;
+    }
+    #[actix_web::test]
+    async fn config_outside_allowed_config_ids_is_refused() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let config = testutil::config(&mock, &[("ALLOWED_CONFIG_IDS", selftest::CONFIG_ID)]).await;
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+        let secret_ref = SecretRef { name: selftest::SECRET_NAME.to_string(), namespace: selftest::NAMESPACE.to_string() };
+
+        assert!(read_secret(&state, &secret_ref, Some(selftest::CONFIG_ID)).await.is_ok());
+        let response = read_secret(&state, &secret_ref, Some(selftest::FORBIDDEN_CONFIG_ID)).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"], "CONFIG_NOT_ALLOWED");
+        mock.stop().await;
+    }
+    #[actix_web::test]
+    async fn reading_a_secret_needs_the_admin_token() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let config = testutil::config(&mock, &[("ADMIN_TOKEN", "admin")]).await;
+        let state = testutil::state(config, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+        let app = actix_web::test::init_service(actix_web::App::new()
+            .app_data(web::Data::new(state))
+            .route("/validate", web::post().to(validate_cert))).await;
+        let by_ref = serde_json::json!({ "secretRef": { "name": selftest::SECRET_NAME, "namespace": selftest::NAMESPACE } });
+
+        let req = actix_web::test::TestRequest::post().uri("/validate").set_json(&by_ref).to_request();
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = actix_web::test::TestRequest::post()
+            .uri("/validate")
+            .insert_header(("authorization", "Bearer admin"))
+            .set_json(&by_ref)
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), StatusCode::OK);
+        let inline = serde_json::json!({ "cert": cert_pem, "key": key_pem });
+        let req = actix_web::test::TestRequest::post().uri("/validate").set_json(&inline).to_request();
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), StatusCode::OK);
+        mock.stop().await;
+    }
+}
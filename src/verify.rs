@@ -0,0 +1,207 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Head start of each address over the next one, as recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+pub struct LiveVerifyConfig {
+    pub host: String,
+    pub attempts: u32,
+    pub delay: Duration,
+    pub prefer_ip: PreferIp,
+}
+
+/// PREFER_IP: which address family handshakes try first when the host
+/// resolves to both. The other family is still tried when the preferred one
+/// fails or has no records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PreferIp {
+    V4,
+    V6,
+    // Alternate families in the resolver's order (happy eyeballs)
+    #[default]
+    Auto,
+}
+
+impl FromStr for PreferIp {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.to_lowercase().as_str() {
+            "v4" => Ok(PreferIp::V4),
+            "v6" => Ok(PreferIp::V6),
+            "auto" | "" => Ok(PreferIp::Auto),
+            other => Err(format!("unknown address family '{}' (expected v4, v6 or auto)", other)),
+        }
+    }
+}
+
+/// POST_VERIFY: how long to keep reading a config back after a PUT until it
+/// reports the pushed fingerprint.
+pub struct PostVerifyConfig {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+#[derive(Debug)]
+pub enum LiveVerifyError {
+    /// The handshake worked but the NodeBalancer still serves another certificate.
+    Mismatch { expected: String, served: String },
+    /// The handshake itself could not be completed.
+    Handshake(String),
+}
+
+impl std::fmt::Display for LiveVerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LiveVerifyError::Mismatch { expected, served } => write!(
+                f,
+                "NodeBalancer serves certificate {} instead of the pushed {}",
+                served, expected
+            ),
+            LiveVerifyError::Handshake(e) => write!(f, "TLS handshake with NodeBalancer failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LiveVerifyError {}
+
+/// Connects to the NodeBalancer and checks that the served leaf matches
+/// `expected_fingerprint`. Linode takes a few seconds to roll out a new cert,
+/// so mismatches are retried before giving up.
+pub async fn verify_served_cert(
+    config: &LiveVerifyConfig,
+    port: u16,
+    expected_fingerprint: &str,
+) -> Result<(), LiveVerifyError> {
+    let mut last_error = LiveVerifyError::Handshake("no attempt made".to_string());
+
+    for attempt in 1..=config.attempts {
+        match served_fingerprint(&config.host, port, config.prefer_ip).await {
+            Ok(served) if served == expected_fingerprint => {
+                info!("Verified NodeBalancer {}:{} serves the pushed certificate", config.host, port);
+                return Ok(());
+            }
+            Ok(served) => {
+                debug!(
+                    "NodeBalancer {}:{} still serves {} (attempt {}/{})",
+                    config.host, port, served, attempt, config.attempts
+                );
+                last_error = LiveVerifyError::Mismatch {
+                    expected: expected_fingerprint.to_string(),
+                    served,
+                };
+            }
+            Err(e) => {
+                warn!("Live verification of {}:{} failed (attempt {}/{}): {}", config.host, port, attempt, config.attempts, e);
+                last_error = LiveVerifyError::Handshake(e.to_string());
+            }
+        }
+
+        if attempt < config.attempts {
+            sleep(config.delay).await;
+        }
+    }
+
+    Err(last_error)
+}
+
+async fn served_fingerprint(host: &str, port: u16, prefer_ip: PreferIp) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(crate::cert::der_fingerprint(&served_certificate(host, port, prefer_ip).await?))
+}
+
+/// DER of the leaf certificate served on `host:port`.
+pub async fn served_certificate(host: &str, port: u16, prefer_ip: PreferIp) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // IPv6 literals may come bracketed like in a URL
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    let tcp = timeout(CONNECT_TIMEOUT, connect(host, port, prefer_ip))
+        .await
+        .map_err(|_| format!("timed out connecting to {}:{}", host, port))??;
+
+    // Trust is established by comparing fingerprints, not by the system roots
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        // SNI only carries hostnames
+        .use_sni(host.parse::<IpAddr>().is_err())
+        .build()?;
+    let connector = tokio_native_tls::TlsConnector::from(connector);
+
+    let tls = timeout(CONNECT_TIMEOUT, connector.connect(host, tcp))
+        .await
+        .map_err(|_| format!("timed out during TLS handshake with {}:{}", host, port))??;
+    let cert = tls
+        .get_ref()
+        .peer_certificate()?
+        .ok_or("NodeBalancer presented no certificate")?;
+
+    Ok(cert.to_der()?)
+}
+
+/// Resolves `host` (A and AAAA) and races connections to its addresses in
+/// preference order, each one starting when the previous fails or after
+/// CONNECTION_ATTEMPT_DELAY, whichever comes first. The first to connect wins.
+async fn connect(host: &str, port: u16, prefer_ip: PreferIp) -> Result<TcpStream, Box<dyn std::error::Error>> {
+    let resolved: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("failed to resolve {}: {}", host, e))?
+        .collect();
+    let addrs = order_addrs(resolved, prefer_ip);
+    if addrs.is_empty() {
+        return Err(format!("{} has no addresses", host).into());
+    }
+
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            debug!("Connecting to {} for {}", addr, host);
+            attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+        } else if attempts.is_empty() {
+            break;
+        }
+
+        // Wait for an attempt to finish, or for the next address's turn
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Connecting to {} failed: {}", addr, e);
+                    last_error = Some(format!("{}: {}", addr, e));
+                }
+            },
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {}
+        }
+    }
+
+    Err(format!("failed to connect to {}:{} ({})", host, port, last_error.unwrap_or_default()).into())
+}
+
+/// Orders addresses for `connect`: the preferred family first, or, with
+/// `Auto`, alternating families starting with the resolver's first choice.
+fn order_addrs(resolved: Vec<SocketAddr>, prefer_ip: PreferIp) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = resolved.iter().copied().partition(SocketAddr::is_ipv6);
+    let (first, second) = match prefer_ip {
+        PreferIp::V4 => return v4.into_iter().chain(v6).collect(),
+        PreferIp::V6 => return v6.into_iter().chain(v4).collect(),
+        PreferIp::Auto if resolved.first().is_some_and(SocketAddr::is_ipv6) => (v6, v4),
+        PreferIp::Auto => (v4, v6),
+    };
+
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
@@ -0,0 +1,241 @@
+use crate::leader::Leadership;
+use crate::{process_update, AppState, CertManagerHook, SecretRef, UpdateSource};
+use futures::StreamExt;
+use kube::api::{Api, ApiResource, DynamicObject, GroupVersionKind};
+use kube::runtime::{watcher, WatchStreamExt};
+use log::{debug, error, info, warn};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, Instant};
+
+// How often a drain checks whether the queue emptied
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// A cert-manager Certificate whose issued secret is pushed on every renewal.
+#[derive(Debug, Clone)]
+pub struct CertificateTarget {
+    pub namespace: String,
+    pub name: String,
+}
+
+/// Pushes triggered by watches, at most `concurrency` at a time. A key queued
+/// again before its push started only keeps the latest request, and a key is
+/// never pushed twice at once, so a burst of changes to one secret collapses
+/// into a single push of its latest state. On shutdown the queue is closed
+/// and drained, see `close` and `drain`.
+#[derive(Clone)]
+pub struct WorkQueue {
+    inner: Rc<RefCell<QueueState>>,
+    permits: Rc<Semaphore>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    // Latest job per key, not started yet
+    pending: HashMap<String, Job>,
+    // Keys with a worker, which picks up whatever is pending for them next
+    active: HashSet<String>,
+    // Set on shutdown, nothing new is queued from then on
+    closed: Option<Instant>,
+    // Since closing, pushes that finished and revisions turned away
+    processed: usize,
+    rejected: usize,
+}
+
+struct Job {
+    target: CertificateTarget,
+    // Secret name and revision the push is for
+    seen: (String, i64),
+    // Where the watch remembers the last revision that went through
+    last_seen: Rc<RefCell<Option<(String, i64)>>>,
+}
+
+impl WorkQueue {
+    pub fn new(concurrency: usize) -> Self {
+        WorkQueue {
+            inner: Rc::new(RefCell::new(QueueState::default())),
+            permits: Rc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    fn submit(&self, state: &Arc<AppState>, key: String, job: Job) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.closed.is_some() {
+            inner.rejected += 1;
+            warn!(
+                "Shutting down, dropping revision {} of Certificate {} (secret {}), re-trigger it once a replica is up",
+                job.seen.1, key, job.seen.0
+            );
+            return;
+        }
+        if let Some(replaced) = inner.pending.insert(key.clone(), job) {
+            debug!("Coalesced queued revision {} of {} into a newer one", replaced.seen.1, key);
+        }
+        if !inner.active.insert(key.clone()) {
+            return;
+        }
+        drop(inner);
+
+        let queue = self.clone();
+        let state = state.clone();
+        actix_web::rt::spawn(async move { queue.work(&state, &key).await });
+    }
+
+    async fn work(&self, state: &AppState, key: &str) {
+        loop {
+            let _permit = self.permits.acquire().await.expect("watch queue semaphore is never closed");
+            // Taken only once a permit is held, so it is the latest request
+            let Some(job) = self.inner.borrow_mut().pending.remove(key) else {
+                self.inner.borrow_mut().active.remove(key);
+                return;
+            };
+            push(state, job).await;
+            let mut inner = self.inner.borrow_mut();
+            if inner.closed.is_some() {
+                inner.processed += 1;
+            }
+        }
+    }
+
+    /// Stops queueing new revisions, what is already queued keeps running.
+    pub fn close(&self) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.closed.is_some() {
+            return;
+        }
+        inner.closed = Some(Instant::now());
+        info!(
+            "Watch queue closed, {} push(es) queued and {} in flight",
+            inner.pending.len(),
+            inner.active.len().saturating_sub(inner.pending.len())
+        );
+    }
+
+    /// Closes the queue if it isn't yet and waits for queued and in-flight
+    /// pushes, up to `timeout` after it was closed. What is still queued then
+    /// is dropped and logged one by one, so operators know which Certificates
+    /// to re-trigger.
+    pub async fn drain(&self, timeout: Duration) {
+        self.close();
+        let deadline = self.inner.borrow().closed.unwrap_or_else(Instant::now) + timeout;
+        loop {
+            let idle = {
+                let inner = self.inner.borrow();
+                inner.pending.is_empty() && inner.active.is_empty()
+            };
+            if idle || Instant::now() >= deadline {
+                break;
+            }
+            sleep(DRAIN_POLL).await;
+        }
+
+        let inner = self.inner.borrow();
+        for (key, job) in &inner.pending {
+            error!(
+                "Dropped queued revision {} of Certificate {} (secret {}) on shutdown, re-trigger it once a replica is up",
+                job.seen.1, key, job.seen.0
+            );
+        }
+        // Active keys without a pending job are mid-push, their configs may not all be updated
+        let interrupted: Vec<&String> = inner.active.iter().filter(|key| !inner.pending.contains_key(*key)).collect();
+        for key in &interrupted {
+            error!("Push for Certificate {} was still running at the shutdown deadline, check its configs", key);
+        }
+        let dropped = inner.rejected + inner.pending.len() + interrupted.len();
+        if dropped > 0 {
+            warn!("Watch queue drained: {} processed, {} dropped", inner.processed, dropped);
+        } else {
+            info!("Watch queue drained: {} processed, none dropped", inner.processed);
+        }
+    }
+}
+
+async fn push(state: &AppState, job: Job) {
+    let Job { target, seen, last_seen } = job;
+    info!(
+        "Certificate {}/{} is at revision {}, updating from secret {}",
+        target.namespace, target.name, seen.1, seen.0
+    );
+    let hook = CertManagerHook {
+        secret_ref: SecretRef {
+            name: seen.0.clone(),
+            namespace: target.namespace.clone(),
+        },
+        config_id: None,
+        update_cert: true,
+        update_key: true,
+    };
+    let (status, response) = process_update(state, &hook, UpdateSource::Watch).await;
+    if status.is_success() && status != actix_web::http::StatusCode::MULTI_STATUS {
+        *last_seen.borrow_mut() = Some(seen);
+    } else {
+        // Left unseen so the next event for the Certificate retries it
+        error!(
+            "Update for Certificate {}/{} failed: {}",
+            target.namespace,
+            target.name,
+            response.message.unwrap_or_default()
+        );
+    }
+}
+
+fn certificate_resource() -> ApiResource {
+    ApiResource::from_gvk(&GroupVersionKind::gvk("cert-manager.io", "v1", "Certificate"))
+}
+
+/// Watches the Certificate and triggers an update whenever its
+/// `status.revision` (bumped by cert-manager on each issuance) or
+/// `spec.secretName` changes. The first sighting triggers one too, which the
+/// fingerprint deduplication turns into a no-op when nothing changed. Only the
+/// leader pushes, through `queue`, and runs on the main arbiter as the update
+/// path isn't `Send`.
+pub fn spawn(state: Arc<AppState>, target: CertificateTarget, leadership: Leadership, queue: WorkQueue) {
+    info!("Watching Certificate {}/{}", target.namespace, target.name);
+
+    actix_web::rt::spawn(async move {
+        let api: Api<DynamicObject> =
+            Api::namespaced_with(state.kube_client.clone(), &target.namespace, &certificate_resource());
+        let config = watcher::Config::default().fields(&format!("metadata.name={}", target.name));
+        let mut events = watcher(api, config).default_backoff().applied_objects().boxed_local();
+        let last_seen: Rc<RefCell<Option<(String, i64)>>> = Rc::default();
+        let key = format!("{}/{}", target.namespace, target.name);
+
+        while let Some(event) = events.next().await {
+            let certificate = match event {
+                Ok(certificate) => certificate,
+                Err(e) => {
+                    warn!("Watch of Certificate {}/{} failed, retrying: {}", target.namespace, target.name, e);
+                    continue;
+                }
+            };
+
+            let Some(secret_name) = certificate.data["spec"]["secretName"].as_str().map(str::to_string) else {
+                error!("Certificate {}/{} has no spec.secretName", target.namespace, target.name);
+                continue;
+            };
+            let Some(revision) = certificate.data["status"]["revision"].as_i64() else {
+                debug!("Certificate {}/{} has not been issued yet", target.namespace, target.name);
+                continue;
+            };
+
+            let current = (secret_name, revision);
+            if last_seen.borrow().as_ref() == Some(&current) {
+                continue;
+            }
+            if !leadership.is_leader() {
+                debug!("Not the leader, leaving revision {} of {}/{} to it", revision, target.namespace, target.name);
+                continue;
+            }
+
+            queue.submit(
+                &state,
+                key.clone(),
+                Job { target: target.clone(), seen: current, last_seen: last_seen.clone() },
+            );
+        }
+    });
+}
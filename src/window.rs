@@ -0,0 +1,245 @@
+use crate::leader::Leadership;
+use crate::{cert, process_update, AppState, CertManagerHook, UpdateSource};
+use actix_web::http::StatusCode;
+use k8s_openapi::chrono::{Datelike, FixedOffset, TimeZone, Timelike};
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often the window is re-checked and, once open, queued updates flushed
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const FULL_DAYS: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// APPLY_WINDOW: when pushes may happen, as `;`-separated spans like
+/// `Mon-Fri 09:00-17:00` in the APPLY_WINDOW_TZ offset. Days are optional
+/// and default to every day, an end before the start runs past midnight.
+#[derive(Debug, Clone)]
+pub struct ApplyWindow {
+    spans: Vec<Span>,
+    offset: FixedOffset,
+}
+
+#[derive(Debug, Clone)]
+struct Span {
+    // Indexed from Monday
+    days: [bool; 7],
+    // Minutes since local midnight, `end` is exclusive
+    start: u32,
+    end: u32,
+}
+
+impl ApplyWindow {
+    pub fn parse(raw: &str, offset: FixedOffset) -> Result<Self, String> {
+        let spans = raw
+            .split(';')
+            .map(str::trim)
+            .filter(|span| !span.is_empty())
+            .map(parse_span)
+            .collect::<Result<Vec<_>, _>>()?;
+        if spans.is_empty() {
+            return Err("has no span, expected e.g. 'Mon-Fri 09:00-17:00'".to_string());
+        }
+        Ok(ApplyWindow { spans, offset })
+    }
+
+    /// Whether the window is open at unix time `now`.
+    pub fn is_open(&self, now: i64) -> bool {
+        let Some(local) = self.offset.timestamp_opt(now, 0).single() else {
+            return false;
+        };
+        let day = local.weekday().num_days_from_monday() as usize;
+        let previous_day = (day + 6) % 7;
+        let minute = local.hour() * 60 + local.minute();
+        self.spans.iter().any(|span| {
+            if span.start < span.end {
+                span.days[day] && (span.start..span.end).contains(&minute)
+            } else {
+                (span.days[day] && minute >= span.start) || (span.days[previous_day] && minute < span.end)
+            }
+        })
+    }
+}
+
+impl fmt::Display for ApplyWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let spans: Vec<String> = self
+            .spans
+            .iter()
+            .map(|span| {
+                let days: Vec<&str> = DAYS.iter().zip(span.days).filter(|(_, on)| *on).map(|(day, _)| *day).collect();
+                let time = |minutes: u32| format!("{:02}:{:02}", minutes / 60, minutes % 60);
+                let end = if span.end == 0 { MINUTES_PER_DAY } else { span.end };
+                format!("{} {}-{}", days.join(","), time(span.start), time(end))
+            })
+            .collect();
+        write!(f, "{} (UTC{})", spans.join("; "), self.offset)
+    }
+}
+
+fn parse_span(raw: &str) -> Result<Span, String> {
+    let (days, times) = match raw.rsplit_once(char::is_whitespace) {
+        Some((days, times)) => (parse_days(days.trim())?, times),
+        None => ([true; 7], raw),
+    };
+    let (start, end) = times
+        .split_once('-')
+        .ok_or_else(|| format!("invalid span '{}', expected [days] HH:MM-HH:MM", raw))?;
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    if start == end || start == MINUTES_PER_DAY {
+        return Err(format!("invalid span '{}', it starts when it ends", raw));
+    }
+    // 24:00 as an end is the next midnight
+    Ok(Span { days, start, end: end % MINUTES_PER_DAY })
+}
+
+/// `Mon-Fri`, `Sat,Sun` or a mix of both, case insensitive. A range may
+/// wrap, `Fri-Mon` is four days.
+fn parse_days(raw: &str) -> Result<[bool; 7], String> {
+    let index = |name: &str| {
+        let name = name.trim().to_lowercase();
+        DAYS.iter()
+            .zip(FULL_DAYS)
+            .position(|(day, full)| name == *day || name == full)
+            .ok_or_else(|| format!("unknown day '{}'", name))
+    };
+    let mut days = [false; 7];
+    for part in raw.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (index(from)?, index(to)?);
+                let mut day = from;
+                loop {
+                    days[day] = true;
+                    if day == to {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days[index(part)?] = true,
+        }
+    }
+    if !days.contains(&true) {
+        return Err(format!("no days in '{}'", raw));
+    }
+    Ok(days)
+}
+
+/// `HH:MM` as minutes since midnight, `24:00` included.
+fn parse_time(raw: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time '{}', expected HH:MM", raw.trim());
+    let (hours, minutes) = raw.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    match hours * 60 + minutes {
+        total if minutes < 60 && total <= MINUTES_PER_DAY => Ok(total),
+        _ => Err(invalid()),
+    }
+}
+
+/// APPLY_WINDOW_TZ: `UTC` or a fixed offset like `+02:00` or `-0530`. Named
+/// zones aren't supported, an offset doesn't follow daylight saving time.
+pub fn parse_offset(raw: &str) -> Result<FixedOffset, String> {
+    let raw = raw.trim();
+    if raw.eq_ignore_ascii_case("utc") || raw == "Z" {
+        return Ok(FixedOffset::east_opt(0).expect("zero is a valid offset"));
+    }
+    let invalid = || format!("'{}' is not UTC or an offset like +02:00", raw);
+    let (sign, rest) = match raw.chars().next() {
+        Some('+') => (1, &raw[1..]),
+        Some('-') => (-1, &raw[1..]),
+        _ => return Err(invalid()),
+    };
+    let digits: String = rest.chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) || !matches!(digits.len(), 2 | 4) {
+        return Err(invalid());
+    }
+    let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+    let minutes: i32 = if digits.len() == 4 { digits[2..].parse().map_err(|_| invalid())? } else { 0 };
+    if minutes >= 60 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+/// Whether pushes are allowed right now, keeping the apply_window_open gauge
+/// in step. Always true without APPLY_WINDOW.
+pub fn is_open(state: &AppState) -> bool {
+    let open = state.apply_window.as_ref().is_none_or(|window| window.is_open(cert::unix_now()));
+    state.metrics.apply_window_open.set(i64::from(open));
+    open
+}
+
+/// Re-checks the window every CHECK_INTERVAL and, while it's open, runs the
+/// updates queued outside it. The queue is shared through the pending
+/// ConfigMap, so only the leader flushes it, re-reading it each time to pick
+/// up what other replicas queued.
+pub fn spawn(state: Arc<AppState>, leadership: Leadership) {
+    info!("Pushing only within APPLY_WINDOW {}", state.apply_window.as_ref().map(ToString::to_string).unwrap_or_default());
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            if !is_open(&state) || !leadership.is_leader() {
+                continue;
+            }
+            flush_pending(&state).await;
+        }
+    });
+}
+
+/// One round of the flush: runs every queued update and takes off the ones
+/// that fully succeeded.
+async fn flush_pending(state: &AppState) {
+    let pending = state.pending.refresh().await;
+    if pending.is_empty() {
+        return;
+    }
+    info!("Apply window is open, flushing {} queued update(s)", pending.len());
+    for (key, body) in pending {
+        let hook: CertManagerHook = match serde_json::from_str(&body) {
+            Ok(hook) => hook,
+            Err(e) => {
+                error!("Dropping queued update {}, it can't be read back: {}", key, e);
+                state.pending.remove_if(&key, &body).await;
+                continue;
+            }
+        };
+        let (status, response) = process_update(state, &hook, UpdateSource::ApplyWindow).await;
+        // Kept on anything short of a full success, including the window closing again, and
+        // only taken off while it still holds this body, a newer request replaces it
+        if status.is_success() && status != StatusCode::MULTI_STATUS && response.status != "queued" {
+            debug!("Queued update {} finished: {}", key, response.status);
+            state.pending.remove_if(&key, &body).await;
+        } else {
+            warn!("Queued update {} failed with {}, keeping it for the next round: {:?}", key, status, response.message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest, testutil};
+
+    #[actix_web::test]
+    async fn a_failed_queued_update_is_kept() {
+        let mock = selftest::MockLinode::start().await.unwrap();
+        let (cert_pem, key_pem) = selftest::generate_chain().unwrap();
+        let state = testutil::state(testutil::config(&mock, &[]).await, testutil::secrets(&[(selftest::SECRET_NAME, &cert_pem, &key_pem)]));
+        let mut failing = selftest::hook(selftest::SECRET_NAME);
+        failing.config_id = Some(selftest::ERROR_CONFIG_ID.to_string());
+        state.pending.insert("failing", &serde_json::to_string(&failing).unwrap()).await;
+        state.pending.insert("working", &serde_json::to_string(&selftest::hook(selftest::SECRET_NAME)).unwrap()).await;
+
+        flush_pending(&state).await;
+        assert_eq!(state.pending.snapshot().into_keys().collect::<Vec<_>>(), ["failing"]);
+        mock.stop().await;
+    }
+}